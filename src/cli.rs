@@ -0,0 +1,258 @@
+// src/cli.rs
+
+use crate::message_db::InvoiceSort;
+use crate::money::{Currency, Money};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use time::Date;
+use time::format_description::well_known::Iso8601;
+
+/// Fetch supplier invoices from Gmail and extract structured data from the PDFs.
+#[derive(Debug, Parser)]
+#[command(name = "invoice_search", version)]
+pub struct Cli {
+    /// SQLite database [default: `db_path` from the config, else msgstore/messages.db]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub db: Option<PathBuf>,
+
+    /// Mailbox / profile config [default: .config/oath_cli.toml]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Log filter, e.g. `debug` or `invoice_search=debug,info`
+    #[arg(long, global = true, value_name = "FILTER", default_value = "info")]
+    pub log_level: String,
+
+    /// Report what would be done without downloading messages or writing any file
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Sync profile queries against Gmail and download new messages
+    Fetch {
+        /// Only run this `[[profiles]]` entry
+        #[arg(long)]
+        profile: Option<String>,
+        /// Ignore stored history ids and re-list every match
+        #[arg(long)]
+        full: bool,
+    },
+    /// Classify downloaded PDFs as text / scanned
+    Extract {
+        /// Re-run text, heuristic and LLM extraction on one attachment and
+        /// print the results without storing them
+        #[arg(long, value_name = "ID")]
+        attachment: Option<i64>,
+    },
+    /// Classify PDFs, then extract invoice data with each profile's strategy
+    Process {
+        /// Only extract attachments labelled by this profile
+        #[arg(long)]
+        profile: Option<String>,
+        /// Also extract attachments that already have an invoice, replacing it
+        #[arg(long)]
+        reextract: bool,
+    },
+    /// List stored invoices, or print one in full
+    Show {
+        /// Attachment id whose invoice to print
+        attachment_id: Option<i64>,
+    },
+    /// Query stored invoices
+    Invoices {
+        #[command(subcommand)]
+        command: InvoicesCommand,
+    },
+    /// Write every stored invoice to stdout or a file
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// Output file [default: stdout]
+        #[arg(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Full-text search over messages and extracted PDF text
+    Search {
+        /// Words that must all appear, e.g. `ELDEN RING PS5`
+        #[arg(required = true)]
+        terms: Vec<String>,
+        /// Only messages whose sender contains this
+        #[arg(long, value_name = "ADDRESS")]
+        from: Option<String>,
+        /// Only messages dated on or after this day (YYYY-MM-DD)
+        #[arg(long, value_name = "DATE", value_parser = parse_day)]
+        since: Option<Date>,
+        /// Only messages dated on or before this day (YYYY-MM-DD)
+        #[arg(long, value_name = "DATE", value_parser = parse_day)]
+        until: Option<Date>,
+        /// Only attachments of this content type (text, ocr, ...)
+        #[arg(long = "type", value_name = "CONTENT_TYPE")]
+        content_type: Option<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Message, attachment and invoice counts
+    Stats,
+    /// List encrypted PDFs that no configured password opens
+    Encrypted,
+    /// Manage Gmail authorization
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommand,
+    },
+    /// Database maintenance
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuthCommand {
+    /// Run the browser OAuth flow and store the mailbox's tokens
+    Login {
+        /// `[[mailboxes]]` entry to authorize; optional when only one exists
+        mailbox: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum InvoicesCommand {
+    /// List invoices matching every given filter
+    List(InvoiceListArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct InvoiceListArgs {
+    /// Vendor name contains this
+    #[arg(long)]
+    pub vendor: Option<String>,
+    /// Buyer name contains this
+    #[arg(long)]
+    pub buyer: Option<String>,
+    /// Invoice number contains this
+    #[arg(long, value_name = "NO")]
+    pub invoice_no: Option<String>,
+    /// Invoice dated on or after this day (YYYY-MM-DD)
+    #[arg(long, value_name = "DATE", value_parser = parse_day)]
+    pub since: Option<Date>,
+    /// Invoice dated on or before this day (YYYY-MM-DD)
+    #[arg(long, value_name = "DATE", value_parser = parse_day)]
+    pub until: Option<Date>,
+    /// ISO currency code, e.g. USD
+    #[arg(long)]
+    pub currency: Option<String>,
+    /// Total at least this, in --currency if given, else in the invoice's own
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_amount)]
+    pub min_amount: Option<String>,
+    /// Total at most this
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_amount)]
+    pub max_amount: Option<String>,
+    /// A line item's description contains this
+    #[arg(long, value_name = "DESCRIPTION")]
+    pub item: Option<String>,
+    #[arg(long, value_enum, default_value_t = InvoiceSort::Created)]
+    pub sort: InvoiceSort,
+    /// Largest / latest first
+    #[arg(long)]
+    pub desc: bool,
+    #[arg(long, default_value_t = 50)]
+    pub limit: usize,
+    /// Skip this many matches, for paging
+    #[arg(long, default_value_t = 0)]
+    pub offset: usize,
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    pub format: ListFormat,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ListFormat {
+    /// One line per invoice
+    Table,
+    /// Full invoices including line items and packing lists
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DbCommand {
+    /// Bring the schema up to date (also done whenever the database is opened)
+    Migrate {
+        /// Only list applied and pending migrations
+        #[arg(long)]
+        status: bool,
+    },
+}
+
+fn parse_day(s: &str) -> Result<Date, String> {
+    Date::parse(s, &Iso8601::DATE).map_err(|_| format!("expected YYYY-MM-DD, got '{s}'"))
+}
+
+/// Checks the amount is a decimal; its scale depends on the currency, so
+/// the invoice query reads it.
+fn parse_amount(s: &str) -> Result<String, String> {
+    Money::parse(s, Currency::NONE)
+        .map(|_| s.to_string())
+        .ok_or_else(|| format!("expected an amount like 2540.00, got '{s}'"))
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// Full invoices including line items and packing lists
+    Json,
+    /// One row per invoice with the scalar fields
+    Csv,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_global_options_and_usage_errors() {
+        let cli = Cli::try_parse_from([
+            "invoice_search",
+            "fetch",
+            "--profile",
+            "maxsoft",
+            "--db",
+            "x.db",
+            "--dry-run",
+        ])
+        .unwrap();
+        assert!(cli.dry_run);
+        assert_eq!(cli.db, Some(PathBuf::from("x.db")));
+        assert!(matches!(
+            cli.command,
+            Command::Fetch { profile: Some(ref p), full: false } if p == "maxsoft"
+        ));
+
+        // A typo must not fall through to a mailbox download
+        assert!(Cli::try_parse_from(["invoice_search", "fecth"]).is_err());
+        assert!(Cli::try_parse_from(["invoice_search"]).is_err());
+        assert!(Cli::try_parse_from(["invoice_search", "show", "abc"]).is_err());
+
+        let cli = Cli::try_parse_from([
+            "invoice_search",
+            "search",
+            "ELDEN",
+            "RING",
+            "--since",
+            "2026-02-01",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Search { ref terms, since: Some(d), .. }
+                if terms.len() == 2 && d.to_string() == "2026-02-01"
+        ));
+        assert!(
+            Cli::try_parse_from(["invoice_search", "search", "x", "--until", "16/02/2026"])
+                .is_err()
+        );
+    }
+}
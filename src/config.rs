@@ -1,19 +1,232 @@
+use crate::dates::DateHints;
+use crate::heuristics::HeuristicTemplate;
+use crate::simplestore::write_private;
 use serde::Deserialize;
-use std::{fs, path::Path};
-use toml_edit::{DocumentMut, value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, value};
 
 #[derive(Deserialize)]
 pub struct Config {
+    /// File this config was loaded from; token files are kept alongside it
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// OAuth client shared by all mailboxes unless a mailbox overrides it
     #[serde(rename = "gmail_oauth")]
     pub gmail: GmailConfig,
     #[serde(default = "default_db_path")]
     pub db_path: String,
+    #[serde(default)]
+    pub fetch: FetchConfig,
+    /// Gmail accounts to fetch from (`[[mailboxes]]`)
+    #[serde(default)]
+    pub mailboxes: Vec<MailboxConfig>,
+    /// Supplier searches and how to extract their invoices (`[[profiles]]`)
+    #[serde(default)]
+    pub profiles: Vec<ProfileConfig>,
+    #[serde(default)]
+    pub ocr: OcrConfig,
+    #[serde(default)]
+    pub pdf: PdfConfig,
+    /// Day/month order of ambiguous invoice dates
+    #[serde(default)]
+    pub dates: DateHints,
+}
+
+/// One Gmail account to monitor.
+#[derive(Deserialize)]
+pub struct MailboxConfig {
+    /// Short name used on the command line and for the token file; only
+    /// ASCII letters, digits, `_` and `-`
+    pub name: String,
+    /// Gmail address; recorded as `messages.user`
+    pub user: String,
+    /// Override the shared `[gmail_oauth]` client for this mailbox
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    /// Filled in by `auth login <name>`
+    #[serde(default)]
+    pub tokens: Tokens,
+}
+
+/// A named Gmail search plus the extraction strategy for what it finds.
+#[derive(Deserialize)]
+pub struct ProfileConfig {
+    pub name: String,
+    /// Gmail search query, e.g. `from:*@maxsoft.sg filename:pdf`
+    pub query: String,
+    /// Label recorded on matched messages; defaults to the profile name
+    pub label: Option<String>,
+    /// Mailbox names to search; empty means every mailbox
+    #[serde(default)]
+    pub mailboxes: Vec<String>,
+    #[serde(default)]
+    pub extraction: ExtractionConfig,
+}
+
+impl ProfileConfig {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn applies_to(&self, mailbox: &MailboxConfig) -> bool {
+        self.mailboxes.is_empty() || self.mailboxes.contains(&mailbox.name)
+    }
+}
+
+/// How to turn a profile's PDFs into `InvoiceData`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExtractionConfig {
+    /// Regex template used by the heuristics backend
+    #[serde(default)]
+    pub template: HeuristicTemplate,
+    /// Overrides `[llm] backend` from llm_conf.toml for this profile
+    pub backend: Option<LlmBackend>,
 }
 
 fn default_db_path() -> String {
     "msgstore/messages.db".to_string()
 }
 
+/// Gmail fetch pipeline tuning (the optional `[fetch]` table).
+#[derive(Debug, Clone, Deserialize)]
+pub struct FetchConfig {
+    /// Maximum number of messages fetched concurrently
+    #[serde(default = "default_fetch_concurrency")]
+    pub concurrency: usize,
+    /// Gmail quota units to spend per second (per-user limit is 250)
+    #[serde(default = "default_quota_units_per_sec")]
+    pub quota_units_per_sec: u32,
+    /// Attempts per Gmail call before giving up (1 disables retries)
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Syncs in a row a message may fail to fetch before later syncs stop
+    /// retrying it
+    #[serde(default = "default_max_failed_syncs")]
+    pub max_failed_syncs: u32,
+    /// Initial backoff delay; doubles on each retry
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: default_fetch_concurrency(),
+            quota_units_per_sec: default_quota_units_per_sec(),
+            max_attempts: default_max_attempts(),
+            max_failed_syncs: default_max_failed_syncs(),
+            retry_base_delay_ms: default_retry_base_delay_ms(),
+        }
+    }
+}
+
+/// PDF text extraction (the optional `[pdf]` table).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PdfConfig {
+    #[serde(default)]
+    pub text_mode: TextMode,
+    /// Passwords to try on encrypted PDFs (`[[pdf.passwords]]`)
+    #[serde(default)]
+    pub passwords: Vec<PdfPasswords>,
+}
+
+impl PdfConfig {
+    /// Passwords configured for a sender, in config order.
+    pub fn passwords_for(&self, from_addr: Option<&str>) -> Vec<&str> {
+        let Some(from) = from_addr.map(str::to_lowercase) else {
+            return Vec::new();
+        };
+        self.passwords
+            .iter()
+            .filter(|p| from.contains(&p.sender.to_lowercase()))
+            .flat_map(|p| p.passwords.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// Passwords for the encrypted statements of one sender.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PdfPasswords {
+    /// Matched case-insensitively against the From header, e.g. `@dhl.com`
+    pub sender: String,
+    pub passwords: Vec<String>,
+}
+
+/// How page text is laid out before it reaches heuristics / the LLM.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextMode {
+    /// pdf-extract's reading-order text; columns may run together
+    #[default]
+    Plain,
+    /// Positioned text runs regrouped into lines, with columns kept apart
+    Layout,
+}
+
+/// Local OCR for scanned PDFs (the optional `[ocr]` table).
+#[derive(Debug, Clone, Deserialize)]
+pub struct OcrConfig {
+    /// poppler's rasterizer
+    #[serde(default = "default_pdftoppm")]
+    pub pdftoppm: String,
+    #[serde(default = "default_tesseract")]
+    pub tesseract: String,
+    /// Render resolution; tesseract works best at 300
+    #[serde(default = "default_ocr_dpi")]
+    pub dpi: u32,
+    /// Tesseract language(s), e.g. `eng` or `eng+deu`
+    #[serde(default = "default_ocr_lang")]
+    pub lang: String,
+}
+
+impl Default for OcrConfig {
+    fn default() -> Self {
+        Self {
+            pdftoppm: default_pdftoppm(),
+            tesseract: default_tesseract(),
+            dpi: default_ocr_dpi(),
+            lang: default_ocr_lang(),
+        }
+    }
+}
+
+fn default_pdftoppm() -> String {
+    "pdftoppm".to_string()
+}
+
+fn default_tesseract() -> String {
+    "tesseract".to_string()
+}
+
+fn default_ocr_dpi() -> u32 {
+    300
+}
+
+fn default_ocr_lang() -> String {
+    "eng".to_string()
+}
+
+fn default_fetch_concurrency() -> usize {
+    8
+}
+
+fn default_quota_units_per_sec() -> u32 {
+    250
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_max_failed_syncs() -> u32 {
+    10
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
 // ---------------------------------------------------------------------------
 // LLM configuration (loaded from a separate llm_conf.toml)
 // ---------------------------------------------------------------------------
@@ -25,18 +238,25 @@ pub struct LlmConfig {
 }
 
 /// Which extraction backend to use.
-#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum LlmBackend {
+    #[default]
     Ollama,
     Cliproxy,
     Remote,
     Heuristics,
 }
 
-impl Default for LlmBackend {
-    fn default() -> Self {
-        Self::Ollama
+impl LlmBackend {
+    /// Lowercase name as used in config files and recorded in the DB.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ollama => "ollama",
+            Self::Cliproxy => "cliproxy",
+            Self::Remote => "remote",
+            Self::Heuristics => "heuristics",
+        }
     }
 }
 
@@ -50,6 +270,8 @@ pub struct LlmSection {
     pub cliproxy: CliProxyConfig,
     #[serde(default)]
     pub remote: RemoteConfig,
+    #[serde(default)]
+    pub vision: VisionConfig,
 }
 
 impl Default for LlmSection {
@@ -59,16 +281,59 @@ impl Default for LlmSection {
             ollama: OllamaConfig::default(),
             cliproxy: CliProxyConfig::default(),
             remote: RemoteConfig::default(),
+            vision: VisionConfig::default(),
         }
     }
 }
 
+/// Vision-model extraction for scanned PDFs (the `[llm.vision]` table).
+/// The model itself is the backend's `vision_model`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VisionConfig {
+    #[serde(default = "default_vision_enabled")]
+    pub enabled: bool,
+    /// poppler's rasterizer, used to render pages to PNG
+    #[serde(default = "default_pdftoppm")]
+    pub pdftoppm: String,
+    #[serde(default = "default_vision_dpi")]
+    pub dpi: u32,
+    /// Only the first N pages are sent, to bound request size
+    #[serde(default = "default_vision_max_pages")]
+    pub max_pages: u32,
+}
+
+impl Default for VisionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_vision_enabled(),
+            pdftoppm: default_pdftoppm(),
+            dpi: default_vision_dpi(),
+            max_pages: default_vision_max_pages(),
+        }
+    }
+}
+
+fn default_vision_enabled() -> bool {
+    true
+}
+
+fn default_vision_dpi() -> u32 {
+    150
+}
+
+fn default_vision_max_pages() -> u32 {
+    4
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct OllamaConfig {
     #[serde(default = "default_ollama_url")]
     pub base_url: String,
     #[serde(default = "default_ollama_model")]
     pub model: String,
+    /// Image-capable model for scanned PDFs
+    #[serde(default = "default_ollama_vision_model")]
+    pub vision_model: String,
 }
 
 impl Default for OllamaConfig {
@@ -76,6 +341,7 @@ impl Default for OllamaConfig {
         Self {
             base_url: default_ollama_url(),
             model: default_ollama_model(),
+            vision_model: default_ollama_vision_model(),
         }
     }
 }
@@ -88,12 +354,19 @@ fn default_ollama_model() -> String {
     "qwen3:8b".to_string()
 }
 
+fn default_ollama_vision_model() -> String {
+    "qwen2.5vl:7b".to_string()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct CliProxyConfig {
     #[serde(default = "default_cliproxy_url")]
     pub base_url: String,
     #[serde(default = "default_cliproxy_model")]
     pub model: String,
+    /// Image-capable model for scanned PDFs
+    #[serde(default = "default_cliproxy_vision_model")]
+    pub vision_model: String,
 }
 
 impl Default for CliProxyConfig {
@@ -101,6 +374,7 @@ impl Default for CliProxyConfig {
         Self {
             base_url: default_cliproxy_url(),
             model: default_cliproxy_model(),
+            vision_model: default_cliproxy_vision_model(),
         }
     }
 }
@@ -113,12 +387,19 @@ fn default_cliproxy_model() -> String {
     "claude-sonnet-4-20250514".to_string()
 }
 
+fn default_cliproxy_vision_model() -> String {
+    "claude-sonnet-4-20250514".to_string()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct RemoteConfig {
     #[serde(default = "default_remote_url")]
     pub base_url: String,
     #[serde(default = "default_remote_model")]
     pub model: String,
+    /// Image-capable model for scanned PDFs
+    #[serde(default = "default_remote_vision_model")]
+    pub vision_model: String,
 }
 
 impl Default for RemoteConfig {
@@ -126,6 +407,7 @@ impl Default for RemoteConfig {
         Self {
             base_url: default_remote_url(),
             model: default_remote_model(),
+            vision_model: default_remote_vision_model(),
         }
     }
 }
@@ -138,45 +420,52 @@ fn default_remote_model() -> String {
     "gpt-4o".to_string()
 }
 
+fn default_remote_vision_model() -> String {
+    "gpt-4o".to_string()
+}
+
 #[derive(Deserialize)]
 pub struct GmailConfig {
     pub client_id: String,
     pub client_secret: String,
+    /// Filled in by `auth login`; may be absent before the first login
+    #[serde(default)]
     pub tokens: Tokens,
-    #[serde(rename = "cmd_urls")]
-    pub cmds: CmdStrings,
+    #[serde(default)]
     pub urls: AuthUrls,
-    pub grants: Grant,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Default, Deserialize)]
 pub struct Tokens {
+    #[serde(default)]
     pub refresh_token: String,
+    #[serde(default)]
     pub access_token: String,
-    #[serde(rename = "authinitial")]
-    pub auth_initial: String,
-}
-
-#[derive(Deserialize)]
-pub struct CmdStrings {
-    pub initial_auth: String,
-    pub refresh_curl: String,
 }
 
 #[derive(Deserialize)]
 pub struct AuthUrls {
+    #[serde(default = "default_token_url")]
     pub token_url: String,
+    #[serde(default = "default_auth_url")]
     pub auth_url: String,
 }
 
-#[derive(Deserialize)]
-pub struct Metadata {
-    pub scopes: Vec<String>,
+impl Default for AuthUrls {
+    fn default() -> Self {
+        Self {
+            token_url: default_token_url(),
+            auth_url: default_auth_url(),
+        }
+    }
 }
 
-#[derive(Deserialize)]
-pub struct Grant {
-    pub refresh: String,
+fn default_token_url() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+fn default_auth_url() -> String {
+    "https://accounts.google.com/o/oauth2/auth".to_string()
 }
 
 impl LlmConfig {
@@ -203,20 +492,215 @@ impl LlmConfig {
 
 impl Config {
     pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = fs::read_to_string(path)?;
-        Ok(toml::from_str(&content)?)
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config {}: {e}", path.display()))?;
+        let mut cfg: Config = toml::from_str(&content)?;
+        cfg.path = path.to_path_buf();
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Mailbox names become part of token file names, so anything that
+    /// could leave the config directory (`/`, `..`) is refused.
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let safe = |name: &str| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        };
+        match self.mailboxes.iter().find(|m| !safe(&m.name)) {
+            Some(bad) => Err(format!(
+                "Invalid mailbox name '{}': use only letters, digits, '_' and '-'",
+                bad.name
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Look up a mailbox by name, or the only mailbox when `name` is `None`.
+    pub fn mailbox(
+        &self,
+        name: Option<&str>,
+    ) -> Result<&MailboxConfig, Box<dyn std::error::Error>> {
+        let names = || {
+            self.mailboxes
+                .iter()
+                .map(|m| m.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match name {
+            Some(name) => self
+                .mailboxes
+                .iter()
+                .find(|m| m.name == name)
+                .ok_or_else(|| {
+                    format!("No mailbox named '{name}' (configured: {})", names()).into()
+                }),
+            None => match self.mailboxes.as_slice() {
+                [only] => Ok(only),
+                [] => Err("No [[mailboxes]] configured".into()),
+                _ => Err(format!("Several mailboxes configured — pick one of: {}", names()).into()),
+            },
+        }
+    }
+
+    /// Profiles to run: the one named `name`, or all of them.
+    pub fn profiles(
+        &self,
+        name: Option<&str>,
+    ) -> Result<Vec<&ProfileConfig>, Box<dyn std::error::Error>> {
+        match name {
+            None => Ok(self.profiles.iter().collect()),
+            Some(name) => {
+                let profile = self
+                    .profiles
+                    .iter()
+                    .find(|p| p.name == name)
+                    .ok_or_else(|| {
+                        let names: Vec<_> = self.profiles.iter().map(|p| p.name.as_str()).collect();
+                        format!(
+                            "No profile named '{name}' (configured: {})",
+                            names.join(", ")
+                        )
+                    })?;
+                Ok(vec![profile])
+            }
+        }
+    }
+
+    /// Refresh/access tokens to seed a mailbox's token store with. A single
+    /// mailbox without its own tokens inherits the legacy `[gmail_oauth.tokens]`.
+    pub fn mailbox_tokens(&self, mailbox: &MailboxConfig) -> Tokens {
+        if mailbox.tokens.refresh_token.is_empty() && self.mailboxes.len() == 1 {
+            self.gmail.tokens.clone()
+        } else {
+            mailbox.tokens.clone()
+        }
     }
 
     pub fn update_access_token(
         path: impl AsRef<Path>,
+        mailbox: &str,
+        new_token: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Self::update_token_field(path, mailbox, "access_token", new_token)
+    }
+
+    pub fn update_refresh_token(
+        path: impl AsRef<Path>,
+        mailbox: &str,
+        new_token: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Self::update_token_field(path, mailbox, "refresh_token", new_token)
+    }
+
+    /// Rewrite one key of a mailbox's `tokens` table, preserving the rest of
+    /// the file (comments, ordering) and creating the table if it is missing.
+    /// The file is replaced atomically and left readable by its owner only,
+    /// as it holds every mailbox's credentials.
+    fn update_token_field(
+        path: impl AsRef<Path>,
+        mailbox: &str,
+        key: &str,
         new_token: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let content = fs::read_to_string(&path)?;
         let mut doc = content.parse::<DocumentMut>()?;
 
-        doc["gmail_oauth"]["tokens"]["access_token"] = value(new_token);
-
-        fs::write(&path, doc.to_string())?;
+        let entry = doc
+            .get_mut("mailboxes")
+            .and_then(Item::as_array_of_tables_mut)
+            .and_then(|tables| {
+                tables
+                    .iter_mut()
+                    .find(|t| t.get("name").and_then(|n| n.as_str()) == Some(mailbox))
+            })
+            .ok_or_else(|| format!("No [[mailboxes]] entry named '{mailbox}'"))?;
+        entry["tokens"][key] = value(new_token);
+
+        write_private(path.as_ref(), doc.to_string().as_bytes())?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_retries_and_failed_syncs_are_separate() {
+        let cfg: FetchConfig = toml::from_str("max_attempts = 8\n").unwrap();
+        assert_eq!(cfg.max_attempts, 8);
+        assert_eq!(cfg.max_failed_syncs, default_max_failed_syncs());
+
+        let cfg: FetchConfig = toml::from_str("max_failed_syncs = 2\n").unwrap();
+        assert_eq!(cfg.max_attempts, default_max_attempts());
+        assert_eq!(cfg.max_failed_syncs, 2);
+    }
+
+    #[test]
+    fn test_update_tokens_creates_missing_table() {
+        let path =
+            std::env::temp_dir().join(format!("invoice_search_cfg_{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "# team config\n[gmail_oauth]\nclient_id = \"id\"\nclient_secret = \"secret\"\n\n\
+             [[mailboxes]]\nname = \"ops\"\nuser = \"ops@example.com\"\n\n\
+             [[mailboxes]]\nname = \"ap\"\nuser = \"ap@example.com\"\n\n\
+             [[profiles]]\nname = \"maxsoft\"\nquery = \"from:x\"\nmailboxes = [\"ap\"]\n\
+             [profiles.extraction]\nbackend = \"heuristics\"\n",
+        )
+        .unwrap();
+
+        Config::update_refresh_token(&path, "ap", "refresh-1").unwrap();
+        Config::update_access_token(&path, "ap", "access-1").unwrap();
+        assert!(Config::update_access_token(&path, "nope", "x").is_err());
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# team config"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!path.with_extension("toml.tmp").exists());
+        let cfg: Config = toml::from_str(&content).unwrap();
+        let ap = cfg.mailbox(Some("ap")).unwrap();
+        assert_eq!(ap.user, "ap@example.com");
+        let profiles = cfg.profiles(Some("maxsoft")).unwrap();
+        assert_eq!(profiles[0].label(), "maxsoft");
+        assert!(profiles[0].applies_to(ap));
+        assert!(!profiles[0].applies_to(cfg.mailbox(Some("ops")).unwrap()));
+        assert_eq!(profiles[0].extraction.backend, Some(LlmBackend::Heuristics));
+        assert!(cfg.profiles(Some("fedex")).is_err());
+        assert_eq!(cfg.mailbox_tokens(ap).refresh_token, "refresh-1");
+        assert_eq!(ap.tokens.access_token, "access-1");
+        assert!(
+            cfg.mailbox(Some("ops"))
+                .unwrap()
+                .tokens
+                .refresh_token
+                .is_empty()
+        );
+        assert!(cfg.mailbox(None).is_err());
+        assert_eq!(cfg.gmail.urls.token_url, default_token_url());
+        assert!(Config::load(&path).is_ok());
+
+        // The name becomes gmail_token_<name>.json, so it can't be a path
+        for bad in ["../ops", "a/b", "", "ops box"] {
+            let content = content.replace("name = \"ops\"", &format!("name = \"{bad}\""));
+            fs::write(&path, content).unwrap();
+            let Err(err) = Config::load(&path) else {
+                panic!("accepted mailbox name {bad:?}");
+            };
+            assert!(err.to_string().contains("Invalid mailbox name"), "{err}");
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
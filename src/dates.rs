@@ -0,0 +1,213 @@
+// src/dates.rs
+
+use regex::Regex;
+use serde::Deserialize;
+use time::format_description::well_known::Rfc2822;
+use time::{Date, Month, OffsetDateTime, UtcOffset};
+
+/// How to read an all-numeric date whose first two fields could both be a
+/// day or a month, like `03/02/2026`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DayOrder {
+    /// 03/02/2026 is 3 February
+    #[default]
+    DayFirst,
+    /// 03/02/2026 is 2 March
+    MonthFirst,
+}
+
+/// Day/month order for ambiguous invoice dates (the optional `[dates]` table).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DateHints {
+    #[serde(default)]
+    pub order: DayOrder,
+    /// Vendors that write dates the other way (`[[dates.vendors]]`)
+    #[serde(default)]
+    pub vendors: Vec<VendorDayOrder>,
+}
+
+/// Day/month order of one vendor's invoices.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VendorDayOrder {
+    /// Matched case-insensitively against the extracted vendor name
+    pub vendor: String,
+    pub order: DayOrder,
+}
+
+impl DateHints {
+    /// Order for a vendor's invoices: the first `[[dates.vendors]]` entry
+    /// contained in its name, else the default.
+    pub fn order_for(&self, vendor: Option<&str>) -> DayOrder {
+        let Some(vendor) = vendor.map(str::to_lowercase) else {
+            return self.order;
+        };
+        self.vendors
+            .iter()
+            .find(|v| vendor.contains(&v.vendor.to_lowercase()))
+            .map_or(self.order, |v| v.order)
+    }
+}
+
+/// Parse an invoice date as printed: `2026-02-16`, `20260216`,
+/// `16/02/2026`, `16.02.26`, `February 16, 2026`, `16-Feb-2026`,
+/// `Monday, 16th February 2026`, ... `order` only matters when neither of
+/// the first two numeric fields is above 12. Two-digit years are 20xx.
+pub fn parse_invoice_date(raw: &str, order: DayOrder) -> Option<Date> {
+    let weekday = Regex::new(r"(?i)^(?:mon|tue|wed|thu|fri|sat|sun)[a-z]*\.?,?\s+").unwrap();
+    let ordinal = Regex::new(r"(?i)(\d)(?:st|nd|rd|th)\b").unwrap();
+    let text = raw.trim().trim_end_matches('.');
+    let text = weekday.replace(text, "");
+    let text = ordinal.replace_all(&text, "$1");
+    let text = text.as_ref();
+
+    let iso = Regex::new(r"^(\d{4})[-/.](\d{1,2})[-/.](\d{1,2})(?:[T\s].*)?$").unwrap();
+    let compact = Regex::new(r"^(\d{4})(\d{2})(\d{2})$").unwrap();
+    let numeric = Regex::new(r"^(\d{1,2})[-/. ](\d{1,2})[-/. ](\d{4}|\d{2})$").unwrap();
+    let month_first = Regex::new(r"^([A-Za-z]+)\.?[\s-]+(\d{1,2}),?[\s-]+(\d{4}|\d{2})$").unwrap();
+    let day_first = Regex::new(r"^(\d{1,2})[\s-]+([A-Za-z]+)\.?,?[\s-]+(\d{4}|\d{2})$").unwrap();
+
+    let num = |s: &str| s.parse::<u32>().ok();
+    let (year, month, day) = if let Some(c) = iso.captures(text).or(compact.captures(text)) {
+        (num(&c[1])?, num(&c[2])?, num(&c[3])?)
+    } else if let Some(c) = numeric.captures(text) {
+        let (a, b) = (num(&c[1])?, num(&c[2])?);
+        let day_first = match (a > 12, b > 12) {
+            (true, true) => return None,
+            (true, false) => true,
+            (false, true) => false,
+            (false, false) => order == DayOrder::DayFirst,
+        };
+        let (day, month) = if day_first { (a, b) } else { (b, a) };
+        (year(&c[3])?, month, day)
+    } else if let Some(c) = month_first.captures(text) {
+        (year(&c[3])?, month_number(&c[1])?, num(&c[2])?)
+    } else if let Some(c) = day_first.captures(text) {
+        (year(&c[3])?, month_number(&c[2])?, num(&c[1])?)
+    } else {
+        return None;
+    };
+
+    let month = Month::try_from(u8::try_from(month).ok()?).ok()?;
+    Date::from_calendar_date(i32::try_from(year).ok()?, month, u8::try_from(day).ok()?).ok()
+}
+
+fn year(digits: &str) -> Option<u32> {
+    let year: u32 = digits.parse().ok()?;
+    Some(if digits.len() == 2 { 2000 + year } else { year })
+}
+
+/// 1-12 for an English month name or its abbreviation ("Sept" included).
+fn month_number(name: &str) -> Option<u32> {
+    const MONTHS: [&str; 12] = [
+        "january",
+        "february",
+        "march",
+        "april",
+        "may",
+        "june",
+        "july",
+        "august",
+        "september",
+        "october",
+        "november",
+        "december",
+    ];
+    let name = name.to_lowercase();
+    if name.len() < 3 {
+        return None;
+    }
+    MONTHS
+        .iter()
+        .position(|m| m.starts_with(&name))
+        .map(|i| i as u32 + 1)
+}
+
+/// Parse an RFC 2822 `Date` header, tolerating a trailing `(zone name)`
+/// comment and the obsolete `GMT` / `UT` / `UTC` zones.
+pub fn parse_message_date(raw: &str) -> Option<OffsetDateTime> {
+    let mut text = raw.trim();
+    if text.ends_with(')')
+        && let Some(i) = text.rfind(" (")
+    {
+        text = text[..i].trim_end();
+    }
+    let text = match text.rsplit_once(' ') {
+        Some((rest, "GMT" | "UT" | "UTC")) => format!("{rest} +0000"),
+        _ => text.to_string(),
+    };
+    OffsetDateTime::parse(&text, &Rfc2822).ok()
+}
+
+/// A message `Date` header as UTC ISO 8601 (`2026-02-16T01:30:05Z`), which
+/// sorts and compares as text.
+pub fn message_date_iso(raw: &str) -> Option<String> {
+    let utc = parse_message_date(raw)?.to_offset(UtcOffset::UTC);
+    Some(format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        utc.date(),
+        utc.hour(),
+        utc.minute(),
+        utc.second()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invoice_and_message_dates() {
+        let day = |y, m: u8, d| Date::from_calendar_date(y, Month::try_from(m).unwrap(), d).ok();
+        let parse = |s| parse_invoice_date(s, DayOrder::DayFirst);
+        for raw in [
+            "2026-02-16",
+            "2026/2/16",
+            "20260216",
+            "16/02/2026",
+            "16.02.26",
+            "February 16, 2026",
+            "Feb. 16 2026",
+            "16 February 2026",
+            "16-Feb-2026",
+            "Monday, 16th February 2026",
+            "2026-02-16T09:30:00",
+        ] {
+            assert_eq!(parse(raw), day(2026, 2, 16), "{raw}");
+        }
+        // A field above 12 settles the order whatever the hint says
+        assert_eq!(
+            parse_invoice_date("02/16/2026", DayOrder::DayFirst),
+            day(2026, 2, 16)
+        );
+        assert_eq!(parse("03/02/2026"), day(2026, 2, 3));
+        assert_eq!(
+            parse_invoice_date("03/02/2026", DayOrder::MonthFirst),
+            day(2026, 3, 2)
+        );
+        assert_eq!(parse("31/02/2026"), None);
+        assert_eq!(parse("Ma 16 2026"), None);
+        assert_eq!(parse("next week"), None);
+
+        let hints = DateHints {
+            order: DayOrder::DayFirst,
+            vendors: vec![VendorDayOrder {
+                vendor: "acme".to_string(),
+                order: DayOrder::MonthFirst,
+            }],
+        };
+        assert_eq!(hints.order_for(Some("ACME Corp")), DayOrder::MonthFirst);
+        assert_eq!(hints.order_for(Some("Soft Source")), DayOrder::DayFirst);
+        assert_eq!(hints.order_for(None), DayOrder::DayFirst);
+
+        assert_eq!(
+            message_date_iso("Mon, 16 Feb 2026 09:30:05 +0800 (SGT)").as_deref(),
+            Some("2026-02-16T01:30:05Z")
+        );
+        assert_eq!(
+            message_date_iso("16 Feb 2026 23:10:00 GMT").as_deref(),
+            Some("2026-02-16T23:10:00Z")
+        );
+        assert_eq!(message_date_iso("unknown"), None);
+    }
+}
@@ -1,131 +1,308 @@
-use crate::message_db::{MessageStore, StoredAttachment, StoredMessage};
+use crate::config::FetchConfig;
+use crate::message_db::{Blob, MessageStore, StoredAttachment, StoredMessage, SyncState};
 use crate::message_processor as mproc;
 use crate::message_processor::EmailData;
-use google_gmail1::api::{MessagePart, MessagePartHeader, Scope};
+use crate::rate_limit::TokenBucket;
+use crate::retry::{self, RetryPolicy};
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use google_gmail1::api::Scope;
 use hyper_rustls::HttpsConnector;
 use hyper_util::client::legacy::connect::HttpConnector;
-use tracing::{info, info_span};
+use std::collections::HashSet;
+use time::OffsetDateTime;
+use tracing::{info, warn};
 
-pub async fn fetch_msgs(
+/// How far before the last sync we re-query, to cover clock skew between
+/// Gmail's internal date and our own timestamp.
+const SYNC_OVERLAP_SECS: i64 = 24 * 60 * 60;
+
+type IdsFuture<'a> = std::pin::Pin<
+    Box<dyn std::future::Future<Output = Result<Vec<String>, Box<dyn std::error::Error>>> + 'a>,
+>;
+
+/// Gmail quota cost of a single `messages.get` call.
+const MESSAGE_GET_UNITS: u32 = 5;
+/// Gmail quota cost of a single `messages.attachments.get` call.
+const ATTACHMENT_GET_UNITS: u32 = 5;
+/// Gmail quota cost of a single `messages.list` call.
+const MESSAGE_LIST_UNITS: u32 = 5;
+/// Gmail quota cost of a single `history.list` call.
+const HISTORY_LIST_UNITS: u32 = 2;
+/// Gmail quota cost of a single `getProfile` call.
+const GET_PROFILE_UNITS: u32 = 1;
+
+/// A message fetched from Gmail, plus how many of its attachments were
+/// skipped because they were already in the database.
+pub struct FetchedMessage {
+    pub email: EmailData,
+    pub skipped_attachments: usize,
+}
+
+/// Fetch messages by id with up to `concurrency` messages in flight, yielding
+/// each one as soon as it (and its attachments) have arrived.
+///
+/// Every Gmail call first takes its quota units from `limiter` and is retried
+/// on transient errors according to `retry`. Each item carries the message id
+/// so a failure can be attributed to its message. Gmail may
+/// hand out a different attachment id on each fetch, so the per-attachment
+/// "already stored" check only catches partially stored messages; callers
+/// should drop already-stored message ids before calling this.
+pub fn fetch_msgs<'a>(
+    hub: &'a google_gmail1::Gmail<HttpsConnector<HttpConnector>>,
+    user: &'a str,
+    ids: Vec<String>,
+    db: &'a MessageStore,
+    concurrency: usize,
+    limiter: &'a TokenBucket,
+    retry: &'a RetryPolicy,
+) -> impl Stream<Item = (String, Result<FetchedMessage, Box<dyn std::error::Error>>)> + 'a {
+    stream::iter(ids)
+        .map(move |id| async move {
+            let result = fetch_msg(hub, user, &id, db, limiter, retry).await;
+            (id, result)
+        })
+        .buffer_unordered(concurrency.max(1))
+}
+
+/// Fetch a single message and download its not-yet-stored PDF attachments
+/// concurrently.
+async fn fetch_msg(
     hub: &google_gmail1::Gmail<HttpsConnector<HttpConnector>>,
     user: &str,
-    ids: Vec<String>,
-) -> Result<Vec<EmailData>, Box<dyn std::error::Error>> {
-    let mut emails = Vec::new();
+    id: &str,
+    db: &MessageStore,
+    limiter: &TokenBucket,
+    retry: &RetryPolicy,
+) -> Result<FetchedMessage, Box<dyn std::error::Error>> {
+    info!(user = %user, id = %id, "Starting email fetch");
+    let (_, email) = retry
+        .run("messages.get", || async move {
+            limiter.acquire(MESSAGE_GET_UNITS).await;
+            hub.users()
+                .messages_get(user, id)
+                .add_scope(Scope::Readonly)
+                .doit()
+                .await
+        })
+        .await?;
 
-    for id in ids {
-        info!(user = %user, id = %id, "Starting email fetch");
-        let (_, email) = hub
-            .users()
-            .messages_get(user, &id)
-            .add_scope(Scope::Readonly)
-            .doit()
-            .await?;
+    info!(mail = ?email.id, "Fetched mail id:");
 
-        info!(mail = ?email.id, "Fetched mail id:");
+    let payload = email.payload.as_ref().unwrap();
 
-        let payload = email.payload.as_ref().unwrap();
+    let headers = mproc::get_headers(
+        payload.headers.as_ref(),
+        vec!["From", "Subject", "To", "Date"],
+    );
 
-        let headers = mproc::get_headers(
-            payload.headers.as_ref(),
-            vec!["From", "Subject", "To", "Date"],
-        );
+    info!(
+        from = headers.first().unwrap_or(&""),
+        // subj = headers.get(1).unwrap_or(&""),
+        // to = headers.get(2).unwrap_or(&""),
+        date = headers.get(3).unwrap_or(&""),
+        "MAIL: "
+    );
 
-        info!(
-            from = headers.get(0).unwrap_or(&""),
-            // subj = headers.get(1).unwrap_or(&""),
-            // to = headers.get(2).unwrap_or(&""),
-            date = headers.get(3).unwrap_or(&""),
-            "MAIL: "
-        );
+    let mut mail_data = mproc::get_email_data(
+        email.payload.as_ref(),
+        id.to_string(),
+        payload.headers.as_ref(),
+    );
+    let uid = MessageStore::generate_uid(id, mail_data.date.as_deref().unwrap_or("unknown"), user);
 
-        let mail_data =
-            mproc::get_email_data(email.payload.as_ref(), id.clone(), payload.headers.as_ref());
-
-        // Fetch actual PDF data for attachments that only have an attachment_id
-        let mut mail_data = mail_data;
-        for attachment in &mut mail_data.attachments {
-            if attachment.data.is_none() {
-                if let Some(att_id) = &attachment.attachment_id {
-                    info!(filename = %attachment.filename, "Fetching attachment data");
-                    let (_, att) = hub
-                        .users()
-                        .messages_attachments_get(user, &id, att_id)
-                        .add_scope(Scope::Readonly)
-                        .doit()
-                        .await?;
-                    attachment.data = att.data;
-                }
+    // Work out which attachments only have an attachment_id and still need fetching
+    let mut skipped_attachments = 0;
+    let mut pending = Vec::new();
+    for (idx, attachment) in mail_data.attachments.iter().enumerate() {
+        if attachment.data.is_none()
+            && let Some(att_id) = &attachment.attachment_id
+        {
+            if db.has_attachment(&uid, att_id)? {
+                info!(filename = %attachment.filename, "Attachment already stored — skipping fetch");
+                skipped_attachments += 1;
+                continue;
             }
+            pending.push((idx, attachment.filename.as_str(), att_id.as_str()));
         }
+    }
+
+    let downloads = pending
+        .into_iter()
+        .map(|(idx, filename, att_id)| async move {
+            info!(filename = %filename, "Fetching attachment data");
+            let (_, att) = retry
+                .run("messages.attachments.get", || async move {
+                    limiter.acquire(ATTACHMENT_GET_UNITS).await;
+                    hub.users()
+                        .messages_attachments_get(user, id, att_id)
+                        .add_scope(Scope::Readonly)
+                        .doit()
+                        .await
+                })
+                .await?;
+            Ok::<_, google_gmail1::Error>((idx, att.data))
+        });
+    let downloaded = future::try_join_all(downloads).await?;
 
-        emails.push(mail_data);
+    for (idx, data) in downloaded {
+        mail_data.attachments[idx].data = data;
     }
-    Ok(emails)
+
+    Ok(FetchedMessage {
+        email: mail_data,
+        skipped_attachments,
+    })
 }
 
 /// Fetch messages by IDs, store them (with PDF attachments) in the database, and return the count stored.
+///
+/// Messages already stored with all their attachments are skipped without
+/// calling Gmail, and each fetched message is stored as soon as it arrives
+/// (attachments already present are not downloaded again). A message whose
+/// Gmail calls still fail after retrying is recorded in `fetch_failures`
+/// under `query` (and retried by that query's next sync) instead of aborting
+/// the run. `limiter` is the mailbox's quota budget, shared with the sync
+/// that selected `ids`.
 pub async fn fetch_and_store(
     hub: &google_gmail1::Gmail<HttpsConnector<HttpConnector>>,
     user: &str,
+    query: &str,
     ids: Vec<String>,
     db: &MessageStore,
+    fetch_cfg: &FetchConfig,
+    limiter: &TokenBucket,
 ) -> Result<usize, Box<dyn std::error::Error>> {
-    let msgs = fetch_msgs(hub, user, ids).await?;
+    let mut pending = Vec::with_capacity(ids.len());
+    let mut skipped_messages = 0;
+    for id in ids {
+        if db.has_message(user, &id)? {
+            info!(user = %user, id = %id, "Message already stored — skipping fetch");
+            // Another query may have stored it since it failed here
+            db.clear_fetch_failure(user, query, &id)?;
+            skipped_messages += 1;
+        } else {
+            pending.push(id);
+        }
+    }
+
+    info!(
+        pending = pending.len(),
+        concurrency = fetch_cfg.concurrency,
+        quota_units_per_sec = fetch_cfg.quota_units_per_sec,
+        "Fetching messages"
+    );
+
+    let retry = RetryPolicy::from_config(fetch_cfg);
+    let mut fetched = std::pin::pin!(fetch_msgs(
+        hub,
+        user,
+        pending,
+        db,
+        fetch_cfg.concurrency,
+        limiter,
+        &retry
+    ));
+
     let mut count = 0;
+    let mut failed = 0;
+    let mut skipped_attachments = 0;
 
-    for msg in &msgs {
-        let message_id = msg.message_id.as_ref().unwrap();
-        let unknown = String::from("unknown");
-        let date = msg.date.as_ref().unwrap_or(&unknown);
-
-        let uid = MessageStore::generate_uid(message_id, date, user);
-
-        let stored_msg = StoredMessage {
-            uid: uid.clone(),
-            message_id: message_id.clone(),
-            user: user.to_string(),
-            date: date.clone(),
-            from_addr: msg.from_addr.clone(),
-            subject: msg.subject.clone(),
-            plain_text: msg.plain.clone(),
-            html: msg.html.clone(),
-            has_attachments: !msg.attachments.is_empty(),
-            is_processed: false,
-        };
-
-        db.upsert_message(&stored_msg)?;
-
-        for attachment in &msg.attachments {
-            if let Some(pdf_data) = &attachment.data {
-                info!(message_id = ?message_id, attachment_id = ?attachment.attachment_id, "STORING ATTACHMENT");
-                let stored_attachment = StoredAttachment {
-                    id: None,
-                    message_uid: uid.clone(),
-                    filename: attachment.filename.clone(),
-                    attachment_id: attachment.attachment_id.clone(),
-                    pdf_data: pdf_data.clone(),
-                    is_processed: false,
-                    content_type: None,
-                    extracted_text: None,
-                };
-                db.insert_attachment(&stored_attachment)?;
+    while let Some((id, result)) = fetched.next().await {
+        match result {
+            Ok(msg) => {
+                skipped_attachments += msg.skipped_attachments + store_msg(db, user, &msg.email)?;
+                db.clear_fetch_failure(user, query, &id)?;
+                count += 1;
+            }
+            Err(e) => {
+                tracing::error!(user = %user, id = %id, error = %e, "Failed to fetch message");
+                db.record_fetch_failure(user, query, &id, &e.to_string())?;
+                failed += 1;
             }
         }
-
-        info!(uid = %uid, id = %message_id, attachments = msg.attachments.len(), "STORED");
-        count += 1;
     }
 
+    info!(
+        stored = count,
+        failed = failed,
+        skipped_messages = skipped_messages,
+        skipped_attachments = skipped_attachments,
+        "Fetch complete — skipped {} already stored",
+        skipped_messages + skipped_attachments
+    );
+
     Ok(count)
 }
 
+/// Store one fetched message and its PDF attachments in one transaction.
+/// Returns the number of attachments that were already present.
+fn store_msg(
+    db: &MessageStore,
+    user: &str,
+    msg: &EmailData,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let message_id = msg.message_id.as_ref().unwrap();
+    let unknown = String::from("unknown");
+    let date = msg.date.as_ref().unwrap_or(&unknown);
+
+    let uid = MessageStore::generate_uid(message_id, date, user);
+
+    let stored_msg = StoredMessage {
+        uid: uid.clone(),
+        message_id: message_id.clone(),
+        user: user.to_string(),
+        date: date.clone(),
+        from_addr: msg.from_addr.clone(),
+        subject: msg.subject.clone(),
+        plain_text: msg.plain.clone(),
+        html: msg.html.clone(),
+        has_attachments: !msg.attachments.is_empty(),
+        is_processed: false,
+    };
+
+    let attachments: Vec<StoredAttachment> = msg
+        .attachments
+        .iter()
+        .filter_map(|attachment| {
+            let pdf_data = attachment.data.as_ref()?;
+            info!(message_id = ?message_id, attachment_id = ?attachment.attachment_id, "STORING ATTACHMENT");
+            Some(StoredAttachment {
+                id: None,
+                message_uid: uid.clone(),
+                filename: attachment.filename.clone(),
+                attachment_id: attachment.attachment_id.clone(),
+                pdf_data: Blob::new(pdf_data.clone()),
+                is_processed: false,
+                content_type: None,
+                extracted_text: None,
+            })
+        })
+        .collect();
+    // Only PDFs Gmail can hand over count towards the message being complete
+    let attachment_count = msg
+        .attachments
+        .iter()
+        .filter(|a| a.data.is_some() || a.attachment_id.is_some())
+        .count();
+    let skipped = db
+        .store_message(&stored_msg, attachment_count, &attachments)?
+        .iter()
+        .filter(|id| id.is_none())
+        .count();
+
+    info!(uid = %uid, id = %message_id, attachments = msg.attachments.len(), "STORED");
+    Ok(skipped)
+}
+
 pub async fn get_message_ids(
     hub: &google_gmail1::Gmail<HttpsConnector<HttpConnector>>,
     query: &str,
     user: &str,
+    limiter: &TokenBucket,
+    retry: &RetryPolicy,
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    get_message_ids_recursive(hub, query, None, user).await
+    get_message_ids_recursive(hub, query, None, user, limiter, retry).await
 }
 
 fn get_message_ids_recursive<'a>(
@@ -133,19 +310,24 @@ fn get_message_ids_recursive<'a>(
     query: &'a str,
     page_token: Option<&'a str>,
     user: &'a str,
-) -> std::pin::Pin<
-    Box<dyn std::future::Future<Output = Result<Vec<String>, Box<dyn std::error::Error>>> + 'a>,
-> {
+    limiter: &'a TokenBucket,
+    retry: &'a RetryPolicy,
+) -> IdsFuture<'a> {
     info!(user = %user, query = %query, has_page_token = page_token.is_some(), "Starting id fetch");
 
     Box::pin(async move {
-        let mut req = hub.users().messages_list(user).q(query);
+        let (_, response) = retry
+            .run("messages.list", || async move {
+                limiter.acquire(MESSAGE_LIST_UNITS).await;
+                let mut req = hub.users().messages_list(user).q(query);
 
-        if let Some(token) = page_token {
-            req = req.page_token(token);
-        }
+                if let Some(token) = page_token {
+                    req = req.page_token(token);
+                }
 
-        let (_, response) = req.doit().await?;
+                req.doit().await
+            })
+            .await?;
 
         let mut ids: Vec<String> = response
             .messages
@@ -156,10 +338,201 @@ fn get_message_ids_recursive<'a>(
 
         if let Some(token) = response.next_page_token {
             info!(next_token = %token, "Fetching next page");
-            let mut next_ids = get_message_ids_recursive(hub, query, Some(&token), user).await?;
+            let mut next_ids =
+                get_message_ids_recursive(hub, query, Some(&token), user, limiter, retry).await?;
             ids.append(&mut next_ids);
         }
         info!(matches = ids.len(), "Page complete");
         Ok(ids)
     })
 }
+
+/// Message ids selected by a sync, plus the position to record once they
+/// have been stored.
+pub struct SyncBatch {
+    pub ids: Vec<String>,
+    /// Mailbox position to persist after the batch is stored; `None` when
+    /// Gmail did not report one.
+    pub next_state: Option<SyncState>,
+}
+
+impl SyncBatch {
+    /// Persist the sync position. Call only after the batch has been stored,
+    /// so a failed run is retried from the previous position.
+    pub fn commit(
+        &self,
+        db: &MessageStore,
+        user: &str,
+        query: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(state) = self.next_state {
+            db.set_sync_state(user, query, state)?;
+        }
+        Ok(())
+    }
+}
+
+/// Select message ids for `query`, using the Gmail History API when a
+/// previous sync position is stored for this user/query.
+///
+/// History only reports *which* messages were added, not whether they match
+/// the query, so new ids are intersected with the query re-run over a window
+/// starting just before the last sync. Falls back to a full listing when no
+/// position is stored, `full` is set, or Gmail reports the history id as
+/// expired (HTTP 404). Messages whose fetch failed on an earlier run of the
+/// same query are added back to the batch until they have failed
+/// `max_failed_syncs` runs in a row.
+pub async fn sync_message_ids(
+    hub: &google_gmail1::Gmail<HttpsConnector<HttpConnector>>,
+    query: &str,
+    user: &str,
+    db: &MessageStore,
+    full: bool,
+    limiter: &TokenBucket,
+    fetch_cfg: &FetchConfig,
+) -> Result<SyncBatch, Box<dyn std::error::Error>> {
+    let retry = RetryPolicy::from_config(fetch_cfg);
+    let mut batch = select_message_ids(hub, query, user, db, full, limiter, &retry).await?;
+
+    let failed = db.get_failed_message_ids(user, query, fetch_cfg.max_failed_syncs)?;
+    if !failed.is_empty() {
+        info!(user = %user, count = failed.len(), "Retrying previously failed messages");
+        let known: HashSet<String> = batch.ids.iter().cloned().collect();
+        batch
+            .ids
+            .extend(failed.into_iter().filter(|id| !known.contains(id)));
+    }
+
+    Ok(batch)
+}
+
+async fn select_message_ids(
+    hub: &google_gmail1::Gmail<HttpsConnector<HttpConnector>>,
+    query: &str,
+    user: &str,
+    db: &MessageStore,
+    full: bool,
+    limiter: &TokenBucket,
+    retry: &RetryPolicy,
+) -> Result<SyncBatch, Box<dyn std::error::Error>> {
+    let started_at = OffsetDateTime::now_utc().unix_timestamp();
+
+    let previous = if full {
+        info!(user = %user, query = %query, "Full sync requested");
+        None
+    } else {
+        db.get_sync_state(user, query)?
+    };
+
+    if let Some(state) = previous {
+        match get_added_message_ids(hub, user, state.history_id, limiter, retry).await {
+            Ok((added, latest)) => {
+                let ids = if added.is_empty() {
+                    Vec::new()
+                } else {
+                    let after = state.synced_at - SYNC_OVERLAP_SECS;
+                    let windowed = format!("{query} after:{after}");
+                    get_message_ids(hub, &windowed, user, limiter, retry)
+                        .await?
+                        .into_iter()
+                        .filter(|id| added.contains(id))
+                        .collect()
+                };
+                info!(
+                    user = %user,
+                    query = %query,
+                    added = added.len(),
+                    matching = ids.len(),
+                    "Incremental sync"
+                );
+                return Ok(SyncBatch {
+                    ids,
+                    next_state: Some(SyncState {
+                        history_id: latest.unwrap_or(state.history_id),
+                        synced_at: started_at,
+                    }),
+                });
+            }
+            Err(e) if retry::status_code(&e) == Some(404) => {
+                warn!(
+                    history_id = state.history_id,
+                    "History id expired — falling back to full sync"
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // Capture the mailbox position *before* listing so nothing that arrives
+    // during the listing is skipped next time.
+    let (_, profile) = retry
+        .run("getProfile", || async move {
+            limiter.acquire(GET_PROFILE_UNITS).await;
+            hub.users()
+                .get_profile(user)
+                .add_scope(Scope::Readonly)
+                .doit()
+                .await
+        })
+        .await?;
+    let ids = get_message_ids(hub, query, user, limiter, retry).await?;
+
+    Ok(SyncBatch {
+        ids,
+        next_state: profile.history_id.map(|history_id| SyncState {
+            history_id,
+            synced_at: started_at,
+        }),
+    })
+}
+
+/// Collect ids of messages added since `start_history_id`, along with the
+/// mailbox's current history id.
+async fn get_added_message_ids(
+    hub: &google_gmail1::Gmail<HttpsConnector<HttpConnector>>,
+    user: &str,
+    start_history_id: u64,
+    limiter: &TokenBucket,
+    retry: &RetryPolicy,
+) -> Result<(HashSet<String>, Option<u64>), google_gmail1::Error> {
+    let mut added = HashSet::new();
+    let mut latest = None;
+    let mut page_token: Option<String> = None;
+
+    loop {
+        info!(user = %user, start_history_id, has_page_token = page_token.is_some(), "Fetching history");
+        let token = page_token.as_deref();
+        let (_, response) = retry
+            .run("history.list", || async move {
+                limiter.acquire(HISTORY_LIST_UNITS).await;
+                let mut req = hub
+                    .users()
+                    .history_list(user)
+                    .start_history_id(start_history_id)
+                    .add_history_types("messageAdded")
+                    .add_scope(Scope::Readonly);
+                if let Some(token) = token {
+                    req = req.page_token(token);
+                }
+                req.doit().await
+            })
+            .await?;
+
+        added.extend(
+            response
+                .history
+                .unwrap_or_default()
+                .into_iter()
+                .flat_map(|h| h.messages_added.unwrap_or_default())
+                .filter_map(|m| m.message.and_then(|m| m.id)),
+        );
+        latest = response.history_id.or(latest);
+
+        match response.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => break,
+        }
+    }
+
+    Ok((added, latest))
+}
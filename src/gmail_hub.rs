@@ -1,57 +1,86 @@
 use google_gmail1::Gmail;
 use yup_oauth2::{ApplicationSecret, InstalledFlowAuthenticator, InstalledFlowReturnMethod};
 
-use crate::config::Config;
-use crate::simple_refresh::manual_refresh;
-use crate::simplestore::SimpleTokenStore;
+use crate::config::{Config, GmailConfig, MailboxConfig};
+use crate::simplestore::{FileTokenStore, seed_token};
+use google_gmail1::api::Scope;
 use hyper_rustls::HttpsConnector;
 use hyper_util::client::legacy::connect::HttpConnector;
-use std::env;
 use std::path::PathBuf;
+use tracing::info;
 
 #[cfg(debug_assertions)]
 fn config_dir() -> PathBuf {
     PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".config")
 }
 
-fn config_path() -> PathBuf {
+#[cfg(not(debug_assertions))]
+fn config_dir() -> PathBuf {
+    PathBuf::from(".config")
+}
+
+/// Config file used when `--config` is not given.
+pub fn default_config_path() -> PathBuf {
     config_dir().join("oath_cli.toml")
 }
 
-pub async fn create_hub()
-    -> Result<Gmail<HttpsConnector<HttpConnector>>, Box<dyn std::error::Error>>
-{
-    let cfg = Config::load(config_path())?;
-
-    let (tok, ttl) = if env::var("REFRESH").is_ok_and(|v| v == "1") {
-        println!("Refreshing....");
-        let token = manual_refresh(&cfg).await?;
-        Config::update_access_token(config_path(), &token.access_token)?;
-        (token.access_token, token.expires_in)
-    } else {
-        (cfg.gmail.tokens.access_token, 3599)
-    };
-
-    let secret = ApplicationSecret {
-        client_id: cfg.gmail.client_id,
-        client_secret: cfg.gmail.client_secret,
-        token_uri: cfg.gmail.urls.token_url,
-        auth_uri: cfg.gmail.urls.auth_url,
+/// Where a mailbox's refreshed OAuth tokens are persisted between runs:
+/// `gmail_token_<name>.json` next to the config file. `Config::load` has
+/// already refused names that are not plain file-name characters.
+fn token_path(cfg: &Config, mailbox: &str) -> PathBuf {
+    cfg.path
+        .with_file_name(format!("gmail_token_{mailbox}.json"))
+}
+
+/// Build the OAuth client secret from the `[gmail_oauth]` config block,
+/// applying any per-mailbox client override.
+fn application_secret(gmail: &GmailConfig, mailbox: &MailboxConfig) -> ApplicationSecret {
+    ApplicationSecret {
+        client_id: mailbox
+            .client_id
+            .clone()
+            .unwrap_or_else(|| gmail.client_id.clone()),
+        client_secret: mailbox
+            .client_secret
+            .clone()
+            .unwrap_or_else(|| gmail.client_secret.clone()),
+        token_uri: gmail.urls.token_url.clone(),
+        auth_uri: gmail.urls.auth_url.clone(),
         redirect_uris: vec!["http://localhost".to_string()],
         project_id: None,
         client_email: None,
         auth_provider_x509_cert_url: None,
         client_x509_cert_url: None,
-    };
+    }
+}
+
+/// Build an authenticated Gmail hub for one mailbox.
+///
+/// Tokens live in `gmail_token_<name>.json`; on first use the store is seeded
+/// from the mailbox's refresh token in `oath_cli.toml`. Expired access tokens
+/// are refreshed by the authenticator and written back automatically, unless
+/// `dry_run` is set, in which case they are only kept for this run.
+pub async fn create_hub(
+    cfg: &Config,
+    mailbox: &MailboxConfig,
+    dry_run: bool,
+) -> Result<Gmail<HttpsConnector<HttpConnector>>, Box<dyn std::error::Error>> {
+    let tokens = cfg.mailbox_tokens(mailbox);
+    let mut store = FileTokenStore::open(
+        token_path(cfg, &mailbox.name),
+        seed_token(&tokens.refresh_token, &tokens.access_token),
+    )?;
+    if dry_run {
+        store = store.without_writes();
+    }
 
-    let auth = InstalledFlowAuthenticator::builder(secret, InstalledFlowReturnMethod::HTTPRedirect)
-        .with_storage(Box::new(SimpleTokenStore {
-            access_token: tok,
-            refresh_token: cfg.gmail.tokens.refresh_token,
-            expires_in: ttl,
-        }))
-        .build()
-        .await?;
+    let auth = InstalledFlowAuthenticator::builder(
+        application_secret(&cfg.gmail, mailbox),
+        InstalledFlowReturnMethod::HTTPRedirect,
+    )
+    .with_storage(Box::new(store))
+    .build()
+    .await?;
 
     let client = hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
         .build(
@@ -64,3 +93,45 @@ pub async fn create_hub()
 
     Ok(Gmail::new(client, auth))
 }
+
+/// Run the installed-app OAuth flow and save the resulting tokens.
+///
+/// Starts a listener on a random localhost port, prints the Google consent
+/// URL, and exchanges the returned code. Consent is always prompted so Google
+/// issues a fresh refresh token, which is written into the config file and
+/// the mailbox's token file. `mailbox` may be omitted when only one is
+/// configured.
+pub async fn login(cfg: &Config, mailbox: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let mailbox = cfg.mailbox(mailbox)?;
+    let token_path = token_path(cfg, &mailbox.name);
+
+    let auth = InstalledFlowAuthenticator::builder(
+        application_secret(&cfg.gmail, mailbox),
+        InstalledFlowReturnMethod::HTTPRedirect,
+    )
+    .force_account_selection(true)
+    .with_storage(Box::new(FileTokenStore::empty(&token_path)))
+    .build()
+    .await?;
+
+    println!(
+        "Opening Google authorization for {} ({}) — follow the URL below in your browser.",
+        mailbox.name, mailbox.user
+    );
+    auth.token(&[Scope::Readonly.as_ref()]).await?;
+
+    let token = FileTokenStore::read_token(&token_path)?
+        .ok_or("Authorization finished but no token was stored")?;
+    let refresh_token = token
+        .refresh_token
+        .ok_or("Google did not return a refresh token — revoke access and retry")?;
+
+    Config::update_refresh_token(&cfg.path, &mailbox.name, &refresh_token)?;
+    if let Some(access_token) = token.access_token {
+        Config::update_access_token(&cfg.path, &mailbox.name, &access_token)?;
+    }
+
+    info!(mailbox = %mailbox.name, config = %cfg.path.display(), "Stored OAuth tokens");
+    println!("Logged in. Tokens saved to {}", cfg.path.display());
+    Ok(())
+}
@@ -1,20 +1,23 @@
 use super::{InvoiceData, LineItem, PackingItem, PackingTotals};
+use crate::money::{Currency, Money};
 use regex::Regex;
 
 /// Main extraction entry point — uses keyword-anchored regex patterns.
 pub fn extract(text: &str) -> InvoiceData {
+    let currency = extract_currency(text);
+    let money = Currency::of(currency.as_deref());
     InvoiceData {
         vendor: extract_vendor(text),
         buyer: extract_buyer(text),
         invoice_no: extract_invoice_no(text),
         invoice_date: extract_invoice_date(text),
-        currency: extract_currency(text),
-        total_amount: extract_total_amount(text),
+        currency,
+        total_amount: extract_total_amount(text, money),
         total_pieces: extract_total_pieces(text),
         ship_from: extract_ship_from(text),
         ship_to: extract_ship_to(text),
         shipping_method: extract_shipping_method(text),
-        line_items: extract_line_items(text),
+        line_items: extract_line_items(text, money),
         packing_items: extract_packing_items(text),
         packing_totals: extract_packing_totals(text),
     }
@@ -24,7 +27,7 @@ pub fn extract(text: &str) -> InvoiceData {
 // Scalar field extractors
 // ---------------------------------------------------------------------------
 
-fn extract_invoice_no(text: &str) -> Option<String> {
+pub(super) fn extract_invoice_no(text: &str) -> Option<String> {
     // Matches "Invoice No." or "Invoice No" followed by optional punctuation then the value
     let re = Regex::new(r"(?i)Invoice\s+No\.?\s*:?\s*([A-Za-z0-9\-/]+)").ok()?;
     re.captures(text).map(|c| c[1].trim().to_string())
@@ -48,7 +51,7 @@ fn extract_currency(text: &str) -> Option<String> {
     Some(if raw == "US$" { "USD".to_string() } else { raw })
 }
 
-fn extract_total_amount(text: &str) -> Option<f64> {
+fn extract_total_amount(text: &str, currency: Currency) -> Option<Money> {
     // Look for "TOTAL" followed by a number (the invoice grand total).
     // We want the TOTAL that sits near the line items, not packing totals.
     // Strategy: find all "TOTAL" + number pairs, take the one before "PACKING LIST".
@@ -60,13 +63,9 @@ fn extract_total_amount(text: &str) -> Option<f64> {
 
     let re = Regex::new(r"(?i)TOTAL\s+(\d[\d,]*\.?\d*)").ok()?;
     // Take the last TOTAL match in the invoice section (skips sub-totals)
-    let mut last: Option<f64> = None;
-    for cap in re.captures_iter(invoice_section) {
-        if let Ok(v) = cap[1].replace(',', "").parse::<f64>() {
-            last = Some(v);
-        }
-    }
-    last
+    re.captures_iter(invoice_section)
+        .filter_map(|cap| Money::parse(&cap[1], currency))
+        .last()
 }
 
 fn extract_total_pieces(text: &str) -> Option<u32> {
@@ -88,10 +87,10 @@ fn extract_vendor(text: &str) -> Option<String> {
 
     // Return the first company that isn't the buyer
     for company in &companies {
-        if let Some(ref b) = buyer {
-            if !company.to_uppercase().contains(&b.to_uppercase()) {
-                return Some(company.clone());
-            }
+        if let Some(ref b) = buyer
+            && !company.to_uppercase().contains(&b.to_uppercase())
+        {
+            return Some(company.clone());
         }
     }
 
@@ -135,7 +134,7 @@ fn extract_company_names(text: &str) -> Vec<String> {
 // Line items extraction
 // ---------------------------------------------------------------------------
 
-fn extract_line_items(text: &str) -> Vec<LineItem> {
+fn extract_line_items(text: &str, currency: Currency) -> Vec<LineItem> {
     let mut items = Vec::new();
 
     // Strategy: find all number clusters that look like qty + unit_price + amount
@@ -171,43 +170,45 @@ fn extract_line_items(text: &str) -> Vec<LineItem> {
         .collect();
 
     // Collect all decimal amounts in the invoice section
-    let amounts: Vec<f64> = amount_re
+    let amounts: Vec<Money> = amount_re
         .captures_iter(invoice_section)
-        .filter_map(|c| c[1].replace(',', "").parse::<f64>().ok())
+        .filter_map(|c| Money::parse(&c[1], currency))
         .collect();
 
+    // Without row structure the lists can only be paired by position, which
+    // is only safe when every row produced a description. Otherwise keep the
+    // quantities and amounts and leave descriptions blank rather than shift
+    // them onto the wrong rows.
+    let descriptions = if descriptions.len() == quantities.len() || quantities.is_empty() {
+        descriptions
+    } else {
+        vec![String::new(); quantities.len()]
+    };
+
     // Match them up: for each description + qty, find the line amount and unit price.
     // Amounts typically come in pairs per item: line total, then unit price
     // or the pattern is: amount ... unit_price near TOTAL
     for (i, desc) in descriptions.iter().enumerate() {
         let qty = quantities.get(i).copied().unwrap_or(0);
 
-        // Find amounts that correspond to this item.
-        // Heuristic: amounts > 100 are likely line totals, amounts < 100 likely unit prices
-        // when we have small qty items. Better: line_total = qty * unit_price.
-        // Try to find a pair where a * b / qty ≈ 1 of the other values.
+        // Find amounts that correspond to this item: a line total that
+        // splits evenly into qty units of a price also on the invoice.
         let mut item = LineItem {
             description: desc.clone(),
             qty,
-            unit_price: 0.0,
-            amount: 0.0,
+            unit_price: Money::zero(currency),
+            amount: Money::zero(currency),
         };
 
-        // Simple approach: look for amounts that divide evenly by qty
         if qty > 0 {
-            for &amt in &amounts {
-                let candidate_unit = amt / qty as f64;
-                // Check if this unit price also appears in the amounts list
-                for &other in &amounts {
-                    if (other - candidate_unit).abs() < 0.01 && amt != other {
-                        item.amount = amt;
-                        item.unit_price = candidate_unit;
-                        break;
-                    }
-                }
-                if item.amount > 0.0 {
-                    break;
-                }
+            let pair = amounts.iter().find_map(|&amt| {
+                let unit = amt.divide(qty)?;
+                (unit.times(qty) == Some(amt) && unit != amt && amounts.contains(&unit))
+                    .then_some((unit, amt))
+            });
+            if let Some((unit, amt)) = pair {
+                item.unit_price = unit;
+                item.amount = amt;
             }
         }
 
@@ -251,16 +252,28 @@ fn extract_packing_items(text: &str) -> Vec<PackingItem> {
         .map(|c| c[1].trim().to_string())
         .collect();
 
-    // Find carton number patterns (e.g. "1", "2-6")
-    let carton_re = Regex::new(r"(?m)^\s*(\d+(?:\s*-\s*\d+)?)\s").unwrap();
-    // Better: look for the structured rows after CARTON #
+    // Look for the structured rows after CARTON #
     let header_pos = packing_section.to_uppercase().find("CARTON").unwrap_or(0);
     let data_section = &packing_section[header_pos..];
 
     // Find numeric rows: carton, ctns, qty, net_wt, gross_wt
     let row_re = Regex::new(r"(\d+(?:\s*-\s*\d+)?)\s+(\d+)\s+(\d+)\s+([\d.]+)\s+([\d.]+)").unwrap();
+    let rows: Vec<_> = row_re.captures_iter(data_section).collect();
+
+    // Descriptions and measurements are matched independently of the rows;
+    // pair them by position only when each row got exactly one
+    let descriptions = if descriptions.len() == rows.len() {
+        descriptions
+    } else {
+        Vec::new()
+    };
+    let measurements = if measurements.len() == rows.len() {
+        measurements
+    } else {
+        Vec::new()
+    };
 
-    for (i, cap) in row_re.captures_iter(data_section).enumerate() {
+    for (i, cap) in rows.iter().enumerate() {
         let item = PackingItem {
             carton: cap[1].trim().to_string(),
             description: descriptions.get(i).cloned().unwrap_or_default(),
@@ -2,16 +2,17 @@
 
 mod generic;
 
+use crate::money::Money;
 use serde::Deserialize;
 use serde::Serialize;
 
-/// A single invoice line item.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A single invoice line item, priced in the invoice's currency.
+#[derive(Debug, Clone, Serialize)]
 pub struct LineItem {
     pub description: String,
     pub qty: u32,
-    pub unit_price: f64,
-    pub amount: f64,
+    pub unit_price: Money,
+    pub amount: Money,
 }
 
 /// A single row from the packing list.
@@ -36,14 +37,14 @@ pub struct PackingTotals {
 }
 
 /// All structured data we can extract from an invoice PDF.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct InvoiceData {
     pub vendor: Option<String>,
     pub buyer: Option<String>,
     pub invoice_no: Option<String>,
     pub invoice_date: Option<String>,
     pub currency: Option<String>,
-    pub total_amount: Option<f64>,
+    pub total_amount: Option<Money>,
     pub total_pieces: Option<u32>,
     pub ship_from: Option<String>,
     pub ship_to: Option<String>,
@@ -74,9 +75,32 @@ impl InvoiceData {
         .count();
         (filled, total)
     }
+
+    /// Sum of the line amounts, if there are any and they share a currency.
+    pub fn line_total(&self) -> Option<Money> {
+        let (first, rest) = self.line_items.split_first()?;
+        rest.iter()
+            .try_fold(first.amount, |sum, item| sum.checked_add(item.amount))
+    }
+}
+
+/// Which set of regex patterns to apply to a PDF's text.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeuristicTemplate {
+    /// Keyword-anchored patterns for commercial invoices + packing lists
+    #[default]
+    Generic,
+}
+
+/// Extract structured invoice data using a specific template.
+pub fn extract_invoice_with(template: HeuristicTemplate, text: &str) -> InvoiceData {
+    match template {
+        HeuristicTemplate::Generic => generic::extract(text),
+    }
 }
 
-/// Extract structured invoice data from raw PDF text.
-pub fn extract_invoice(text: &str) -> InvoiceData {
-    generic::extract(text)
+/// The first "Invoice No." value in `text`, whatever the template.
+pub fn invoice_no(text: &str) -> Option<String> {
+    generic::extract_invoice_no(text)
 }
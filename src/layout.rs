@@ -0,0 +1,609 @@
+// src/layout.rs
+
+use lopdf::{Dictionary, Document, Encoding, Object, ObjectId};
+use std::collections::BTreeMap;
+
+/// Text drawn by one text-showing operator, positioned in page space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextRun {
+    /// 1-based page number
+    pub page: u32,
+    /// Left edge, in points from the left of the page
+    pub x: f32,
+    /// Baseline, in points from the bottom of the page
+    pub y: f32,
+    /// Font size after the text and graphics matrices are applied
+    pub font_size: f32,
+    /// Advance width, from the font's `Widths` when it has them
+    pub width: f32,
+    pub text: String,
+}
+
+impl TextRun {
+    fn x_end(&self) -> f32 {
+        self.x + self.width
+    }
+}
+
+/// Runs sharing a baseline, split into cells wherever the gap between runs
+/// is wide enough to be a column break.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub page: u32,
+    pub y: f32,
+    pub font_size: f32,
+    pub cells: Vec<Cell>,
+}
+
+/// One column's worth of text within a line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub x: f32,
+    pub x_end: f32,
+    pub text: String,
+}
+
+/// Runs whose baselines differ by less than this fraction of the font size
+/// are on the same line (covers superscripts and sloppy generators).
+const SAME_LINE_TOLERANCE: f32 = 0.4;
+/// A horizontal gap wider than this many font sizes starts a new cell.
+const COLUMN_GAP: f32 = 1.0;
+/// A gap wider than this many font sizes between runs of the same cell is
+/// an implied space.
+const WORD_GAP: f32 = 0.15;
+/// Glyph width used when a font carries no `Widths` (e.g. the standard 14).
+const DEFAULT_GLYPH_WIDTH: f32 = 500.0;
+/// Widest indent `render_lines` pads a cell out to, in characters.
+const MAX_COLUMN: f32 = 300.0;
+
+/// 2-D affine transform `[a b c d e f]` in PDF's row-vector convention.
+type Matrix = [f32; 6];
+
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// `m1 × m2`: apply `m1`, then `m2`.
+fn multiply(m1: &Matrix, m2: &Matrix) -> Matrix {
+    [
+        m1[0] * m2[0] + m1[1] * m2[2],
+        m1[0] * m2[1] + m1[1] * m2[3],
+        m1[2] * m2[0] + m1[3] * m2[2],
+        m1[2] * m2[1] + m1[3] * m2[3],
+        m1[4] * m2[0] + m1[5] * m2[2] + m2[4],
+        m1[4] * m2[1] + m1[5] * m2[3] + m2[5],
+    ]
+}
+
+fn translate(tx: f32, ty: f32) -> Matrix {
+    [1.0, 0.0, 0.0, 1.0, tx, ty]
+}
+
+/// What we need from a font resource to decode and measure its strings.
+struct FontInfo<'a> {
+    encoding: Option<Encoding<'a>>,
+    /// Composite (Type0) fonts use two-byte codes
+    two_byte: bool,
+    first_char: u32,
+    /// Glyph widths in 1/1000 text space units, starting at `first_char`
+    widths: Vec<f32>,
+    default_width: f32,
+}
+
+impl<'a> FontInfo<'a> {
+    fn load(doc: &'a Document, font: &'a Dictionary) -> Self {
+        let two_byte = font
+            .get(b"Subtype")
+            .and_then(Object::as_name)
+            .is_ok_and(|s| s == b"Type0");
+        let entry = |key: &[u8]| {
+            font.get(key)
+                .ok()
+                .and_then(|o| doc.dereference(o).ok())
+                .and_then(|(_, o)| number(o))
+        };
+        let widths = font
+            .get(b"Widths")
+            .ok()
+            .and_then(|o| doc.dereference(o).ok())
+            .and_then(|(_, o)| o.as_array().ok())
+            .map(|arr| {
+                arr.iter()
+                    .map(|w| number(w).unwrap_or(DEFAULT_GLYPH_WIDTH))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let default_width = if two_byte {
+            // CID fonts: /DW on the descendant, 1000 when absent
+            font.get(b"DescendantFonts")
+                .ok()
+                .and_then(|o| doc.dereference(o).ok())
+                .and_then(|(_, o)| o.as_array().ok())
+                .and_then(|arr| arr.first())
+                .and_then(|o| doc.dereference(o).ok())
+                .and_then(|(_, o)| o.as_dict().ok())
+                .and_then(|d| d.get(b"DW").ok().and_then(number))
+                .unwrap_or(1000.0)
+        } else {
+            DEFAULT_GLYPH_WIDTH
+        };
+
+        Self {
+            encoding: font.get_font_encoding(doc).ok(),
+            two_byte,
+            first_char: entry(b"FirstChar").unwrap_or(0.0) as u32,
+            widths,
+            default_width,
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> String {
+        self.encoding
+            .as_ref()
+            .and_then(|enc| Document::decode_text(enc, bytes).ok())
+            .unwrap_or_else(|| bytes.iter().map(|&b| b as char).collect())
+    }
+
+    /// Character codes in `bytes`, paired with whether the code is a space
+    /// (word spacing only applies to single-byte code 32).
+    fn codes(&self, bytes: &[u8]) -> Vec<(u32, bool)> {
+        if self.two_byte {
+            bytes
+                .chunks(2)
+                .map(|c| (c.iter().fold(0u32, |acc, &b| acc << 8 | b as u32), false))
+                .collect()
+        } else {
+            bytes.iter().map(|&b| (b as u32, b == b' ')).collect()
+        }
+    }
+
+    fn glyph_width(&self, code: u32) -> f32 {
+        code.checked_sub(self.first_char)
+            .and_then(|i| self.widths.get(i as usize))
+            .copied()
+            .unwrap_or(self.default_width)
+    }
+}
+
+/// Text state carried between operators (PDF 32000 §9.3).
+struct TextState {
+    font: Option<Vec<u8>>,
+    size: f32,
+    char_spacing: f32,
+    word_spacing: f32,
+    horizontal_scale: f32,
+    leading: f32,
+    rise: f32,
+    matrix: Matrix,
+    line_matrix: Matrix,
+}
+
+impl Default for TextState {
+    fn default() -> Self {
+        Self {
+            font: None,
+            size: 0.0,
+            char_spacing: 0.0,
+            word_spacing: 0.0,
+            horizontal_scale: 1.0,
+            leading: 0.0,
+            rise: 0.0,
+            matrix: IDENTITY,
+            line_matrix: IDENTITY,
+        }
+    }
+}
+
+impl TextState {
+    fn next_line(&mut self, tx: f32, ty: f32) {
+        self.line_matrix = multiply(&translate(tx, ty), &self.line_matrix);
+        self.matrix = self.line_matrix;
+    }
+}
+
+fn number(obj: &Object) -> Option<f32> {
+    match obj {
+        Object::Integer(i) => Some(*i as f32),
+        Object::Real(r) => Some(*r),
+        _ => None,
+    }
+}
+
+/// Left and right edges of a page's `MediaBox`, inherited from parent
+/// page-tree nodes if needed.
+fn media_box_x(doc: &Document, page_id: ObjectId) -> Option<(f32, f32)> {
+    let mut node = doc.get_dictionary(page_id).ok();
+    while let Some(dict) = node {
+        if let Ok(media_box) = dict.get(b"MediaBox") {
+            let corners: Vec<f32> = doc
+                .dereference(media_box)
+                .ok()?
+                .1
+                .as_array()
+                .ok()?
+                .iter()
+                .filter_map(number)
+                .collect();
+            let &[x0, _, x1, _] = corners.as_slice() else {
+                return None;
+            };
+            return Some((x0.min(x1), x0.max(x1)));
+        }
+        node = dict
+            .get(b"Parent")
+            .and_then(|p| p.as_reference())
+            .and_then(|id| doc.get_dictionary(id))
+            .ok();
+    }
+    None
+}
+
+/// Walk a page's content stream and collect every piece of text it shows,
+/// with its position. Text inside form XObjects is not followed, and text
+/// starting outside the page's `MediaBox` (often hidden) is dropped.
+pub fn extract_runs(
+    doc: &Document,
+    page: u32,
+    page_id: ObjectId,
+) -> Result<Vec<TextRun>, lopdf::Error> {
+    let content = doc.get_and_decode_page_content(page_id)?;
+    let fonts: BTreeMap<Vec<u8>, FontInfo> = doc
+        .get_page_fonts(page_id)?
+        .into_iter()
+        .map(|(name, font)| (name, FontInfo::load(doc, font)))
+        .collect();
+
+    let mut runs = Vec::new();
+    let mut ctm = IDENTITY;
+    let mut ctm_stack = Vec::new();
+    let mut ts = TextState::default();
+
+    for op in &content.operations {
+        let nums: Vec<f32> = op.operands.iter().filter_map(number).collect();
+        match (op.operator.as_str(), nums.as_slice()) {
+            ("q", _) => ctm_stack.push(ctm),
+            ("Q", _) => ctm = ctm_stack.pop().unwrap_or(IDENTITY),
+            ("cm", &[a, b, c, d, e, f]) => ctm = multiply(&[a, b, c, d, e, f], &ctm),
+            ("BT", _) => {
+                ts.matrix = IDENTITY;
+                ts.line_matrix = IDENTITY;
+            }
+            ("Tf", &[size]) => {
+                ts.font = op
+                    .operands
+                    .first()
+                    .and_then(|o| o.as_name().ok())
+                    .map(<[u8]>::to_vec);
+                ts.size = size;
+            }
+            ("Tc", &[v]) => ts.char_spacing = v,
+            ("Tw", &[v]) => ts.word_spacing = v,
+            ("Tz", &[v]) => ts.horizontal_scale = v / 100.0,
+            ("TL", &[v]) => ts.leading = v,
+            ("Ts", &[v]) => ts.rise = v,
+            ("Td", &[tx, ty]) => ts.next_line(tx, ty),
+            ("TD", &[tx, ty]) => {
+                ts.leading = -ty;
+                ts.next_line(tx, ty);
+            }
+            ("Tm", &[a, b, c, d, e, f]) => {
+                ts.line_matrix = [a, b, c, d, e, f];
+                ts.matrix = ts.line_matrix;
+            }
+            ("T*", _) => ts.next_line(0.0, -ts.leading),
+            ("Tj" | "'" | "\"", _) => {
+                if op.operator == "\""
+                    && let &[aw, ac] = nums.as_slice()
+                {
+                    ts.word_spacing = aw;
+                    ts.char_spacing = ac;
+                }
+                if op.operator != "Tj" {
+                    ts.next_line(0.0, -ts.leading);
+                }
+                if let Some(Object::String(bytes, _)) = op.operands.last() {
+                    show(&mut ts, &ctm, &fonts, page, bytes, &mut runs);
+                }
+            }
+            ("TJ", _) => {
+                let Some(Ok(items)) = op.operands.first().map(Object::as_array) else {
+                    continue;
+                };
+                for item in items {
+                    match item {
+                        Object::String(bytes, _) => {
+                            show(&mut ts, &ctm, &fonts, page, bytes, &mut runs)
+                        }
+                        other => {
+                            if let Some(adjust) = number(other) {
+                                let tx = -adjust / 1000.0 * ts.size * ts.horizontal_scale;
+                                ts.matrix = multiply(&translate(tx, 0.0), &ts.matrix);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let (left, right) = media_box_x(doc, page_id).unwrap_or((f32::MIN, f32::MAX));
+    runs.retain(|r| !r.text.trim().is_empty() && (left..=right).contains(&r.x));
+    Ok(runs)
+}
+
+/// Emit a run for one shown string and advance the text matrix past it.
+fn show(
+    ts: &mut TextState,
+    ctm: &Matrix,
+    fonts: &BTreeMap<Vec<u8>, FontInfo>,
+    page: u32,
+    bytes: &[u8],
+    runs: &mut Vec<TextRun>,
+) {
+    let font = ts.font.as_ref().and_then(|name| fonts.get(name));
+    let (text, codes) = match font {
+        Some(font) => (font.decode(bytes), font.codes(bytes)),
+        None => (
+            bytes.iter().map(|&b| b as char).collect(),
+            bytes.iter().map(|&b| (b as u32, b == b' ')).collect(),
+        ),
+    };
+
+    let advance: f32 = codes
+        .iter()
+        .map(|&(code, is_space)| {
+            let glyph = font.map_or(DEFAULT_GLYPH_WIDTH, |f| f.glyph_width(code));
+            let spacing = ts.char_spacing + if is_space { ts.word_spacing } else { 0.0 };
+            (glyph / 1000.0 * ts.size + spacing) * ts.horizontal_scale
+        })
+        .sum();
+
+    let start = multiply(&multiply(&translate(0.0, ts.rise), &ts.matrix), ctm);
+    let end = multiply(&translate(advance, 0.0), &start);
+    let vertical_scale = (start[2] * start[2] + start[3] * start[3]).sqrt();
+    runs.push(TextRun {
+        page,
+        x: start[4],
+        y: start[5],
+        font_size: ts.size * vertical_scale,
+        width: (end[4] - start[4]).abs(),
+        text,
+    });
+
+    ts.matrix = multiply(&translate(advance, 0.0), &ts.matrix);
+}
+
+/// Group runs into lines (top to bottom) and each line into cells (left to
+/// right). Pages keep their order.
+pub fn group_lines(runs: &[TextRun]) -> Vec<Line> {
+    let mut sorted: Vec<&TextRun> = runs.iter().collect();
+    sorted.sort_by(|a, b| {
+        a.page
+            .cmp(&b.page)
+            .then(b.y.total_cmp(&a.y))
+            .then(a.x.total_cmp(&b.x))
+    });
+
+    let mut rows: Vec<Vec<&TextRun>> = Vec::new();
+    for run in sorted {
+        match rows.last_mut() {
+            Some(row)
+                if row[0].page == run.page
+                    && (row[0].y - run.y).abs()
+                        <= SAME_LINE_TOLERANCE * row[0].font_size.max(run.font_size) =>
+            {
+                row.push(run)
+            }
+            _ => rows.push(vec![run]),
+        }
+    }
+
+    rows.into_iter()
+        .map(|mut row| {
+            row.sort_by(|a, b| a.x.total_cmp(&b.x));
+            let font_size = row.iter().map(|r| r.font_size).fold(0.0, f32::max);
+            let mut cells: Vec<Cell> = Vec::new();
+            for run in &row {
+                let gap = cells.last().map(|c| run.x - c.x_end);
+                match (cells.last_mut(), gap) {
+                    (Some(cell), Some(gap)) if gap <= COLUMN_GAP * font_size => {
+                        if gap > WORD_GAP * font_size
+                            && !cell.text.ends_with(' ')
+                            && !run.text.starts_with(' ')
+                        {
+                            cell.text.push(' ');
+                        }
+                        cell.text.push_str(&run.text);
+                        cell.x_end = cell.x_end.max(run.x_end());
+                    }
+                    _ => cells.push(Cell {
+                        x: run.x,
+                        x_end: run.x_end(),
+                        text: run.text.clone(),
+                    }),
+                }
+            }
+            for cell in &mut cells {
+                cell.text = cell.text.split_whitespace().collect::<Vec<_>>().join(" ");
+            }
+            Line {
+                page: row[0].page,
+                y: row[0].y,
+                font_size,
+                cells,
+            }
+        })
+        .collect()
+}
+
+/// Render lines as plain text, padding each cell out to its column so that
+/// columns stay aligned across lines (like `pdftotext -layout`). Cells are
+/// always separated by at least two spaces, and never indented past
+/// [`MAX_COLUMN`] characters.
+pub fn render_lines(lines: &[Line]) -> String {
+    // One character column per half em of the page's typical font size
+    let mut sizes: Vec<f32> = lines.iter().map(|l| l.font_size).collect();
+    sizes.sort_by(f32::total_cmp);
+    let char_width = sizes.get(sizes.len() / 2).copied().unwrap_or(10.0).max(1.0) / 2.0;
+    let left = lines
+        .iter()
+        .filter_map(|l| l.cells.first())
+        .map(|c| c.x)
+        .fold(f32::INFINITY, f32::min);
+
+    let mut out = String::new();
+    let mut page = lines.first().map(|l| l.page);
+    for line in lines {
+        if Some(line.page) != page {
+            out.push('\n');
+            page = Some(line.page);
+        }
+        let mut row = String::new();
+        for (i, cell) in line.cells.iter().enumerate() {
+            let column = ((cell.x - left) / char_width)
+                .round()
+                .clamp(0.0, MAX_COLUMN) as usize;
+            let width = row.chars().count();
+            let pad = if i == 0 {
+                column
+            } else {
+                column.max(width + 2) - width
+            };
+            row.extend(std::iter::repeat_n(' ', pad));
+            row.push_str(&cell.text);
+        }
+        out.push_str(&row);
+        out.push('\n');
+    }
+    out
+}
+
+/// Layout-preserving text of one page.
+pub fn page_text(doc: &Document, page: u32, page_id: ObjectId) -> Result<String, lopdf::Error> {
+    let runs = extract_runs(doc, page, page_id)?;
+    Ok(render_lines(&group_lines(&runs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::content::{Content, Operation};
+    use lopdf::{Stream, dictionary};
+
+    fn run(x: f32, y: f32, width: f32, text: &str) -> TextRun {
+        TextRun {
+            page: 1,
+            x,
+            y,
+            font_size: 10.0,
+            width,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_group_lines_splits_columns() {
+        // Out of order, one run slightly below its neighbours' baseline
+        let runs = vec![
+            run(300.0, 700.0, 30.0, "AMOUNT"),
+            run(50.0, 700.0, 60.0, "DESCRIPTION"),
+            run(200.0, 700.0, 20.0, "QTY"),
+            run(50.0, 685.0, 25.0, "Cotton"),
+            run(77.0, 685.0, 20.0, "shirt"),
+            run(200.0, 684.0, 10.0, "12"),
+            run(300.0, 685.0, 30.0, "144.00"),
+        ];
+        let lines = group_lines(&runs);
+        assert_eq!(lines.len(), 2);
+        let texts = |l: &Line| l.cells.iter().map(|c| c.text.clone()).collect::<Vec<_>>();
+        assert_eq!(texts(&lines[0]), ["DESCRIPTION", "QTY", "AMOUNT"]);
+        assert_eq!(texts(&lines[1]), ["Cotton shirt", "12", "144.00"]);
+
+        let rendered = render_lines(&lines);
+        let rows: Vec<&str> = rendered.lines().collect();
+        assert_eq!(rows[0].find("QTY"), rows[1].find("12"));
+        assert_eq!(rows[0].find("AMOUNT"), rows[1].find("144.00"));
+    }
+
+    #[test]
+    fn test_extract_runs_positions() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        });
+        let content = Content {
+            operations: vec![
+                Operation::new("BT", vec![]),
+                Operation::new("Tf", vec!["F1".into(), 10.into()]),
+                Operation::new("Td", vec![50.into(), 700.into()]),
+                Operation::new("Tj", vec![Object::string_literal("INVOICE")]),
+                Operation::new("Td", vec![250.into(), 0.into()]),
+                Operation::new(
+                    "TJ",
+                    vec![Object::Array(vec![
+                        Object::string_literal("No."),
+                        (-1000).into(),
+                        Object::string_literal("42"),
+                    ])],
+                ),
+                // Hidden text parked far off the page
+                Operation::new("Td", vec![(-5300).into(), 0.into()]),
+                Operation::new("Tj", vec![Object::string_literal("HIDDEN")]),
+                Operation::new("ET", vec![]),
+            ],
+        };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "Resources" => dictionary! { "Font" => dictionary! { "F1" => font_id } },
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+
+        let runs = extract_runs(&doc, 1, page_id).unwrap();
+        let texts: Vec<&str> = runs.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, ["INVOICE", "No.", "42"]);
+        assert_eq!(
+            (runs[0].x, runs[0].y, runs[0].font_size),
+            (50.0, 700.0, 10.0)
+        );
+        assert_eq!(runs[1].x, 300.0);
+        // "No." is 3 default-width glyphs, then a 1 em TJ adjustment
+        assert_eq!(runs[2].x, 300.0 + 15.0 + 10.0);
+
+        let lines = group_lines(&runs);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].cells.len(), 2);
+        assert_eq!(lines[0].cells[1].text, "No. 42");
+    }
+
+    #[test]
+    fn test_render_lines_caps_indent() {
+        let lines = group_lines(&[
+            run(-1.0e30, 700.0, 30.0, "STRAY"),
+            run(50.0, 680.0, 35.0, "INVOICE"),
+            run(1.0e30, 680.0, 10.0, "42"),
+        ]);
+        let text = render_lines(&lines);
+        let widths: Vec<usize> = text.lines().map(|l| l.chars().count()).collect();
+        // "42" is capped too, then kept two spaces clear of "INVOICE"
+        assert_eq!(widths, [5, 300 + 7 + 2 + 2]);
+        assert!(
+            text.lines()
+                .nth(1)
+                .unwrap()
+                .starts_with(&format!("{}INVOICE", " ".repeat(300)))
+        );
+    }
+}
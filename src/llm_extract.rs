@@ -1,8 +1,13 @@
 // src/llm_extract.rs
 
-use crate::config::{LlmBackend, LlmSection};
-use crate::heuristics::InvoiceData;
-use crate::message_db::MessageStore;
+use crate::config::{LlmBackend, LlmSection, PdfConfig};
+use crate::heuristics::{InvoiceData, LineItem, PackingItem, PackingTotals};
+use crate::message_db::{MessageStore, StoredAttachment};
+use crate::money::{Currency, Money};
+use crate::pdf_extract::{self, segment};
+use crate::render::{self, PageSelection, RenderedPage};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use tracing::{info, warn};
@@ -62,10 +67,31 @@ struct ChatRequest {
     temperature: f64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 struct ChatMessage {
     role: String,
-    content: String,
+    content: MessageContent,
+}
+
+/// Plain text, or a list of parts for multimodal (vision) requests.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Serialize)]
+struct ImageUrl {
+    /// `data:image/png;base64,...`
+    url: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -75,13 +101,134 @@ struct ChatResponse {
 
 #[derive(Debug, Deserialize)]
 struct ChatChoice {
-    message: ChatMessage,
+    message: ResponseMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseMessage {
+    content: String,
+}
+
+/// `InvoiceData` as the model writes it. Amounts may come back as numbers
+/// or strings and are only read once the invoice's currency is known.
+#[derive(Debug, Deserialize)]
+struct LlmInvoice {
+    vendor: Option<String>,
+    buyer: Option<String>,
+    invoice_no: Option<String>,
+    invoice_date: Option<String>,
+    currency: Option<String>,
+    total_amount: Option<LlmAmount>,
+    total_pieces: Option<u32>,
+    ship_from: Option<String>,
+    ship_to: Option<String>,
+    shipping_method: Option<String>,
+    line_items: Vec<LlmLineItem>,
+    packing_items: Vec<PackingItem>,
+    packing_totals: Option<PackingTotals>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LlmLineItem {
+    description: String,
+    qty: u32,
+    unit_price: LlmAmount,
+    amount: LlmAmount,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LlmAmount {
+    Number(serde_json::Number),
+    Text(String),
+}
+
+impl LlmAmount {
+    /// The amount read exactly from its decimal digits; JSON numbers keep
+    /// their shortest form, so `25.4` is 2540 cents rather than 2539.99...
+    /// None for anything else, e.g. `1.234,56` or `2.5e-7`.
+    fn money(&self, currency: Currency) -> Option<Money> {
+        match self {
+            Self::Number(n) => Money::parse(&n.to_string(), currency),
+            Self::Text(t) => {
+                // "US$ 25.40", "25.40 USD"; negatives as "-25.40", "US$ -25.40"
+                // or, accounting style, "(25.40)"
+                let t = t.trim();
+                let negative = t.contains('-') || (t.starts_with('(') && t.ends_with(')'));
+                let digits = t
+                    .trim_start_matches(|c: char| !c.is_ascii_digit())
+                    .trim_end_matches(|c: char| !c.is_ascii_digit());
+                let money = Money::parse(digits, currency)?;
+                Some(if negative {
+                    Money::new(-money.minor, currency)
+                } else {
+                    money
+                })
+            }
+        }
+    }
+}
+
+impl LlmLineItem {
+    /// The line item, with a price the model left unreadable worked out
+    /// from the other one and `qty`. Dropped (None) if neither can be read:
+    /// a zero would look like a real amount and throw off `line_total`.
+    fn into_line_item(self, currency: Currency) -> Option<LineItem> {
+        let prices = match (self.unit_price.money(currency), self.amount.money(currency)) {
+            (Some(unit_price), Some(amount)) => Some((unit_price, amount)),
+            (None, Some(amount)) => amount.divide(self.qty).map(|p| (p, amount)),
+            (Some(unit_price), None) => unit_price.times(self.qty).map(|a| (unit_price, a)),
+            (None, None) => None,
+        };
+        let Some((unit_price, amount)) = prices else {
+            warn!(
+                description = %self.description,
+                unit_price = ?self.unit_price,
+                amount = ?self.amount,
+                "Unreadable line item amount — item dropped"
+            );
+            return None;
+        };
+        Some(LineItem {
+            description: self.description,
+            qty: self.qty,
+            unit_price,
+            amount,
+        })
+    }
+}
+
+impl LlmInvoice {
+    fn into_invoice(self) -> InvoiceData {
+        let currency = Currency::of(self.currency.as_deref());
+        InvoiceData {
+            vendor: self.vendor,
+            buyer: self.buyer,
+            invoice_no: self.invoice_no,
+            invoice_date: self.invoice_date,
+            total_amount: self.total_amount.and_then(|a| a.money(currency)),
+            currency: self.currency,
+            total_pieces: self.total_pieces,
+            ship_from: self.ship_from,
+            ship_to: self.ship_to,
+            shipping_method: self.shipping_method,
+            line_items: self
+                .line_items
+                .into_iter()
+                .filter_map(|item| item.into_line_item(currency))
+                .collect(),
+            packing_items: self.packing_items,
+            packing_totals: self.packing_totals,
+        }
+    }
 }
 
 /// Resolved endpoint configuration ready to make API calls.
 struct ResolvedEndpoint {
     base_url: String,
     model: String,
+    /// Image-capable model used for scanned PDFs
+    vision_model: String,
     api_key: String,
 }
 
@@ -97,6 +244,7 @@ fn resolve_endpoint(llm: &LlmSection) -> Result<ResolvedEndpoint, Box<dyn std::e
             Ok(ResolvedEndpoint {
                 base_url: llm.ollama.base_url.clone(),
                 model: llm.ollama.model.clone(),
+                vision_model: llm.ollama.vision_model.clone(),
                 api_key: "ollama".to_string(), // required by API but ignored
             })
         }
@@ -109,6 +257,7 @@ fn resolve_endpoint(llm: &LlmSection) -> Result<ResolvedEndpoint, Box<dyn std::e
             Ok(ResolvedEndpoint {
                 base_url: llm.cliproxy.base_url.clone(),
                 model: llm.cliproxy.model.clone(),
+                vision_model: llm.cliproxy.vision_model.clone(),
                 api_key: "cliproxy".to_string(), // CLIProxyAPI uses OAuth, not API keys
             })
         }
@@ -123,6 +272,7 @@ fn resolve_endpoint(llm: &LlmSection) -> Result<ResolvedEndpoint, Box<dyn std::e
             Ok(ResolvedEndpoint {
                 base_url: llm.remote.base_url.clone(),
                 model: llm.remote.model.clone(),
+                vision_model: llm.remote.vision_model.clone(),
                 api_key,
             })
         }
@@ -178,22 +328,71 @@ async fn extract_invoice_with_llm(
         messages: vec![
             ChatMessage {
                 role: "system".to_string(),
-                content: SYSTEM_PROMPT.to_string(),
+                content: MessageContent::Text(SYSTEM_PROMPT.to_string()),
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: MessageContent::Text(format!(
+                    "Extract invoice data from the following PDF text:\n\n{text}"
+                )),
+            },
+        ],
+        temperature: 0.0,
+    };
+
+    send_chat_request(client, endpoint, &request).await
+}
+
+/// Send rendered pages of a scanned PDF to a vision model and parse the
+/// structured invoice data. Uses the same schema as the text path.
+async fn extract_invoice_from_images(
+    client: &Client,
+    endpoint: &ResolvedEndpoint,
+    pages: &[RenderedPage],
+) -> Result<InvoiceData, Box<dyn std::error::Error>> {
+    let mut parts = vec![ContentPart::Text {
+        text: format!(
+            "The following {} image(s) are the pages of a scanned PDF invoice, in order. \
+             Read them in place of extracted text and extract the invoice data.",
+            pages.len()
+        ),
+    }];
+    parts.extend(pages.iter().map(|page| ContentPart::ImageUrl {
+        image_url: ImageUrl {
+            url: format!("data:image/png;base64,{}", BASE64.encode(&page.png)),
+        },
+    }));
+
+    let request = ChatRequest {
+        model: endpoint.vision_model.clone(),
+        messages: vec![
+            ChatMessage {
+                role: "system".to_string(),
+                content: MessageContent::Text(SYSTEM_PROMPT.to_string()),
             },
             ChatMessage {
                 role: "user".to_string(),
-                content: format!("Extract invoice data from the following PDF text:\n\n{text}"),
+                content: MessageContent::Parts(parts),
             },
         ],
         temperature: 0.0,
     };
 
+    send_chat_request(client, endpoint, &request).await
+}
+
+/// POST a chat completion request and parse the reply as `InvoiceData`.
+async fn send_chat_request(
+    client: &Client,
+    endpoint: &ResolvedEndpoint,
+    request: &ChatRequest,
+) -> Result<InvoiceData, Box<dyn std::error::Error>> {
     let url = format!("{}/chat/completions", endpoint.base_url);
 
     let response = client
         .post(&url)
         .header("Authorization", format!("Bearer {}", endpoint.api_key))
-        .json(&request)
+        .json(request)
         .send()
         .await?;
 
@@ -222,11 +421,11 @@ async fn extract_invoice_with_llm(
     // Find the first '{' and last '}' to extract just the JSON object.
     let json_str = extract_json_object(json_str)?;
 
-    let invoice: InvoiceData = serde_json::from_str(json_str).map_err(|e| {
+    let invoice: LlmInvoice = serde_json::from_str(json_str).map_err(|e| {
         format!("Failed to parse LLM response as InvoiceData: {e}\nRaw: {json_str}")
     })?;
 
-    Ok(invoice)
+    Ok(invoice.into_invoice())
 }
 
 /// Extract the outermost JSON object from a string that may contain
@@ -240,6 +439,24 @@ fn extract_json_object(s: &str) -> Result<&str, Box<dyn std::error::Error>> {
     Ok(&s[start..=end])
 }
 
+/// Fail early when a local backend is not running.
+async fn ensure_reachable(
+    client: &Client,
+    llm_config: &LlmSection,
+    endpoint: &ResolvedEndpoint,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if llm_config.backend == LlmBackend::Ollama
+        && !check_ollama_health(client, &endpoint.base_url).await
+    {
+        return Err(format!(
+            "Ollama is not running at {}. Start it with: ollama serve",
+            endpoint.base_url
+        )
+        .into());
+    }
+    Ok(())
+}
+
 /// Extract invoice data from a single text string (for testing).
 pub async fn run_llm_extraction_single(
     text: &str,
@@ -248,39 +465,22 @@ pub async fn run_llm_extraction_single(
     let endpoint = resolve_endpoint(llm_config)?;
     let client = Client::new();
 
-    if llm_config.backend == LlmBackend::Ollama {
-        if !check_ollama_health(&client, &endpoint.base_url).await {
-            return Err(format!(
-                "Ollama is not running at {}. Start it with: ollama serve",
-                endpoint.base_url
-            )
-            .into());
-        }
-    }
+    ensure_reachable(&client, llm_config, &endpoint).await?;
 
     extract_invoice_with_llm(&client, &endpoint, text).await
 }
 
-/// Run LLM-based extraction on all text-classified attachments.
+/// Run LLM-based extraction on text-classified attachments.
 pub async fn run_llm_extraction(
     db: &MessageStore,
+    text_attachments: &[StoredAttachment],
     llm_config: &LlmSection,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let endpoint = resolve_endpoint(llm_config)?;
     let client = Client::new();
 
-    // Health check for local backends
-    if llm_config.backend == LlmBackend::Ollama {
-        if !check_ollama_health(&client, &endpoint.base_url).await {
-            return Err(format!(
-                "Ollama is not running at {}. Start it with: ollama serve",
-                endpoint.base_url
-            )
-            .into());
-        }
-    }
+    ensure_reachable(&client, llm_config, &endpoint).await?;
 
-    let text_attachments = db.get_text_attachments()?;
     info!(
         count = text_attachments.len(),
         backend = ?llm_config.backend,
@@ -288,37 +488,280 @@ pub async fn run_llm_extraction(
         "Text attachments for LLM extraction"
     );
 
-    for att in &text_attachments {
+    for att in text_attachments {
         let att_id = att.id.expect("attachment must have an id from DB");
         let span = tracing::info_span!("llm_extract", id = att_id, filename = %att.filename);
         let _guard = span.enter();
 
-        let Some(ref text) = att.extracted_text else {
+        let segments = segment::attachment_segments(db, att)?;
+        if segments.is_empty() {
             warn!("No extracted text despite content_type = text");
             continue;
+        }
+
+        // Each bundled document is extracted on its own; one failure drops
+        // the attachment's result for this run rather than storing a subset
+        let mut invoices = Vec::with_capacity(segments.len());
+        for seg in &segments {
+            match extract_invoice_with_llm(&client, &endpoint, &seg.text).await {
+                Ok(invoice) => {
+                    let (filled, total) = invoice.coverage();
+                    info!(
+                        pages = ?seg.pages,
+                        filled, total,
+                        invoice_no = ?invoice.invoice_no,
+                        vendor = ?invoice.vendor,
+                        total_amount = ?invoice.total_amount,
+                        line_items = invoice.line_items.len(),
+                        "LLM extraction result"
+                    );
+                    invoices.push((seg.pages, invoice));
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "LLM extraction failed for attachment {att_id}");
+                    break;
+                }
+            }
+        }
+
+        if invoices.len() == segments.len() {
+            db.insert_invoices(
+                att_id,
+                llm_config.backend.as_str(),
+                Some(&endpoint.model),
+                &invoices,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Extract invoice data from a single scanned PDF via the vision model (for
+/// testing). Encrypted PDFs must already be decrypted.
+pub async fn run_vision_extraction_single(
+    pdf_bytes: &[u8],
+    llm_config: &LlmSection,
+) -> Result<InvoiceData, Box<dyn std::error::Error>> {
+    let endpoint = resolve_endpoint(llm_config)?;
+    let client = Client::new();
+    ensure_reachable(&client, llm_config, &endpoint).await?;
+
+    let vision = &llm_config.vision;
+    let pages = render::render_pages(
+        pdf_bytes,
+        &vision.pdftoppm,
+        vision.dpi,
+        PageSelection::First(vision.max_pages),
+    )?;
+    extract_invoice_from_images(&client, &endpoint, &pages).await
+}
+
+/// Run vision-model extraction on scanned attachments: render the first
+/// pages to PNG and send them as image parts. Callers pass only attachments
+/// without an invoice (unless re-extracting), so each scan is rendered and
+/// sent once. Encrypted
+/// PDFs are decrypted with the configured password that opens them first.
+pub async fn run_vision_extraction(
+    db: &MessageStore,
+    scanned_attachments: &[StoredAttachment],
+    llm_config: &LlmSection,
+    pdf_config: &PdfConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let endpoint = resolve_endpoint(llm_config)?;
+    let client = Client::new();
+    ensure_reachable(&client, llm_config, &endpoint).await?;
+
+    let vision = &llm_config.vision;
+    info!(
+        count = scanned_attachments.len(),
+        backend = ?llm_config.backend,
+        model = %endpoint.vision_model,
+        max_pages = vision.max_pages,
+        "Scanned attachments for vision extraction"
+    );
+
+    for att in scanned_attachments {
+        let att_id = att.id.expect("attachment must have an id from DB");
+        let span = tracing::info_span!("vision_extract", id = att_id, filename = %att.filename);
+        let _guard = span.enter();
+
+        let pdf_bytes = att.pdf_data.read(db)?;
+        let pdf_bytes = pdf_extract::pdf_for_tools(db, att, pdf_config, &pdf_bytes)?;
+        let pages = match render::render_pages(
+            &pdf_bytes,
+            &vision.pdftoppm,
+            vision.dpi,
+            PageSelection::First(vision.max_pages),
+        ) {
+            Ok(pages) if !pages.is_empty() => pages,
+            Ok(_) => {
+                warn!("PDF rendered no pages");
+                continue;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to render pages for attachment {att_id}");
+                continue;
+            }
         };
 
-        match extract_invoice_with_llm(&client, &endpoint, text).await {
+        match extract_invoice_from_images(&client, &endpoint, &pages).await {
             Ok(invoice) => {
                 let (filled, total) = invoice.coverage();
                 info!(
                     filled, total,
+                    pages = pages.len(),
                     invoice_no = ?invoice.invoice_no,
                     vendor = ?invoice.vendor,
                     total_amount = ?invoice.total_amount,
-                    line_items = invoice.line_items.len(),
-                    "LLM extraction result"
+                    "Vision extraction result"
                 );
 
-                let json = serde_json::to_string_pretty(&invoice)?;
-                info!(json_len = json.len(), "Storing structured invoice JSON");
-                // TODO: persist `json` to a new DB column or table
+                db.insert_invoice(
+                    att_id,
+                    llm_config.backend.as_str(),
+                    Some(&endpoint.vision_model),
+                    &invoice,
+                )?;
             }
             Err(e) => {
-                tracing::error!(error = %e, "LLM extraction failed for attachment {att_id}");
+                tracing::error!(error = %e, "Vision extraction failed for attachment {att_id}");
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Minimal stand-in for an OpenAI-compatible server: answers one
+    /// request with `reply` as the assistant message and hands back the
+    /// request body it received.
+    async fn stand_in_server(reply: &str) -> (String, tokio::task::JoinHandle<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let body = serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": reply } }]
+        })
+        .to_string();
+
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 8192];
+            let body_start = loop {
+                let n = socket.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break pos + 4;
+                }
+            };
+            let headers = String::from_utf8_lossy(&buf[..body_start]).to_lowercase();
+            let length: usize = headers
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length:"))
+                .map(|v| v.trim().parse().unwrap())
+                .unwrap();
+            while buf.len() < body_start + length {
+                let n = socket.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            serde_json::from_slice(&buf[body_start..body_start + length]).unwrap()
+        });
+
+        (base_url, handle)
+    }
+
+    #[test]
+    fn test_unreadable_line_amounts_are_not_zero() {
+        let invoice: LlmInvoice = serde_json::from_str(
+            r#"{"vendor": null, "buyer": null, "invoice_no": null, "invoice_date": null,
+                "currency": "EUR", "total_amount": "1.234,56", "total_pieces": null,
+                "ship_from": null, "ship_to": null, "shipping_method": null,
+                "packing_items": [], "packing_totals": null,
+                "line_items": [
+                    {"description": "Credit", "qty": 1, "unit_price": "(25.40)", "amount": "EUR -25.40"},
+                    {"description": "Bolts", "qty": 4, "unit_price": "n/a", "amount": 10},
+                    {"description": "Nuts", "qty": 2, "unit_price": "1.234,56", "amount": 2.5e-7}
+                ]}"#,
+        )
+        .unwrap();
+        let invoice = invoice.into_invoice();
+        let eur = |minor| Money::new(minor, Currency::of(Some("EUR")));
+        assert_eq!(invoice.total_amount, None);
+
+        let prices: Vec<_> = invoice
+            .line_items
+            .iter()
+            .map(|item| (item.description.as_str(), item.unit_price, item.amount))
+            .collect();
+        assert_eq!(
+            prices,
+            [
+                ("Credit", eur(-2540), eur(-2540)),
+                ("Bolts", eur(250), eur(1000)),
+            ]
+        );
+        assert_eq!(invoice.line_total(), Some(eur(-1540)));
+    }
+
+    #[tokio::test]
+    async fn test_vision_request_sends_image_parts() {
+        let reply = r#"```json
+{"vendor": "FedEx", "buyer": null, "invoice_no": "F-1", "invoice_date": null,
+ "currency": "USD", "total_amount": 42.5, "total_pieces": null, "ship_from": null,
+ "ship_to": null, "shipping_method": null, "packing_items": [],
+ "line_items": [{"description": "Ground", "qty": 3, "unit_price": "US$ 14.17", "amount": 42.51}],
+ "packing_totals": null}
+```"#;
+        let (base_url, server) = stand_in_server(reply).await;
+        let endpoint = ResolvedEndpoint {
+            base_url,
+            model: "text-model".to_string(),
+            vision_model: "llava".to_string(),
+            api_key: "test".to_string(),
+        };
+        let pages = vec![
+            RenderedPage {
+                page: 1,
+                png: b"png-1".to_vec(),
+            },
+            RenderedPage {
+                page: 2,
+                png: b"png-2".to_vec(),
+            },
+        ];
+
+        let invoice = extract_invoice_from_images(&Client::new(), &endpoint, &pages)
+            .await
+            .unwrap();
+        assert_eq!(invoice.invoice_no.as_deref(), Some("F-1"));
+        let usd = |minor| Money::new(minor, Currency::of(Some("USD")));
+        assert_eq!(invoice.total_amount, Some(usd(4250)));
+        let item = &invoice.line_items[0];
+        assert_eq!((item.unit_price, item.amount), (usd(1417), usd(4251)));
+
+        let request = server.await.unwrap();
+        assert_eq!(request["model"], "llava");
+        assert_eq!(request["messages"][0]["content"], SYSTEM_PROMPT);
+        let parts = request["messages"][1]["content"].as_array().unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0]["type"], "text");
+        assert_eq!(parts[1]["type"], "image_url");
+        assert_eq!(
+            parts[2]["image_url"]["url"],
+            format!("data:image/png;base64,{}", BASE64.encode(b"png-2"))
+        );
+    }
+}
@@ -1,89 +1,653 @@
+mod cli;
 mod config;
+mod dates;
 mod filter;
 mod gmail_hub;
 mod heuristics;
+mod layout;
 mod llm_extract;
 mod message_db;
 mod message_processor;
+mod money;
+mod ocr;
 mod pdf_extract;
-mod simple_refresh;
+mod rate_limit;
+mod render;
+mod retry;
 mod simplestore;
 
-use crate::config::{LlmConfig, LlmSection};
-use message_db::MessageStore;
+use crate::cli::{
+    AuthCommand, Cli, Command, DbCommand, ExportFormat, InvoiceListArgs, InvoicesCommand,
+    ListFormat,
+};
+use crate::config::{Config, LlmConfig, LlmSection, OcrConfig, PdfConfig};
+use clap::Parser;
+use message_db::{InvoiceQuery, MessageStore, SearchQuery, StoredInvoice, migrations};
+use rate_limit::TokenBucket;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_DB_PATH: &str = "msgstore/messages.db";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
     // init tracing
     tracing_subscriber::fmt()
         .with_target(true)
         .with_level(true)
-        .with_env_filter("info")
+        .with_env_filter(
+            EnvFilter::try_new(&cli.log_level)
+                .map_err(|e| format!("Invalid --log-level '{}': {e}", cli.log_level))?,
+        )
         .init();
 
-    let args: Vec<String> = std::env::args().collect();
+    match &cli.command {
+        Command::Fetch { profile, full } => fetch(&cli, profile.as_deref(), *full).await,
+        Command::Extract {
+            attachment: Some(att_id),
+        } => {
+            let cfg = load_optional_config(&cli)?;
+            let db = open_db(&cli, &db_path(&cli, cfg.as_ref()))?;
+            let llm_config = load_llm_config();
+            pdf_extract::test_single_pdf(&db, *att_id, &llm_config, &pdf_config(cfg.as_ref())).await
+        }
+        Command::Extract { attachment: None } => extract(&cli),
+        Command::Process { profile, reextract } => {
+            process(&cli, profile.as_deref(), *reextract).await
+        }
+        Command::Show { attachment_id } => show(&cli, *attachment_id),
+        Command::Invoices {
+            command: InvoicesCommand::List(args),
+        } => list_invoices(&cli, args),
+        Command::Export { format, output } => export(&cli, *format, output.as_deref()),
+        Command::Search {
+            terms,
+            from,
+            since,
+            until,
+            content_type,
+            limit,
+        } => search(
+            &cli,
+            &SearchQuery {
+                text: terms.join(" "),
+                sender: from.clone(),
+                since: *since,
+                until: *until,
+                content_type: content_type.clone(),
+                limit: *limit,
+            },
+        ),
+        Command::Stats => stats(&cli),
+        Command::Encrypted => encrypted(&cli),
+        Command::Auth {
+            command: AuthCommand::Login { mailbox },
+        } => {
+            let cfg = load_config(&cli)?;
+            if cli.dry_run {
+                let mailbox = cfg.mailbox(mailbox.as_deref())?;
+                println!("Would authorize {} ({})", mailbox.name, mailbox.user);
+                return Ok(());
+            }
+            install_crypto_provider();
+            gmail_hub::login(&cfg, mailbox.as_deref()).await
+        }
+        Command::Db {
+            command: DbCommand::Migrate { status },
+        } => migrate(&cli, *status),
+    }
+}
 
-    // Dispatch subcommands
-    if args.len() >= 2 && args[1] == "process-pdfs" {
-        let db_path = args
-            .get(2)
-            .map(|s| s.as_str())
-            .unwrap_or("msgstore/messages.db");
+/// Sync each selected profile's query in every mailbox it applies to, then
+/// download and label the new matches.
+async fn fetch(
+    cli: &Cli,
+    profile: Option<&str>,
+    full_sync: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    install_crypto_provider();
 
-        let llm_config = load_llm_config();
-        return pdf_extract::process_pdfs(db_path, &llm_config).await;
+    let cfg = load_config(cli)?;
+    let db = open_db(cli, &db_path(cli, Some(&cfg)))?;
+    if cfg.mailboxes.is_empty() {
+        return Err(format!("No [[mailboxes]] configured in {}", cfg.path.display()).into());
+    }
+    let profiles = cfg.profiles(profile)?;
+    if profiles.is_empty() {
+        return Err(format!("No [[profiles]] configured in {}", cfg.path.display()).into());
     }
 
-    // cargo run -- test-pdf <attachment_id> [db_path]
-    if args.len() >= 3 && args[1] == "test-pdf" {
-        let att_id: i64 = args[2]
-            .parse()
-            .map_err(|_| format!("Invalid attachment ID: {}", args[2]))?;
-        let db_path = args
-            .get(3)
-            .map(|s| s.as_str())
-            .unwrap_or("msgstore/messages.db");
+    for mailbox in &cfg.mailboxes {
+        let mailbox_profiles: Vec<_> = profiles.iter().filter(|p| p.applies_to(mailbox)).collect();
+        if mailbox_profiles.is_empty() {
+            continue;
+        }
+        info!(mailbox = %mailbox.name, user = %mailbox.user, "Syncing mailbox");
+        let hub = gmail_hub::create_hub(&cfg, mailbox, cli.dry_run).await?;
+        let user = mailbox.user.as_str();
+        // Gmail's quota is per user, so every call to this mailbox shares it
+        let limiter = TokenBucket::new(cfg.fetch.quota_units_per_sec);
+
+        for profile in mailbox_profiles {
+            let span = tracing::info_span!("profile", name = %profile.name);
+            let _guard = span.enter();
 
-        let llm_config = load_llm_config();
-        return pdf_extract::test_single_pdf(db_path, att_id, &llm_config).await;
+            let query = profile.query.as_str();
+            let batch =
+                filter::sync_message_ids(&hub, query, user, &db, full_sync, &limiter, &cfg.fetch)
+                    .await?;
+            if cli.dry_run {
+                let mut stored = 0;
+                for id in &batch.ids {
+                    stored += usize::from(db.has_message(user, id)?);
+                }
+                println!(
+                    "{}/{}: {} matches, {} to download",
+                    mailbox.name,
+                    profile.name,
+                    batch.ids.len(),
+                    batch.ids.len() - stored
+                );
+                continue;
+            }
+
+            filter::fetch_and_store(
+                &hub,
+                user,
+                query,
+                batch.ids.clone(),
+                &db,
+                &cfg.fetch,
+                &limiter,
+            )
+            .await?;
+            let labelled = db.label_messages(user, &batch.ids, profile.label())?;
+            info!(label = %profile.label(), labelled, "Labelled matched messages");
+            batch.commit(&db, user, query)?;
+        }
     }
 
-    // --- Default: full Gmail fetch + process flow ---
-    // Install crypto provider
-    rustls::crypto::ring::default_provider()
-        .install_default()
-        .expect("Failed to install rustls crypto provider");
+    Ok(())
+}
 
-    let hub = gmail_hub::create_hub().await?;
-    let cfg = config::Config::load(".config/oath_cli.toml")?;
-    let db = MessageStore::new(&cfg.db_path)?;
+/// Classify every unprocessed PDF attachment and OCR the scanned ones.
+fn extract(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let cfg = load_optional_config(cli)?;
+    let db = open_db(cli, &db_path(cli, cfg.as_ref()))?;
+    if cli.dry_run {
+        let pending = db.get_unprocessed_attachments()?.len();
+        let scanned = db.get_scanned_attachments()?.len();
+        println!("Would classify {pending} unprocessed attachments and OCR {scanned} scanned ones");
+        return Ok(());
+    }
+    let pdf_config = pdf_config(cfg.as_ref());
+    pdf_extract::run_pdf_extraction(&db, &pdf_config)?;
+    pdf_extract::run_ocr(&db, &ocr_config(cfg.as_ref()), &pdf_config)
+}
 
-    let user = "mmsoft.mudit@gmail.com";
-    let maxsoft = "from:*@maxsoft.sg AND after:2025/11/01 AND filename:pdf";
-    let fedex = "from:thicc@fedex.com AND after:2025/01/01";
+/// Classify PDFs and extract invoices. Profiles are optional here: without a
+/// config every attachment is extracted with the default LLM config.
+async fn process(
+    cli: &Cli,
+    profile: Option<&str>,
+    reextract: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cfg = if profile.is_some() {
+        Some(load_config(cli)?)
+    } else {
+        load_optional_config(cli)?
+    };
+    let profiles = match &cfg {
+        Some(cfg) => cfg.profiles(profile)?,
+        None => Vec::new(),
+    };
+    let db = open_db(cli, &db_path(cli, cfg.as_ref()))?;
 
-    let maxsoft_msgs = filter::get_message_ids(&hub, maxsoft, user).await?;
-    let fedex_msgs = filter::get_message_ids(&hub, fedex, user).await?;
+    if cli.dry_run {
+        println!(
+            "Would classify {} unprocessed attachments and OCR {} scanned ones",
+            db.get_unprocessed_attachments()?.len(),
+            db.get_scanned_attachments()?.len()
+        );
+        for profile in &profiles {
+            let pending = db.get_text_attachments_for_label(Some(profile.label()), reextract)?;
+            println!(
+                "{}: {} text attachments to extract",
+                profile.name,
+                pending.len()
+            );
+        }
+        if profile.is_none() {
+            let pending = db.get_text_attachments_for_label(None, reextract)?;
+            println!(
+                "(unlabelled): {} text attachments to extract",
+                pending.len()
+            );
+        }
+        return Ok(());
+    }
+
+    let llm_config = load_llm_config();
+    let db = db.with_date_hints(cfg.as_ref().map(|c| c.dates.clone()).unwrap_or_default());
+    pdf_extract::process_pdfs(
+        &db,
+        &llm_config,
+        &ocr_config(cfg.as_ref()),
+        &pdf_config(cfg.as_ref()),
+        &profiles,
+        profile.is_none(),
+        reextract,
+    )
+    .await
+}
 
-    filter::fetch_and_store(&hub, user, maxsoft_msgs, &db).await?;
+/// Print an attachment's stored invoices as JSON, or a one-line summary of each.
+fn show(cli: &Cli, attachment_id: Option<i64>) -> Result<(), Box<dyn std::error::Error>> {
+    let db = open_db(cli, &db_path(cli, load_optional_config(cli)?.as_ref()))?;
+
+    let Some(att_id) = attachment_id else {
+        for stored in db.list_invoices()? {
+            let inv = &stored.invoice;
+            println!(
+                "{:>6}  {:<12} {:<20} {:<24} {:>12} {:<4} {} ({})",
+                stored.attachment_id,
+                inv.invoice_no.as_deref().unwrap_or("-"),
+                inv.invoice_date.as_deref().unwrap_or("-"),
+                inv.vendor.as_deref().unwrap_or("-"),
+                inv.total_amount
+                    .map(|a| a.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                inv.currency.as_deref().unwrap_or(""),
+                stored.backend,
+                stored.created_at,
+            );
+        }
+        return Ok(());
+    };
+
+    let invoices = db.get_invoices_for_attachment(att_id)?;
+    if invoices.is_empty() {
+        return Err(format!("No invoice stored for attachment {att_id}").into());
+    }
+    for stored in invoices {
+        let pages = stored
+            .pages
+            .map(|p| format!(", pages {}-{}", p.first, p.last))
+            .unwrap_or_default();
+        println!(
+            "--- Invoice for attachment {}{pages} ({} / {}) ---",
+            stored.attachment_id,
+            stored.backend,
+            stored.model.as_deref().unwrap_or("-"),
+        );
+        println!("{}", serde_json::to_string_pretty(&stored.invoice)?);
+    }
+    Ok(())
+}
+
+/// Print the invoices matching the filters as a table or JSON.
+fn list_invoices(cli: &Cli, args: &InvoiceListArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let db = open_db(cli, &db_path(cli, load_optional_config(cli)?.as_ref()))?;
+
+    let mut query = InvoiceQuery::new().sort(args.sort);
+    if args.desc {
+        query = query.descending();
+    }
+    if let Some(vendor) = &args.vendor {
+        query = query.vendor(vendor);
+    }
+    if let Some(buyer) = &args.buyer {
+        query = query.buyer(buyer);
+    }
+    if let Some(invoice_no) = &args.invoice_no {
+        query = query.invoice_no(invoice_no);
+    }
+    if let Some(since) = args.since {
+        query = query.since(since);
+    }
+    if let Some(until) = args.until {
+        query = query.until(until);
+    }
+    if let Some(currency) = &args.currency {
+        query = query.currency(currency);
+    }
+    if let Some(min) = &args.min_amount {
+        query = query.min_amount(min);
+    }
+    if let Some(max) = &args.max_amount {
+        query = query.max_amount(max);
+    }
+    if let Some(item) = &args.item {
+        query = query.item(item);
+    }
+    let total = db.count_invoices(&query)?;
+    let invoices = db.query_invoices(&query.limit(args.limit).offset(args.offset))?;
+
+    match args.format {
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&invoices)?),
+        ListFormat::Table => {
+            println!(
+                "{:>6} {:>6}  {:<16} {:<12} {:<28} {:>14} {:<4} {:>5}",
+                "id", "att", "invoice no", "date", "vendor", "total", "cur", "items"
+            );
+            for stored in &invoices {
+                let inv = &stored.invoice;
+                let vendor = inv.vendor.as_deref().unwrap_or("-");
+                println!(
+                    "{:>6} {:>6}  {:<16} {:<12} {:<28} {:>14} {:<4} {:>5}",
+                    stored.id,
+                    stored.attachment_id,
+                    inv.invoice_no.as_deref().unwrap_or("-"),
+                    stored
+                        .invoice_date_iso
+                        .as_deref()
+                        .or(inv.invoice_date.as_deref())
+                        .unwrap_or("-"),
+                    &vendor[..vendor.floor_char_boundary(28)],
+                    inv.total_amount
+                        .map(|a| a.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    inv.currency.as_deref().unwrap_or(""),
+                    inv.line_items.len(),
+                );
+            }
+            if invoices.is_empty() {
+                println!("\n{total} matching, none past offset {}.", args.offset);
+            } else {
+                println!(
+                    "\n{}-{} of {total}",
+                    args.offset + 1,
+                    args.offset + invoices.len()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write all stored invoices as JSON or CSV.
+fn export(
+    cli: &Cli,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = open_db(cli, &db_path(cli, load_optional_config(cli)?.as_ref()))?;
+    let invoices = db.list_invoices()?;
+
+    if cli.dry_run {
+        let target = output.map_or("stdout".to_string(), |p| p.display().to_string());
+        println!("Would export {} invoices to {target}", invoices.len());
+        return Ok(());
+    }
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &invoices)?;
+            writeln!(out)?;
+        }
+        ExportFormat::Csv => write_invoices_csv(&mut out, &invoices)?,
+    }
+    out.flush()?;
+
+    if let Some(path) = output {
+        info!(count = invoices.len(), path = %path.display(), "Exported invoices");
+    }
+    Ok(())
+}
+
+fn write_invoices_csv(
+    out: &mut dyn Write,
+    invoices: &[StoredInvoice],
+) -> Result<(), Box<dyn std::error::Error>> {
+    writeln!(
+        out,
+        "attachment_id,first_page,last_page,backend,model,vendor,buyer,invoice_no,invoice_date,invoice_date_iso,\
+         currency,total_amount,total_pieces,ship_from,ship_to,shipping_method,line_items"
+    )?;
+    for stored in invoices {
+        let inv = &stored.invoice;
+        let fields = [
+            stored.attachment_id.to_string(),
+            stored
+                .pages
+                .map(|p| p.first.to_string())
+                .unwrap_or_default(),
+            stored.pages.map(|p| p.last.to_string()).unwrap_or_default(),
+            stored.backend.clone(),
+            stored.model.clone().unwrap_or_default(),
+            inv.vendor.clone().unwrap_or_default(),
+            inv.buyer.clone().unwrap_or_default(),
+            inv.invoice_no.clone().unwrap_or_default(),
+            inv.invoice_date.clone().unwrap_or_default(),
+            stored.invoice_date_iso.clone().unwrap_or_default(),
+            inv.currency.clone().unwrap_or_default(),
+            inv.total_amount.map(|a| a.to_string()).unwrap_or_default(),
+            inv.total_pieces.map(|p| p.to_string()).unwrap_or_default(),
+            inv.ship_from.clone().unwrap_or_default(),
+            inv.ship_to.clone().unwrap_or_default(),
+            inv.shipping_method.clone().unwrap_or_default(),
+            inv.line_items.len().to_string(),
+        ];
+        let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        writeln!(out, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+/// Quote a CSV field if it contains a delimiter, quote or newline.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Print search hits: where each match is, then its snippet.
+fn search(cli: &Cli, query: &SearchQuery) -> Result<(), Box<dyn std::error::Error>> {
+    let db = open_db(cli, &db_path(cli, load_optional_config(cli)?.as_ref()))?;
+
+    // Bold in a terminal, plain markers when piped
+    let highlight = if std::io::stdout().is_terminal() {
+        ("\x1b[1m", "\x1b[0m")
+    } else {
+        ("[", "]")
+    };
+    let hits = db.search(query, highlight)?;
+    if hits.is_empty() {
+        println!("No matches.");
+        return Ok(());
+    }
+    for hit in &hits {
+        let location = match (hit.attachment_id, &hit.filename) {
+            (Some(id), Some(filename)) => format!("attachment {id} {filename}"),
+            _ => "message".to_string(),
+        };
+        println!(
+            "{}  {location}\n  {}  {}  {}",
+            hit.message_uid,
+            hit.date,
+            hit.from_addr.as_deref().unwrap_or("-"),
+            hit.subject.as_deref().unwrap_or("-"),
+        );
+        println!(
+            "  {}\n",
+            hit.snippet.split_whitespace().collect::<Vec<_>>().join(" ")
+        );
+    }
+    println!("{} match(es)", hits.len());
+    Ok(())
+}
+
+/// Print database statistics.
+fn stats(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let db = open_db(cli, &db_path(cli, load_optional_config(cli)?.as_ref()))?;
 
-    // Print statistics
     let (total_msgs, processed_msgs, total_pdfs, processed_pdfs) = db.get_counts()?;
-    info!(
-        messages_total = total_msgs,
-        messages_processed = processed_msgs,
-        pdfs_total = total_pdfs,
-        pdfs_processed = processed_pdfs,
-        "Database statistics"
+    println!("Messages:    {total_msgs} ({processed_msgs} processed)");
+    println!("Attachments: {total_pdfs} ({processed_pdfs} classified)");
+    for (content_type, count) in db.get_content_type_counts()? {
+        println!("  {content_type:<10} {count}");
+    }
+    let duplicates = db.get_duplicate_count()?;
+    if duplicates > 0 {
+        println!("  {:<10} {duplicates}", "duplicate");
+    }
+    let (blobs, blob_bytes) = db.get_blob_counts()?;
+    println!(
+        "Stored PDFs: {blobs} distinct, {:.1} MB",
+        blob_bytes as f64 / 1_048_576.0
+    );
+    let producers = db.get_producer_counts()?;
+    if !producers.is_empty() {
+        println!("Producers:");
+        for (producer, count) in producers.iter().take(10) {
+            println!("  {count:>5}  {producer}");
+        }
+    }
+    let invoice_counts = db.get_invoice_counts()?;
+    let total_invoices: usize = invoice_counts.iter().map(|(_, n)| n).sum();
+    println!("Invoices:    {total_invoices}");
+    for (backend, count) in invoice_counts {
+        println!("  {backend:<10} {count}");
+    }
+    Ok(())
+}
+
+/// Attachments no configured password opens, with their senders.
+fn encrypted(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let db = open_db(cli, &db_path(cli, load_optional_config(cli)?.as_ref()))?;
+
+    let attachments = db.get_encrypted_attachments()?;
+    if attachments.is_empty() {
+        println!("No encrypted attachments.");
+        return Ok(());
+    }
+    for att in &attachments {
+        let from = db
+            .get_message_by_uid(&att.message_uid)?
+            .and_then(|m| m.from_addr);
+        println!(
+            "{:>6}  {:<40} {}",
+            att.id.unwrap_or_default(),
+            att.filename,
+            from.as_deref().unwrap_or("-"),
+        );
+    }
+    println!(
+        "\n{} encrypted — add passwords under [[pdf.passwords]] and re-run `extract`.",
+        attachments.len()
     );
+    Ok(())
+}
 
-    // Process unprocessed PDF attachments
-    pdf_extract::run_pdf_extraction(&db)?;
+/// List schema migrations and whether the database has them, applying the
+/// pending ones unless only the status was asked for.
+fn migrate(cli: &Cli, status_only: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let path = db_path(cli, load_optional_config(cli)?.as_ref());
+    let current = if path.exists() {
+        MessageStore::peek_schema_version(&path)?
+    } else {
+        0
+    };
+    println!(
+        "{}: schema version {current} (this build: {})",
+        path.display(),
+        migrations::latest()
+    );
+    if current > migrations::latest() {
+        return Err("database is newer than this build — upgrade invoice_search".into());
+    }
+    for m in migrations::MIGRATIONS {
+        let state = if m.version <= current {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!("  {:>3}  {:<24} {state}", m.version, m.name);
+    }
 
+    if status_only || current == migrations::latest() {
+        return Ok(());
+    }
+    if cli.dry_run {
+        println!(
+            "Would apply {} migration(s)",
+            migrations::latest() - current
+        );
+        return Ok(());
+    }
+    let db = MessageStore::new(&path)?;
+    println!("Migrated to schema version {}", db.schema_version()?);
     Ok(())
 }
 
+/// The database at `path`, migrated first on real runs. Under `--dry-run`
+/// it is opened read-only: never created, migrated or written.
+fn open_db(cli: &Cli, path: &Path) -> Result<MessageStore, Box<dyn std::error::Error>> {
+    if !cli.dry_run {
+        return Ok(MessageStore::new(path)?);
+    }
+    if !path.exists() {
+        return Err(format!("{}: no database yet; a real run creates it", path.display()).into());
+    }
+    MessageStore::open_read_only(path).map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+fn config_path(cli: &Cli) -> PathBuf {
+    cli.config
+        .clone()
+        .unwrap_or_else(gmail_hub::default_config_path)
+}
+
+/// Load the mailbox / profile config; it must exist.
+fn load_config(cli: &Cli) -> Result<Config, Box<dyn std::error::Error>> {
+    Config::load(config_path(cli))
+}
+
+/// Load the config for commands that can run without one. A missing default
+/// config is fine; an explicit `--config` must exist.
+fn load_optional_config(cli: &Cli) -> Result<Option<Config>, Box<dyn std::error::Error>> {
+    let path = config_path(cli);
+    if cli.config.is_none() && !path.exists() {
+        return Ok(None);
+    }
+    Config::load(path).map(Some)
+}
+
+/// The `[ocr]` table, or defaults when running without a config.
+fn ocr_config(cfg: Option<&Config>) -> OcrConfig {
+    cfg.map(|c| c.ocr.clone()).unwrap_or_default()
+}
+
+/// The `[pdf]` table, or defaults when running without a config.
+fn pdf_config(cfg: Option<&Config>) -> PdfConfig {
+    cfg.map(|c| c.pdf.clone()).unwrap_or_default()
+}
+
+/// `--db`, else `db_path` from the config, else the default location.
+fn db_path(cli: &Cli, cfg: Option<&Config>) -> PathBuf {
+    cli.db
+        .clone()
+        .or_else(|| cfg.map(|c| PathBuf::from(&c.db_path)))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DB_PATH))
+}
+
+/// Install the rustls crypto provider used by the Gmail / OAuth HTTP clients.
+fn install_crypto_provider() {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+}
+
 /// Load LLM config from `.config/llm_conf.toml`, falling back to defaults.
 fn load_llm_config() -> LlmSection {
     let path = LlmConfig::default_path();
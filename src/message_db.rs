@@ -1,10 +1,28 @@
-use rusqlite::{Connection, Result as SqliteResult, params};
+pub mod invoice_query;
+pub mod migrations;
+
+use crate::dates::{self, DateHints};
+use crate::heuristics::{InvoiceData, LineItem, PackingItem, PackingTotals};
+use crate::money::{Currency, Money};
+use crate::ocr::OcrPage;
+use crate::pdf_extract::metadata::PdfMetadata;
+use crate::pdf_extract::segment::PageRange;
+use crate::pdf_extract::{PageContent, PageKind};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Result as SqliteResult, params};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::OnceCell;
 use std::path::Path;
+use time::Date;
 use tracing::info;
 
+pub use invoice_query::{InvoiceQuery, InvoiceSort};
+
 pub struct MessageStore {
     conn: Connection,
+    /// Day/month order used to normalize ambiguous invoice dates on write
+    date_hints: DateHints,
 }
 
 #[derive(Debug)]
@@ -27,114 +45,184 @@ pub struct StoredAttachment {
     pub message_uid: String,
     pub filename: String,
     pub attachment_id: Option<String>,
-    pub pdf_data: Vec<u8>,
+    pub pdf_data: Blob,
     pub is_processed: bool,
-    /// Classification after extraction: "text", "scanned", "error", or "unknown"
+    /// Classification after extraction: "text", "mixed", "scanned", "ocr",
+    /// "einvoice", "empty", "encrypted", "error", or "unknown" (not yet classified)
     pub content_type: Option<String>,
-    /// Extracted plain text (populated only when content_type == "text")
+    /// Text for the later stages: the native pages' text for "text" and
+    /// "mixed", native and OCR text for "ocr", the embedded XML for
+    /// "einvoice", and the parse error for "error" PDFs that failed to load.
+    /// None otherwise
     pub extracted_text: Option<String>,
 }
 
-impl MessageStore {
-    /// Create a new message store with SQLite backend
-    pub fn new<P: AsRef<Path>>(db_path: P) -> SqliteResult<Self> {
-        let conn = Connection::open(db_path)?;
+/// Attachment bytes, stored once per distinct content in the `blobs` table
+/// (keyed by SHA-256) and only read from it when first needed.
+#[derive(Clone)]
+pub struct Blob {
+    pub sha256: String,
+    data: OnceCell<Vec<u8>>,
+}
 
-        // Create messages table
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS messages (
-                uid TEXT PRIMARY KEY,
-                message_id TEXT NOT NULL,
-                user TEXT NOT NULL,
-                date TEXT NOT NULL,
-                from_addr TEXT,
-                subject TEXT,
-                plain_text TEXT,
-                html TEXT,
-                has_attachments INTEGER NOT NULL DEFAULT 0,
-                is_processed INTEGER NOT NULL DEFAULT 0,
-                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
-            )",
-            [],
-        )?;
+impl Blob {
+    /// Bytes in hand, e.g. a freshly downloaded attachment.
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            sha256: sha256_hex(&data),
+            data: OnceCell::from(data),
+        }
+    }
 
-        // Create attachments table for PDF storage
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS attachments (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                message_uid TEXT NOT NULL,
-                filename TEXT NOT NULL,
-                attachment_id TEXT,
-                pdf_data BLOB NOT NULL,
-                is_processed INTEGER NOT NULL DEFAULT 0,
-                content_type TEXT NOT NULL DEFAULT 'unknown',
-                extracted_text TEXT,
-                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY (message_uid) REFERENCES messages(uid) ON DELETE CASCADE
-            )",
-            [],
-        )?;
+    /// A stored blob, not read yet.
+    fn stored(sha256: String) -> Self {
+        Self {
+            sha256,
+            data: OnceCell::new(),
+        }
+    }
 
-        // Create processed_messages table for tracking
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS processed_messages (
-                uid TEXT PRIMARY KEY,
-                processed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY (uid) REFERENCES messages(uid)
-            )",
-            [],
-        )?;
+    /// The bytes, read from `db` on first use.
+    pub fn load(&self, db: &MessageStore) -> SqliteResult<&[u8]> {
+        if let Some(data) = self.data.get() {
+            return Ok(data);
+        }
+        let data = db.get_blob(&self.sha256)?;
+        Ok(self.data.get_or_init(|| data))
+    }
 
-        // Create processed_attachments table for tracking
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS processed_attachments (
-                attachment_id INTEGER PRIMARY KEY,
-                processed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY (attachment_id) REFERENCES attachments(id)
-            )",
-            [],
-        )?;
+    /// The bytes without keeping them, for passes over many attachments.
+    pub fn read(&self, db: &MessageStore) -> SqliteResult<Cow<'_, [u8]>> {
+        match self.data.get() {
+            Some(data) => Ok(Cow::Borrowed(data)),
+            None => db.get_blob(&self.sha256).map(Cow::Owned),
+        }
+    }
+}
 
-        // Create indexes
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_messages_user ON messages(user)",
-            [],
-        )?;
+impl std::fmt::Debug for Blob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Blob")
+            .field("sha256", &self.sha256)
+            .field("loaded", &self.data.get().map(Vec::len))
+            .finish()
+    }
+}
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_messages_is_processed ON messages(is_processed)",
-            [],
-        )?;
+/// Quote each word of free text as an FTS5 phrase, so punctuation in
+/// invoice numbers and part codes is matched rather than parsed as syntax.
+fn fts_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_messages_date ON messages(date)",
-            [],
-        )?;
+/// Hex SHA-256 of some bytes: the blob store key.
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_attachments_message_uid ON attachments(message_uid)",
-            [],
-        )?;
+/// Last successful Gmail sync position for a (user, query) pair.
+#[derive(Debug, Clone, Copy)]
+pub struct SyncState {
+    /// Mailbox `historyId` at the time of the last sync
+    pub history_id: u64,
+    /// Unix timestamp (seconds) of the last sync
+    pub synced_at: i64,
+}
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_attachments_is_processed ON attachments(is_processed)",
-            [],
-        )?;
+/// What to look for with [`MessageStore::search`].
+#[derive(Debug, Default)]
+pub struct SearchQuery {
+    /// Words that must all appear, in any order and any indexed field
+    pub text: String,
+    /// Part of the sender address, case-insensitive
+    pub sender: Option<String>,
+    /// Message dates (UTC days), both inclusive
+    pub since: Option<Date>,
+    pub until: Option<Date>,
+    /// Attachment content type ("text", "ocr", ...); leaves out hits in
+    /// message bodies
+    pub content_type: Option<String>,
+    pub limit: usize,
+}
 
-        // Migrate: add content_type and extracted_text columns if missing
-        let has_content_type: bool = conn
-            .prepare("SELECT content_type FROM attachments LIMIT 0")
-            .is_ok();
-        if !has_content_type {
-            conn.execute_batch(
-                "ALTER TABLE attachments ADD COLUMN content_type TEXT;
-                 ALTER TABLE attachments ADD COLUMN extracted_text TEXT;",
-            )?;
-            info!("Migrated attachments table: added content_type, extracted_text");
-        }
+/// A message, or one of its attachments, matching a search.
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub message_uid: String,
+    /// `None` when the match is in the message itself
+    pub attachment_id: Option<i64>,
+    pub filename: Option<String>,
+    pub date: String,
+    pub from_addr: Option<String>,
+    pub subject: Option<String>,
+    /// Matching text around the hit, search terms wrapped in the markers
+    pub snippet: String,
+}
 
+/// A structured invoice persisted from an extraction run, keyed by attachment
+/// and, for PDFs bundling several documents, page range.
+#[derive(Debug, Serialize)]
+pub struct StoredInvoice {
+    pub id: i64,
+    pub attachment_id: i64,
+    /// Pages of the attachment the invoice was read from; `None` when it
+    /// covers the whole attachment
+    pub pages: Option<PageRange>,
+    /// Extraction backend that produced the data ("ollama", "heuristics", ...)
+    pub backend: String,
+    /// Model name for LLM backends; `None` for heuristics
+    pub model: Option<String>,
+    pub created_at: String,
+    /// `invoice.invoice_date` as YYYY-MM-DD, when it could be read
+    pub invoice_date_iso: Option<String>,
+    pub invoice: InvoiceData,
+}
+
+impl MessageStore {
+    /// Open (or create) the SQLite database and bring its schema up to date.
+    /// Foreign keys are enforced on every connection; SQLite's default is off.
+    pub fn new<P: AsRef<Path>>(db_path: P) -> SqliteResult<Self> {
+        let mut conn = Connection::open(db_path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrations::migrate(&mut conn)?;
         info!("Database initialized successfully");
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            date_hints: DateHints::default(),
+        })
+    }
+
+    /// Open an existing database read-only, e.g. for `--dry-run`. Nothing
+    /// is created or migrated; a schema behind this build is refused.
+    pub fn open_read_only<P: AsRef<Path>>(db_path: P) -> SqliteResult<Self> {
+        let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrations::ensure_current(&conn)?;
+        Ok(Self {
+            conn,
+            date_hints: DateHints::default(),
+        })
+    }
+
+    /// Use `hints` for invoice dates written from now on.
+    pub fn with_date_hints(mut self, hints: DateHints) -> Self {
+        self.date_hints = hints;
+        self
+    }
+
+    /// Schema version of the database at `db_path`, read without creating
+    /// or migrating anything.
+    pub fn peek_schema_version<P: AsRef<Path>>(db_path: P) -> SqliteResult<u32> {
+        let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        migrations::schema_version(&conn)
+    }
+
+    /// Schema version this database is at.
+    pub fn schema_version(&self) -> SqliteResult<u32> {
+        migrations::schema_version(&self.conn)
     }
 
     /// Generate a unique ID from message_id, date, and user
@@ -146,54 +234,47 @@ impl MessageStore {
         format!("{:x}", hasher.finalize())
     }
 
-    /// Insert or update a message
-    pub fn upsert_message(&self, msg: &StoredMessage) -> SqliteResult<()> {
-        self.conn.execute(
-            "INSERT INTO messages 
-                (uid, message_id, user, date, from_addr, subject, plain_text, html, has_attachments, is_processed)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-             ON CONFLICT(uid) DO UPDATE SET
-                from_addr = excluded.from_addr,
-                subject = excluded.subject,
-                plain_text = excluded.plain_text,
-                html = excluded.html,
-                has_attachments = excluded.has_attachments",
-            params![
-                msg.uid,
-                msg.message_id,
-                msg.user,
-                msg.date,
-                msg.from_addr,
-                msg.subject,
-                msg.plain_text,
-                msg.html,
-                msg.has_attachments,
-                msg.is_processed,
-            ],
-        )?;
-        info!(uid = %msg.uid, "Message stored");
-        Ok(())
+    /// Store a fetched message together with its attachments in one
+    /// transaction, recording that Gmail listed `attachment_count` PDFs for
+    /// it. Returns each attachment's new id, or `None` where the same
+    /// (message_uid, attachment_id) was already stored.
+    pub fn store_message(
+        &self,
+        msg: &StoredMessage,
+        attachment_count: usize,
+        attachments: &[StoredAttachment],
+    ) -> SqliteResult<Vec<Option<i64>>> {
+        let tx = self.conn.unchecked_transaction()?;
+        write_message(&tx, msg, Some(attachment_count))?;
+        let ids = attachments
+            .iter()
+            .map(|attachment| write_attachment(&tx, attachment))
+            .collect::<SqliteResult<_>>()?;
+        tx.commit()?;
+        Ok(ids)
     }
 
-    /// Insert an attachment (PDF)
-    pub fn insert_attachment(&self, attachment: &StoredAttachment) -> SqliteResult<i64> {
-        self.conn.execute(
-            "INSERT INTO attachments 
-                (message_uid, filename, attachment_id, pdf_data, is_processed, content_type, extracted_text)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-            params![
-                attachment.message_uid,
-                attachment.filename,
-                attachment.attachment_id,
-                attachment.pdf_data,
-                attachment.is_processed,
-                attachment.content_type,
-                attachment.extracted_text,
-            ],
-        )?;
-        let id = self.conn.last_insert_rowid();
-        info!(attachment_id = id, filename = %attachment.filename, "Attachment stored");
-        Ok(id)
+    /// Check whether a Gmail message has already been stored for a user
+    /// along with all of its attachments. Messages stored before attachment
+    /// counts were kept count as complete once they have any attachment.
+    pub fn has_message(&self, user: &str, message_id: &str) -> SqliteResult<bool> {
+        self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM messages m
+                           WHERE m.user = ?1 AND m.message_id = ?2
+                             AND (SELECT COUNT(*) FROM attachments a WHERE a.message_uid = m.uid)
+                                 >= COALESCE(m.attachment_count, m.has_attachments))",
+            params![user, message_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Check whether an attachment has already been stored for a message.
+    pub fn has_attachment(&self, message_uid: &str, attachment_id: &str) -> SqliteResult<bool> {
+        self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM attachments WHERE message_uid = ?1 AND attachment_id = ?2)",
+            params![message_uid, attachment_id],
+            |row| row.get(0),
+        )
     }
 
     /// Mark a message as processed
@@ -260,27 +341,308 @@ impl MessageStore {
         Ok(())
     }
 
-    /// Get all attachments that contain extractable text (for heuristic parsing).
+    /// Get all attachments that contain extractable text, native or OCR'd
+    /// (for heuristic parsing). `mixed` ones count with their native pages
+    /// until OCR fills in the rest.
     pub fn get_text_attachments(&self) -> SqliteResult<Vec<StoredAttachment>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, message_uid, filename, attachment_id, pdf_data, is_processed, content_type, extracted_text
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
              FROM attachments
-             WHERE content_type = 'text'
+             WHERE content_type IN ('text', 'mixed', 'ocr')
              ORDER BY created_at DESC",
         )?;
-        let rows = stmt.query_map([], |row| Self::row_to_attachment(row))?;
+        let rows = stmt.query_map([], Self::row_to_attachment)?;
+        rows.collect()
+    }
+
+    /// Text attachments of messages carrying `label`, or of messages with no
+    /// label at all when `label` is `None`, that have no invoice yet so each
+    /// goes to the LLM once. With `reextract`, those with one as well.
+    pub fn get_text_attachments_for_label(
+        &self,
+        label: Option<&str>,
+        reextract: bool,
+    ) -> SqliteResult<Vec<StoredAttachment>> {
+        self.attachments_for_label("content_type IN ('text', 'mixed', 'ocr')", label, reextract)
+    }
+
+    /// Scanned attachments of messages carrying `label` (or unlabelled) that
+    /// have no invoice yet, so the vision model sees each one only once.
+    /// With `reextract`, those with one as well.
+    pub fn get_scanned_attachments_for_label(
+        &self,
+        label: Option<&str>,
+        reextract: bool,
+    ) -> SqliteResult<Vec<StoredAttachment>> {
+        self.attachments_for_label("content_type = 'scanned'", label, reextract)
+    }
+
+    fn attachments_for_label(
+        &self,
+        content_filter: &str,
+        label: Option<&str>,
+        reextract: bool,
+    ) -> SqliteResult<Vec<StoredAttachment>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
+             FROM attachments a
+             WHERE {content_filter}
+               AND (?2 OR NOT EXISTS (SELECT 1 FROM invoices i WHERE i.attachment_id = a.id))
+               AND CASE WHEN ?1 IS NULL
+                   THEN NOT EXISTS (SELECT 1 FROM message_labels l WHERE l.message_uid = a.message_uid)
+                   ELSE EXISTS (SELECT 1 FROM message_labels l WHERE l.message_uid = a.message_uid AND l.label = ?1)
+               END
+             ORDER BY created_at DESC"
+        ))?;
+        let rows = stmt.query_map(params![label, reextract], Self::row_to_attachment)?;
+        rows.collect()
+    }
+
+    /// Replace the per-page classification of an attachment.
+    pub fn set_attachment_pages(
+        &self,
+        attachment_id: i64,
+        pages: &[PageContent],
+    ) -> SqliteResult<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM attachment_pages WHERE attachment_id = ?1",
+            params![attachment_id],
+        )?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO attachment_pages (attachment_id, page, kind, text)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for page in pages {
+                stmt.execute(params![
+                    attachment_id,
+                    page.page,
+                    page.kind.as_str(),
+                    page.text
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Per-page classification of an attachment, in page order. Empty for
+    /// attachments classified before pages were recorded.
+    pub fn get_attachment_pages(&self, attachment_id: i64) -> SqliteResult<Vec<PageContent>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT page, kind, text FROM attachment_pages
+             WHERE attachment_id = ?1 ORDER BY page",
+        )?;
+        let rows = stmt.query_map(params![attachment_id], |row| {
+            let kind: String = row.get(1)?;
+            Ok(PageContent {
+                page: row.get(0)?,
+                kind: PageKind::parse(&kind).unwrap_or(PageKind::Error),
+                text: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Store OCR output for a scanned attachment: one row per page, the
+    /// joined text in `extracted_text`, and content type `ocr`.
+    pub fn set_attachment_ocr(
+        &self,
+        attachment_id: i64,
+        pages: &[OcrPage],
+        text: &str,
+    ) -> SqliteResult<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM ocr_pages WHERE attachment_id = ?1",
+            params![attachment_id],
+        )?;
+        // A mixed attachment's invoices were read from its native pages
+        // alone; dropping them has the full text extracted again
+        let mixed: bool = tx.query_row(
+            "SELECT content_type = 'mixed' FROM attachments WHERE id = ?1",
+            params![attachment_id],
+            |row| row.get(0),
+        )?;
+        if mixed {
+            Self::delete_invoices(&tx, attachment_id)?;
+        }
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO ocr_pages (attachment_id, page, confidence, text)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for page in pages {
+                stmt.execute(params![
+                    attachment_id,
+                    page.page,
+                    page.confidence,
+                    page.text
+                ])?;
+            }
+        }
+        tx.execute(
+            "UPDATE attachments SET content_type = 'ocr', extracted_text = ?1 WHERE id = ?2",
+            params![text, attachment_id],
+        )?;
+        tx.commit()?;
+        info!(
+            attachment_id = attachment_id,
+            pages = pages.len(),
+            "OCR text stored"
+        );
+        Ok(())
+    }
+
+    /// Per-page OCR confidence for an attachment, in page order.
+    pub fn get_ocr_confidence(&self, attachment_id: i64) -> SqliteResult<Vec<(u32, f32)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT page, confidence FROM ocr_pages WHERE attachment_id = ?1 ORDER BY page",
+        )?;
+        let rows = stmt.query_map(params![attachment_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// OCR text of each page of an attachment, in page order.
+    pub fn get_ocr_pages(&self, attachment_id: i64) -> SqliteResult<Vec<OcrPage>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT page, text, confidence FROM ocr_pages WHERE attachment_id = ?1 ORDER BY page",
+        )?;
+        let rows = stmt.query_map(params![attachment_id], |row| {
+            Ok(OcrPage {
+                page: row.get(0)?,
+                text: row.get(1)?,
+                confidence: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Record a PDF's metadata on its attachment row. The fingerprint is
+    /// already there: it keys the attachment's blob.
+    pub fn set_attachment_metadata(
+        &self,
+        attachment_id: i64,
+        meta: &PdfMetadata,
+    ) -> SqliteResult<()> {
+        self.conn.execute(
+            "UPDATE attachments SET page_count = ?1, pdf_version = ?2, producer = ?3,
+                creator = ?4, pdf_created = ?5, pdf_modified = ?6, encrypted = ?7,
+                byte_size = ?8
+             WHERE id = ?9",
+            params![
+                meta.page_count,
+                meta.version,
+                meta.producer,
+                meta.creator,
+                meta.created,
+                meta.modified,
+                meta.encrypted,
+                meta.byte_size,
+                attachment_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Metadata recorded for an attachment; `None` until it has been read.
+    pub fn get_attachment_metadata(&self, attachment_id: i64) -> SqliteResult<Option<PdfMetadata>> {
+        self.conn
+            .query_row(
+                "SELECT page_count, pdf_version, producer, creator, pdf_created, pdf_modified,
+                        encrypted, byte_size, sha256
+                 FROM attachments
+                 WHERE id = ?1 AND encrypted IS NOT NULL",
+                params![attachment_id],
+                |row| {
+                    Ok(PdfMetadata {
+                        page_count: row.get(0)?,
+                        version: row.get(1)?,
+                        producer: row.get(2)?,
+                        creator: row.get(3)?,
+                        created: row.get(4)?,
+                        modified: row.get(5)?,
+                        encrypted: row.get(6)?,
+                        byte_size: row.get(7)?,
+                        sha256: row.get(8)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// The earliest other attachment with the same content, if any.
+    pub fn find_attachment_by_sha256(
+        &self,
+        sha256: &str,
+        exclude_id: i64,
+    ) -> SqliteResult<Option<i64>> {
+        self.conn.query_row(
+            "SELECT MIN(id) FROM attachments WHERE sha256 = ?1 AND id != ?2",
+            params![sha256, exclude_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Classified attachments whose metadata has not been recorded yet.
+    pub fn get_attachments_without_metadata(&self) -> SqliteResult<Vec<StoredAttachment>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
+             FROM attachments
+             WHERE is_processed = 1 AND encrypted IS NULL",
+        )?;
+        let rows = stmt.query_map([], Self::row_to_attachment)?;
         rows.collect()
     }
 
-    /// Get all attachments that need OCR (scanned images).
+    /// Number of attachments per PDF producer, most common first.
+    pub fn get_producer_counts(&self) -> SqliteResult<Vec<(String, usize)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT COALESCE(producer, '(unknown)'), COUNT(*) FROM attachments
+             WHERE encrypted IS NOT NULL
+             GROUP BY 1 ORDER BY 2 DESC, 1",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Attachments whose content repeats an earlier attachment's.
+    pub fn get_duplicate_count(&self) -> SqliteResult<usize> {
+        self.conn.query_row(
+            "SELECT COUNT(*) - COUNT(DISTINCT sha256) FROM attachments",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    /// Bytes of a stored blob.
+    pub fn get_blob(&self, sha256: &str) -> SqliteResult<Vec<u8>> {
+        self.conn.query_row(
+            "SELECT data FROM blobs WHERE sha256 = ?1",
+            params![sha256],
+            |row| row.get(0),
+        )
+    }
+
+    /// Number of distinct blobs and their total size in bytes.
+    pub fn get_blob_counts(&self) -> SqliteResult<(usize, usize)> {
+        self.conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM blobs",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    /// Get all attachments that need OCR: scanned ones, and the scanned
+    /// pages of mixed ones.
     pub fn get_scanned_attachments(&self) -> SqliteResult<Vec<StoredAttachment>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, message_uid, filename, attachment_id, pdf_data, is_processed, content_type, extracted_text
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
              FROM attachments
-             WHERE content_type = 'scanned'
+             WHERE content_type IN ('scanned', 'mixed')
              ORDER BY created_at DESC",
         )?;
-        let rows = stmt.query_map([], |row| Self::row_to_attachment(row))?;
+        let rows = stmt.query_map([], Self::row_to_attachment)?;
         rows.collect()
     }
 
@@ -291,7 +653,7 @@ impl MessageStore {
             message_uid: row.get(1)?,
             filename: row.get(2)?,
             attachment_id: row.get(3)?,
-            pdf_data: row.get(4)?,
+            pdf_data: Blob::stored(row.get(4)?),
             is_processed: row.get(5)?,
             content_type: row.get(6)?,
             extracted_text: row.get(7)?,
@@ -328,20 +690,20 @@ impl MessageStore {
     /// Get all unprocessed PDF attachments (for batch processing)
     pub fn get_unprocessed_attachments(&self) -> SqliteResult<Vec<StoredAttachment>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, message_uid, filename, attachment_id, pdf_data, is_processed, content_type, extracted_text
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
              FROM attachments
              WHERE is_processed = 0
              ORDER BY created_at DESC",
         )?;
 
-        let attachments = stmt.query_map([], |row| Self::row_to_attachment(row))?;
+        let attachments = stmt.query_map([], Self::row_to_attachment)?;
         attachments.collect()
     }
 
     /// Get a single attachment by its primary key ID.
     pub fn get_attachment_by_id(&self, id: i64) -> SqliteResult<Option<StoredAttachment>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, message_uid, filename, attachment_id, pdf_data, is_processed, content_type, extracted_text
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
              FROM attachments
              WHERE id = ?1",
         )?;
@@ -358,14 +720,13 @@ impl MessageStore {
         message_uid: &str,
     ) -> SqliteResult<Vec<StoredAttachment>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, message_uid, filename, attachment_id, pdf_data, is_processed, content_type, extracted_text
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
              FROM attachments
              WHERE message_uid = ?1
              ORDER BY created_at",
         )?;
 
-        let attachments =
-            stmt.query_map(params![message_uid], |row| Self::row_to_attachment(row))?;
+        let attachments = stmt.query_map(params![message_uid], Self::row_to_attachment)?;
         attachments.collect()
     }
 
@@ -432,28 +793,559 @@ impl MessageStore {
         content_type: &str,
     ) -> SqliteResult<Vec<StoredAttachment>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, message_uid, filename, attachment_id, pdf_data, is_processed, content_type, extracted_text
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
              FROM attachments
              WHERE content_type = ?1
              ORDER BY created_at DESC",
         )?;
 
-        let attachments = stmt.query_map(params![content_type], |row| {
-            Ok(StoredAttachment {
-                id: Some(row.get(0)?),
-                message_uid: row.get(1)?,
-                filename: row.get(2)?,
-                attachment_id: row.get(3)?,
-                pdf_data: row.get(4)?,
-                is_processed: row.get(5)?,
-                content_type: row.get(6)?,
-                extracted_text: row.get(7)?,
-            })
-        })?;
+        let attachments = stmt.query_map(params![content_type], Self::row_to_attachment)?;
 
         attachments.collect()
     }
 
+    /// Attachments waiting on a password: classified `encrypted`, plus
+    /// `error` ones from before encrypted PDFs were told apart whose
+    /// metadata says they are encrypted.
+    pub fn get_encrypted_attachments(&self) -> SqliteResult<Vec<StoredAttachment>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text
+             FROM attachments
+             WHERE content_type = 'encrypted' OR (content_type = 'error' AND encrypted = 1)
+             ORDER BY created_at DESC",
+        )?;
+        let rows = stmt.query_map([], Self::row_to_attachment)?;
+        rows.collect()
+    }
+
+    /// Persist a structured invoice for an attachment, replacing any previous
+    /// extraction result for the same attachment. Returns the invoice row id.
+    pub fn insert_invoice(
+        &self,
+        attachment_id: i64,
+        backend: &str,
+        model: Option<&str>,
+        invoice: &InvoiceData,
+    ) -> SqliteResult<i64> {
+        let ids =
+            self.insert_invoices(attachment_id, backend, model, &[(None, invoice.clone())])?;
+        Ok(ids[0])
+    }
+
+    /// Persist the invoices of each logical document in an attachment,
+    /// replacing every previous extraction result for the attachment. Returns
+    /// the invoice row ids in order.
+    pub fn insert_invoices(
+        &self,
+        attachment_id: i64,
+        backend: &str,
+        model: Option<&str>,
+        invoices: &[(Option<PageRange>, InvoiceData)],
+    ) -> SqliteResult<Vec<i64>> {
+        let tx = self.conn.unchecked_transaction()?;
+
+        // Drop the previous results so re-runs don't accumulate
+        Self::delete_invoices(&tx, attachment_id)?;
+
+        let mut ids = Vec::with_capacity(invoices.len());
+        for (pages, invoice) in invoices {
+            ids.push(Self::insert_invoice_rows(
+                &tx,
+                attachment_id,
+                backend,
+                model,
+                *pages,
+                invoice,
+                self.invoice_date_iso(invoice),
+            )?);
+        }
+
+        tx.commit()?;
+        info!(
+            invoice_ids = ?ids,
+            attachment_id = attachment_id,
+            backend = backend,
+            "Invoice stored"
+        );
+        Ok(ids)
+    }
+
+    /// An invoice's date as `YYYY-MM-DD`, read with its vendor's day order.
+    fn invoice_date_iso(&self, invoice: &InvoiceData) -> Option<String> {
+        let order = self.date_hints.order_for(invoice.vendor.as_deref());
+        dates::parse_invoice_date(invoice.invoice_date.as_deref()?, order).map(|d| d.to_string())
+    }
+
+    /// Re-read every stored invoice date with the current hints, e.g. after
+    /// a vendor's order was configured. Returns how many changed.
+    pub fn normalize_invoice_dates(&self) -> SqliteResult<usize> {
+        let rows: Vec<(i64, Option<String>, Option<String>)> = self
+            .conn
+            .prepare("SELECT id, vendor, invoice_date, invoice_date_iso FROM invoices")?
+            .query_map([], |row| {
+                let vendor: Option<String> = row.get(1)?;
+                let raw: Option<String> = row.get(2)?;
+                let order = self.date_hints.order_for(vendor.as_deref());
+                let iso = raw
+                    .as_deref()
+                    .and_then(|raw| dates::parse_invoice_date(raw, order))
+                    .map(|d| d.to_string());
+                Ok((row.get(0)?, iso, row.get(3)?))
+            })?
+            .collect::<SqliteResult<_>>()?;
+        let tx = self.conn.unchecked_transaction()?;
+        let mut changed = 0;
+        for (id, iso, stored) in rows {
+            if iso != stored {
+                tx.execute(
+                    "UPDATE invoices SET invoice_date_iso = ?1 WHERE id = ?2",
+                    params![iso, id],
+                )?;
+                changed += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// Helper: insert one invoice header with its line items, packing rows
+    /// and totals.
+    fn insert_invoice_rows(
+        conn: &Connection,
+        attachment_id: i64,
+        backend: &str,
+        model: Option<&str>,
+        pages: Option<PageRange>,
+        invoice: &InvoiceData,
+        invoice_date_iso: Option<String>,
+    ) -> SqliteResult<i64> {
+        conn.execute(
+            "INSERT INTO invoices
+                (attachment_id, backend, model, vendor, buyer, invoice_no, invoice_date, currency,
+                 total_amount_minor, total_pieces, ship_from, ship_to, shipping_method,
+                 first_page, last_page, invoice_date_iso)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            params![
+                attachment_id,
+                backend,
+                model,
+                invoice.vendor,
+                invoice.buyer,
+                invoice.invoice_no,
+                invoice.invoice_date,
+                invoice.currency,
+                invoice.total_amount.map(|a| a.minor),
+                invoice.total_pieces,
+                invoice.ship_from,
+                invoice.ship_to,
+                invoice.shipping_method,
+                pages.map(|p| p.first),
+                pages.map(|p| p.last),
+                invoice_date_iso,
+            ],
+        )?;
+        let invoice_id = conn.last_insert_rowid();
+
+        for (pos, item) in invoice.line_items.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO invoice_line_items
+                    (invoice_id, position, description, qty, unit_price_minor, amount_minor)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    invoice_id,
+                    pos as i64,
+                    item.description,
+                    item.qty,
+                    item.unit_price.minor,
+                    item.amount.minor,
+                ],
+            )?;
+        }
+
+        for (pos, item) in invoice.packing_items.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO packing_items
+                    (invoice_id, position, carton, description, ctns, qty,
+                     net_wt_per_ctn, gross_wt_per_ctn, measurement)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    invoice_id,
+                    pos as i64,
+                    item.carton,
+                    item.description,
+                    item.ctns,
+                    item.qty,
+                    item.net_wt_per_ctn,
+                    item.gross_wt_per_ctn,
+                    item.measurement,
+                ],
+            )?;
+        }
+
+        if let Some(ref totals) = invoice.packing_totals {
+            conn.execute(
+                "INSERT INTO packing_totals
+                    (invoice_id, total_cartons, total_qty, total_net_wt, total_gross_wt)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    invoice_id,
+                    totals.total_cartons,
+                    totals.total_qty,
+                    totals.total_net_wt,
+                    totals.total_gross_wt,
+                ],
+            )?;
+        }
+
+        Ok(invoice_id)
+    }
+
+    /// Helper: remove an attachment's invoices and their children.
+    fn delete_invoices(conn: &Connection, attachment_id: i64) -> SqliteResult<()> {
+        let previous: Vec<i64> = conn
+            .prepare("SELECT id FROM invoices WHERE attachment_id = ?1")?
+            .query_map(params![attachment_id], |row| row.get(0))?
+            .collect::<SqliteResult<_>>()?;
+        for old_id in previous {
+            Self::delete_invoice_children(conn, old_id)?;
+            conn.execute("DELETE FROM invoices WHERE id = ?1", params![old_id])?;
+        }
+        Ok(())
+    }
+
+    /// Helper: remove line items, packing rows and totals belonging to an invoice.
+    fn delete_invoice_children(conn: &Connection, invoice_id: i64) -> SqliteResult<()> {
+        conn.execute(
+            "DELETE FROM invoice_line_items WHERE invoice_id = ?1",
+            params![invoice_id],
+        )?;
+        conn.execute(
+            "DELETE FROM packing_items WHERE invoice_id = ?1",
+            params![invoice_id],
+        )?;
+        conn.execute(
+            "DELETE FROM packing_totals WHERE invoice_id = ?1",
+            params![invoice_id],
+        )?;
+        Ok(())
+    }
+
+    /// Get the stored invoices extracted from a given attachment, in page order.
+    pub fn get_invoices_for_attachment(
+        &self,
+        attachment_id: i64,
+    ) -> SqliteResult<Vec<StoredInvoice>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, attachment_id, backend, model, created_at, vendor, buyer, invoice_no,
+                    invoice_date, currency, total_amount_minor, total_pieces, ship_from, ship_to,
+                    shipping_method, first_page, last_page, invoice_date_iso
+             FROM invoices
+             WHERE attachment_id = ?1
+             ORDER BY first_page, id",
+        )?;
+        let invoices = stmt
+            .query_map(params![attachment_id], Self::row_to_invoice)?
+            .collect::<SqliteResult<Vec<_>>>()?;
+        invoices
+            .into_iter()
+            .map(|inv| self.load_invoice_children(inv))
+            .collect()
+    }
+
+    /// List all stored invoices, most recent first.
+    pub fn list_invoices(&self) -> SqliteResult<Vec<StoredInvoice>> {
+        self.query_invoices(&InvoiceQuery::new())
+    }
+
+    /// Helper: map a row with the 18-column invoice projection to `StoredInvoice`
+    /// (line items, packing rows and totals are filled in separately).
+    fn row_to_invoice(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredInvoice> {
+        let currency: Option<String> = row.get(9)?;
+        let money = Currency::of(currency.as_deref());
+        Ok(StoredInvoice {
+            id: row.get(0)?,
+            attachment_id: row.get(1)?,
+            pages: match (row.get(15)?, row.get(16)?) {
+                (Some(first), Some(last)) => Some(PageRange { first, last }),
+                _ => None,
+            },
+            backend: row.get(2)?,
+            model: row.get(3)?,
+            created_at: row.get(4)?,
+            invoice_date_iso: row.get(17)?,
+            invoice: InvoiceData {
+                vendor: row.get(5)?,
+                buyer: row.get(6)?,
+                invoice_no: row.get(7)?,
+                invoice_date: row.get(8)?,
+                total_amount: row
+                    .get::<_, Option<i64>>(10)?
+                    .map(|minor| Money::new(minor, money)),
+                currency,
+                total_pieces: row.get(11)?,
+                ship_from: row.get(12)?,
+                ship_to: row.get(13)?,
+                shipping_method: row.get(14)?,
+                line_items: Vec::new(),
+                packing_items: Vec::new(),
+                packing_totals: None,
+            },
+        })
+    }
+
+    /// Helper: attach line items, packing rows and totals to an invoice header.
+    fn load_invoice_children(&self, mut stored: StoredInvoice) -> SqliteResult<StoredInvoice> {
+        let mut stmt = self.conn.prepare(
+            "SELECT description, qty, unit_price_minor, amount_minor
+             FROM invoice_line_items
+             WHERE invoice_id = ?1
+             ORDER BY position",
+        )?;
+        let currency = Currency::of(stored.invoice.currency.as_deref());
+        stored.invoice.line_items = stmt
+            .query_map(params![stored.id], |row| {
+                Ok(LineItem {
+                    description: row.get(0)?,
+                    qty: row.get(1)?,
+                    unit_price: Money::new(row.get(2)?, currency),
+                    amount: Money::new(row.get(3)?, currency),
+                })
+            })?
+            .collect::<SqliteResult<Vec<_>>>()?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT carton, description, ctns, qty, net_wt_per_ctn, gross_wt_per_ctn, measurement
+             FROM packing_items
+             WHERE invoice_id = ?1
+             ORDER BY position",
+        )?;
+        stored.invoice.packing_items = stmt
+            .query_map(params![stored.id], |row| {
+                Ok(PackingItem {
+                    carton: row.get(0)?,
+                    description: row.get(1)?,
+                    ctns: row.get(2)?,
+                    qty: row.get(3)?,
+                    net_wt_per_ctn: row.get(4)?,
+                    gross_wt_per_ctn: row.get(5)?,
+                    measurement: row.get(6)?,
+                })
+            })?
+            .collect::<SqliteResult<Vec<_>>>()?;
+
+        stored.invoice.packing_totals = self
+            .conn
+            .query_row(
+                "SELECT total_cartons, total_qty, total_net_wt, total_gross_wt
+                 FROM packing_totals
+                 WHERE invoice_id = ?1",
+                params![stored.id],
+                |row| {
+                    Ok(PackingTotals {
+                        total_cartons: row.get(0)?,
+                        total_qty: row.get(1)?,
+                        total_net_wt: row.get(2)?,
+                        total_gross_wt: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(stored)
+    }
+
+    /// Get the last recorded sync position for a user/query pair.
+    pub fn get_sync_state(&self, user: &str, query: &str) -> SqliteResult<Option<SyncState>> {
+        self.conn
+            .query_row(
+                "SELECT history_id, synced_at FROM sync_state WHERE user = ?1 AND query = ?2",
+                params![user, query],
+                |row| {
+                    Ok(SyncState {
+                        history_id: row.get::<_, i64>(0)? as u64,
+                        synced_at: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// Record the sync position reached for a user/query pair.
+    pub fn set_sync_state(&self, user: &str, query: &str, state: SyncState) -> SqliteResult<()> {
+        self.conn.execute(
+            "INSERT INTO sync_state (user, query, history_id, synced_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(user, query) DO UPDATE SET
+                history_id = excluded.history_id,
+                synced_at = excluded.synced_at,
+                updated_at = CURRENT_TIMESTAMP",
+            params![user, query, state.history_id as i64, state.synced_at],
+        )?;
+        info!(
+            user = %user,
+            query = %query,
+            history_id = state.history_id,
+            "Sync state stored"
+        );
+        Ok(())
+    }
+
+    /// Record that fetching a message failed, bumping its failure count.
+    pub fn record_fetch_failure(
+        &self,
+        user: &str,
+        query: &str,
+        message_id: &str,
+        error: &str,
+    ) -> SqliteResult<()> {
+        self.conn.execute(
+            "INSERT INTO fetch_failures (user, query, message_id, error)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(user, query, message_id) DO UPDATE SET
+                error = excluded.error,
+                attempts = attempts + 1,
+                failed_at = CURRENT_TIMESTAMP",
+            params![user, query, message_id, error],
+        )?;
+        info!(user = %user, query = %query, message_id = %message_id, "Fetch failure recorded");
+        Ok(())
+    }
+
+    /// Forget a previously recorded fetch failure (e.g. after a successful retry).
+    pub fn clear_fetch_failure(
+        &self,
+        user: &str,
+        query: &str,
+        message_id: &str,
+    ) -> SqliteResult<()> {
+        self.conn.execute(
+            "DELETE FROM fetch_failures WHERE user = ?1 AND query = ?2 AND message_id = ?3",
+            params![user, query, message_id],
+        )?;
+        Ok(())
+    }
+
+    /// Gmail message ids whose fetch failed for a user's query in fewer than
+    /// `max_failed_syncs` syncs, oldest first. Ids that reached the limit
+    /// stay recorded but are no longer retried.
+    pub fn get_failed_message_ids(
+        &self,
+        user: &str,
+        query: &str,
+        max_failed_syncs: u32,
+    ) -> SqliteResult<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT message_id FROM fetch_failures
+             WHERE user = ?1 AND query = ?2 AND attempts < ?3
+             ORDER BY failed_at, message_id",
+        )?;
+        let ids = stmt.query_map(params![user, query, max_failed_syncs], |row| row.get(0))?;
+        ids.collect()
+    }
+
+    /// Tag stored messages (by Gmail id) with a profile label. Ids that were
+    /// not stored are ignored. Returns the number of newly labelled messages.
+    pub fn label_messages(
+        &self,
+        user: &str,
+        message_ids: &[String],
+        label: &str,
+    ) -> SqliteResult<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut labelled = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO message_labels (message_uid, label)
+                 SELECT uid, ?3 FROM messages WHERE user = ?1 AND message_id = ?2",
+            )?;
+            for id in message_ids {
+                labelled += stmt.execute(params![user, id, label])?;
+            }
+        }
+        tx.commit()?;
+        Ok(labelled)
+    }
+
+    /// Number of attachments per content type ("text", "scanned", ...).
+    pub fn get_content_type_counts(&self) -> SqliteResult<Vec<(String, usize)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT content_type, COUNT(*) FROM attachments
+             GROUP BY content_type ORDER BY content_type",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Number of stored invoices per extraction backend.
+    pub fn get_invoice_counts(&self) -> SqliteResult<Vec<(String, usize)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT backend, COUNT(*) FROM invoices GROUP BY backend ORDER BY backend")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Full-text search over message subjects, bodies and senders and over
+    /// extracted attachment text, best matches first. `highlight` wraps the
+    /// matched terms in snippets.
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        highlight: (&str, &str),
+    ) -> SqliteResult<Vec<SearchHit>> {
+        let match_expr = fts_query(&query.text);
+        if match_expr.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut stmt = self.conn.prepare(
+            "SELECT m.uid, NULL, NULL, m.date, m.from_addr, m.subject,
+                    snippet(messages_fts, -1, ?2, ?3, '…', 16), bm25(messages_fts) AS rank
+             FROM messages_fts JOIN messages m ON m.uid = messages_fts.uid
+             WHERE messages_fts MATCH ?1
+               AND (?4 IS NULL OR m.from_addr LIKE '%' || ?4 || '%')
+               AND ?5 IS NULL
+               AND (?6 IS NULL OR substr(m.date_iso, 1, 10) >= ?6)
+               AND (?7 IS NULL OR substr(m.date_iso, 1, 10) <= ?7)
+             UNION ALL
+             SELECT m.uid, a.id, a.filename, m.date, m.from_addr, m.subject,
+                    snippet(attachments_fts, 0, ?2, ?3, '…', 16), bm25(attachments_fts)
+             FROM attachments_fts
+             JOIN attachments a ON a.id = attachments_fts.rowid
+             JOIN messages m ON m.uid = a.message_uid
+             WHERE attachments_fts MATCH ?1
+               AND (?4 IS NULL OR m.from_addr LIKE '%' || ?4 || '%')
+               AND (?5 IS NULL OR a.content_type = ?5)
+               AND (?6 IS NULL OR substr(m.date_iso, 1, 10) >= ?6)
+               AND (?7 IS NULL OR substr(m.date_iso, 1, 10) <= ?7)
+             ORDER BY rank
+             LIMIT ?8",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                match_expr,
+                highlight.0,
+                highlight.1,
+                query.sender,
+                query.content_type,
+                query.since.map(|d| d.to_string()),
+                query.until.map(|d| d.to_string()),
+                query.limit as i64,
+            ],
+            |row| {
+                Ok(SearchHit {
+                    message_uid: row.get(0)?,
+                    attachment_id: row.get(1)?,
+                    filename: row.get(2)?,
+                    date: row.get(3)?,
+                    from_addr: row.get(4)?,
+                    subject: row.get(5)?,
+                    snippet: row.get(6)?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
     /// Get count of messages by processing status
     pub fn get_counts(&self) -> SqliteResult<(usize, usize, usize, usize)> {
         let total_messages: usize =
@@ -485,6 +1377,79 @@ impl MessageStore {
     }
 }
 
+/// Insert or update a message row. `attachment_count`, when known, is how
+/// many PDFs Gmail listed for it.
+fn write_message(
+    conn: &Connection,
+    msg: &StoredMessage,
+    attachment_count: Option<usize>,
+) -> SqliteResult<()> {
+    conn.execute(
+        "INSERT INTO messages 
+            (uid, message_id, user, date, from_addr, subject, plain_text, html, has_attachments, is_processed, date_iso, attachment_count)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+         ON CONFLICT(uid) DO UPDATE SET
+            from_addr = excluded.from_addr,
+            subject = excluded.subject,
+            plain_text = excluded.plain_text,
+            html = excluded.html,
+            has_attachments = excluded.has_attachments,
+            attachment_count = COALESCE(excluded.attachment_count, attachment_count)",
+        params![
+            msg.uid,
+            msg.message_id,
+            msg.user,
+            msg.date,
+            msg.from_addr,
+            msg.subject,
+            msg.plain_text,
+            msg.html,
+            msg.has_attachments,
+            msg.is_processed,
+            dates::message_date_iso(&msg.date),
+            attachment_count,
+        ],
+    )?;
+    info!(uid = %msg.uid, "Message stored");
+    Ok(())
+}
+
+/// Insert an attachment row and its blob, inside the caller's transaction.
+fn write_attachment(conn: &Connection, attachment: &StoredAttachment) -> SqliteResult<Option<i64>> {
+    let inserted = conn.execute(
+        "INSERT INTO attachments 
+            (message_uid, filename, attachment_id, sha256, is_processed, content_type, extracted_text)
+         VALUES (?1, ?2, ?3, ?4, ?5, COALESCE(?6, 'unknown'), ?7)
+         ON CONFLICT(message_uid, attachment_id) DO NOTHING",
+        params![
+            attachment.message_uid,
+            attachment.filename,
+            attachment.attachment_id,
+            attachment.pdf_data.sha256,
+            attachment.is_processed,
+            attachment.content_type,
+            attachment.extracted_text,
+        ],
+    )?;
+    if inserted == 0 {
+        info!(filename = %attachment.filename, "Attachment already stored — skipped");
+        return Ok(None);
+    }
+    let id = conn.last_insert_rowid();
+    // A blob that was never loaded came from the store in the first place
+    if let Some(data) = attachment.pdf_data.data.get() {
+        let new_blob = conn.execute(
+            "INSERT OR IGNORE INTO blobs (sha256, data, size) VALUES (?1, ?2, ?3)",
+            params![attachment.pdf_data.sha256, data, data.len()],
+        )?;
+        if new_blob == 0 {
+            info!(sha256 = %attachment.pdf_data.sha256, "Same PDF already stored — bytes shared");
+        }
+    }
+    info!(attachment_id = id, filename = %attachment.filename, "Attachment stored");
+    Ok(Some(id))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -498,4 +1463,722 @@ mod tests {
         assert_eq!(uid1, uid2); // Same inputs = same hash
         assert_ne!(uid1, uid3); // Different inputs = different hash
     }
+
+    #[test]
+    fn test_sync_state_upsert() {
+        let db = MessageStore::new(":memory:").unwrap();
+        assert!(db.get_sync_state("me", "from:a").unwrap().is_none());
+
+        let first = SyncState {
+            history_id: 100,
+            synced_at: 1_700_000_000,
+        };
+        db.set_sync_state("me", "from:a", first).unwrap();
+        let second = SyncState {
+            history_id: 250,
+            synced_at: 1_700_086_400,
+        };
+        db.set_sync_state("me", "from:a", second).unwrap();
+
+        let state = db.get_sync_state("me", "from:a").unwrap().unwrap();
+        assert_eq!(state.history_id, 250);
+        assert_eq!(state.synced_at, 1_700_086_400);
+        assert!(db.get_sync_state("me", "from:b").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_attachment_insert_is_idempotent() {
+        let db = MessageStore::new(":memory:").unwrap();
+        let att_id = insert_sample_attachment(&db);
+        let uid = MessageStore::generate_uid("msg123", "2025-01-01", "user@example.com");
+
+        assert!(db.has_message("user@example.com", "msg123").unwrap());
+        assert!(!db.has_message("other@example.com", "msg123").unwrap());
+        assert!(db.has_attachment(&uid, "att-1").unwrap());
+        assert!(!db.has_attachment(&uid, "att-2").unwrap());
+
+        let att = db.get_attachment_by_id(att_id).unwrap().unwrap();
+        assert_eq!(att.content_type.as_deref(), Some("unknown"));
+        // Gmail listed two PDFs: the message is refetched until both are in
+        let msg = db.get_message_by_uid(&uid).unwrap().unwrap();
+        assert_eq!(db.store_message(&msg, 2, &[att]).unwrap(), [None]);
+        assert_eq!(db.get_attachments_for_message(&uid).unwrap().len(), 1);
+        assert!(!db.has_message("user@example.com", "msg123").unwrap());
+        let second = StoredAttachment {
+            id: None,
+            attachment_id: Some("att-2".to_string()),
+            pdf_data: Blob::new(b"%PDF-1.5".to_vec()),
+            ..db.get_attachment_by_id(att_id).unwrap().unwrap()
+        };
+        assert!(db.store_message(&msg, 2, &[second]).unwrap()[0].is_some());
+        assert!(db.has_message("user@example.com", "msg123").unwrap());
+    }
+
+    #[test]
+    fn test_attachment_metadata_finds_resent_file() {
+        let db = MessageStore::new(":memory:").unwrap();
+        let first = insert_sample_attachment(&db);
+        let mut resent = db.get_attachment_by_id(first).unwrap().unwrap();
+        resent.attachment_id = Some("att-2".to_string());
+        resent.filename = "invoice (1).pdf".to_string();
+        let msg = db.get_message_by_uid(&resent.message_uid).unwrap().unwrap();
+        let second = db.store_message(&msg, 2, &[resent]).unwrap()[0].unwrap();
+
+        assert_eq!(db.get_attachment_metadata(first).unwrap(), None);
+        let meta = PdfMetadata {
+            page_count: Some(2),
+            version: Some("1.4".to_string()),
+            producer: Some("iText".to_string()),
+            byte_size: 8,
+            sha256: sha256_hex(b"%PDF-1.4"),
+            ..Default::default()
+        };
+        db.set_attachment_metadata(first, &meta).unwrap();
+        db.set_attachment_metadata(second, &meta).unwrap();
+
+        assert_eq!(
+            db.get_attachment_metadata(second).unwrap(),
+            Some(meta.clone())
+        );
+        assert_eq!(
+            db.find_attachment_by_sha256(&meta.sha256, second).unwrap(),
+            Some(first)
+        );
+        assert_eq!(db.get_duplicate_count().unwrap(), 1);
+        assert_eq!(
+            db.get_producer_counts().unwrap(),
+            [("iText".to_string(), 2)]
+        );
+
+        // An encrypted PDF that an older build stored as `error` is retried
+        db.set_attachment_extraction(first, "error", Some("decryption failed"))
+            .unwrap();
+        assert!(db.get_encrypted_attachments().unwrap().is_empty());
+        let encrypted = PdfMetadata {
+            encrypted: true,
+            ..meta
+        };
+        db.set_attachment_metadata(first, &encrypted).unwrap();
+        let retried = db.get_encrypted_attachments().unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].id, Some(first));
+    }
+
+    #[test]
+    fn test_fetch_failures() {
+        let db = MessageStore::new(":memory:").unwrap();
+        db.record_fetch_failure("me", "from:a", "m1", "503")
+            .unwrap();
+        db.record_fetch_failure("me", "from:a", "m1", "429")
+            .unwrap();
+        db.record_fetch_failure("me", "from:a", "m2", "500")
+            .unwrap();
+        db.record_fetch_failure("me", "from:b", "m4", "500")
+            .unwrap();
+        db.record_fetch_failure("you", "from:a", "m3", "500")
+            .unwrap();
+
+        assert_eq!(
+            db.get_failed_message_ids("me", "from:a", 5).unwrap(),
+            vec!["m1", "m2"]
+        );
+        assert_eq!(
+            db.get_failed_message_ids("me", "from:b", 5).unwrap(),
+            vec!["m4"]
+        );
+
+        // m1 has failed twice: a limit of two attempts gives up on it
+        assert_eq!(
+            db.get_failed_message_ids("me", "from:a", 2).unwrap(),
+            vec!["m2"]
+        );
+
+        db.clear_fetch_failure("me", "from:a", "m2").unwrap();
+        assert_eq!(
+            db.get_failed_message_ids("me", "from:a", 5).unwrap(),
+            vec!["m1"]
+        );
+    }
+
+    #[test]
+    fn test_label_messages_filters_text_attachments() {
+        let db = MessageStore::new(":memory:").unwrap();
+        let att_id = insert_sample_attachment(&db);
+        db.set_attachment_extraction(att_id, "text", Some("INVOICE"))
+            .unwrap();
+
+        assert_eq!(
+            db.get_text_attachments_for_label(None, false)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(
+            db.get_text_attachments_for_label(Some("maxsoft"), false)
+                .unwrap()
+                .is_empty()
+        );
+
+        let ids = vec!["msg123".to_string(), "missing".to_string()];
+        assert_eq!(
+            db.label_messages("user@example.com", &ids, "maxsoft")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            db.label_messages("user@example.com", &ids, "maxsoft")
+                .unwrap(),
+            0
+        );
+
+        assert!(
+            db.get_text_attachments_for_label(None, false)
+                .unwrap()
+                .is_empty()
+        );
+        let labelled = db
+            .get_text_attachments_for_label(Some("maxsoft"), false)
+            .unwrap();
+        assert_eq!(labelled[0].id, Some(att_id));
+
+        // Extracted once, then only when asked to again
+        db.insert_invoice(att_id, "ollama", Some("qwen"), &sample_invoice())
+            .unwrap();
+        assert!(
+            db.get_text_attachments_for_label(Some("maxsoft"), false)
+                .unwrap()
+                .is_empty()
+        );
+        let again = db
+            .get_text_attachments_for_label(Some("maxsoft"), true)
+            .unwrap();
+        assert_eq!(again[0].id, Some(att_id));
+    }
+
+    #[test]
+    fn test_ocr_pages_reclassify_attachment() {
+        let db = MessageStore::new(":memory:").unwrap();
+        let att_id = insert_sample_attachment(&db);
+        db.set_attachment_extraction(att_id, "scanned", None)
+            .unwrap();
+        assert!(db.get_text_attachments().unwrap().is_empty());
+
+        // Vision extraction skips scans that already have an invoice
+        assert_eq!(
+            db.get_scanned_attachments_for_label(None, false)
+                .unwrap()
+                .len(),
+            1
+        );
+        db.insert_invoice(att_id, "ollama", Some("llava"), &sample_invoice())
+            .unwrap();
+        assert!(
+            db.get_scanned_attachments_for_label(None, false)
+                .unwrap()
+                .is_empty()
+        );
+
+        // Mixed: extracted from its native pages now, OCR'd as well
+        db.set_attachment_extraction(att_id, "mixed", Some("INVOICE"))
+            .unwrap();
+        assert_eq!(db.get_text_attachments().unwrap().len(), 1);
+        assert_eq!(db.get_scanned_attachments().unwrap().len(), 1);
+
+        let pages = vec![
+            OcrPage {
+                page: 1,
+                text: "INVOICE".to_string(),
+                confidence: 0.9,
+            },
+            OcrPage {
+                page: 2,
+                text: "TOTAL 10".to_string(),
+                confidence: 0.5,
+            },
+        ];
+        db.set_attachment_ocr(att_id, &pages, "INVOICE\n\nTOTAL 10")
+            .unwrap();
+        // The native-pages-only invoice gives way to one from the full text
+        assert!(db.get_invoices_for_attachment(att_id).unwrap().is_empty());
+        assert_eq!(
+            db.get_text_attachments_for_label(None, false)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(db.get_scanned_attachments().unwrap().is_empty());
+        let text = db.get_text_attachments().unwrap();
+        assert_eq!(text[0].content_type.as_deref(), Some("ocr"));
+        assert_eq!(
+            text[0].extracted_text.as_deref(),
+            Some("INVOICE\n\nTOTAL 10")
+        );
+        assert_eq!(
+            db.get_ocr_confidence(att_id).unwrap(),
+            vec![(1, 0.9), (2, 0.5)]
+        );
+    }
+
+    #[test]
+    fn test_attachment_pages_roundtrip() {
+        let db = MessageStore::new(":memory:").unwrap();
+        let att_id = insert_sample_attachment(&db);
+        assert!(db.get_attachment_pages(att_id).unwrap().is_empty());
+
+        let pages = vec![
+            PageContent {
+                page: 1,
+                kind: PageKind::Text,
+                text: Some("INVOICE".to_string()),
+            },
+            PageContent {
+                page: 2,
+                kind: PageKind::Scanned,
+                text: None,
+            },
+        ];
+        db.set_attachment_pages(att_id, &pages).unwrap();
+        db.set_attachment_pages(att_id, &pages).unwrap();
+
+        let stored = db.get_attachment_pages(att_id).unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].text.as_deref(), Some("INVOICE"));
+        assert_eq!(stored[1].kind, PageKind::Scanned);
+    }
+
+    fn insert_sample_attachment(db: &MessageStore) -> i64 {
+        let uid = MessageStore::generate_uid("msg123", "2025-01-01", "user@example.com");
+        let msg = StoredMessage {
+            uid: uid.clone(),
+            message_id: "msg123".to_string(),
+            user: "user@example.com".to_string(),
+            date: "2025-01-01".to_string(),
+            from_addr: None,
+            subject: None,
+            plain_text: None,
+            html: None,
+            has_attachments: true,
+            is_processed: false,
+        };
+        let attachment = StoredAttachment {
+            id: None,
+            message_uid: uid,
+            filename: "invoice.pdf".to_string(),
+            attachment_id: Some("att-1".to_string()),
+            pdf_data: Blob::new(b"%PDF-1.4".to_vec()),
+            is_processed: false,
+            content_type: None,
+            extracted_text: None,
+        };
+        db.store_message(&msg, 1, &[attachment]).unwrap()[0].unwrap()
+    }
+
+    fn usd(minor: i64) -> Money {
+        Money::new(minor, Currency::of(Some("USD")))
+    }
+
+    fn sample_invoice() -> InvoiceData {
+        InvoiceData {
+            vendor: Some("SOFT SOURCE PTE LTD".to_string()),
+            buyer: Some("MAXSOFT CO., LTD".to_string()),
+            invoice_no: Some("SS-2026-014".to_string()),
+            invoice_date: Some("February 16, 2026".to_string()),
+            currency: Some("USD".to_string()),
+            total_amount: Some(usd(254_000)),
+            total_pieces: Some(100),
+            ship_from: Some("SINGAPORE".to_string()),
+            ship_to: Some("BANGKOK".to_string()),
+            shipping_method: Some("AIR".to_string()),
+            line_items: vec![LineItem {
+                description: "ELDEN RING PS5".to_string(),
+                qty: 100,
+                unit_price: usd(2540),
+                amount: usd(254_000),
+            }],
+            packing_items: vec![PackingItem {
+                carton: "1".to_string(),
+                description: "ELDEN RING PS5".to_string(),
+                ctns: 1,
+                qty: 100,
+                net_wt_per_ctn: 9.5,
+                gross_wt_per_ctn: 10.2,
+                measurement: "59 X 25 X 20 CM".to_string(),
+            }],
+            packing_totals: Some(PackingTotals {
+                total_cartons: 1,
+                total_qty: 100,
+                total_net_wt: 9.5,
+                total_gross_wt: 10.2,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_invoice_roundtrip_replaces_previous() {
+        let db = MessageStore::new(":memory:").unwrap();
+        let att_id = insert_sample_attachment(&db);
+        let mut invoice = sample_invoice();
+
+        let first = db
+            .insert_invoice(att_id, "ollama", Some("qwen3:8b"), &invoice)
+            .unwrap();
+        invoice.line_items.clear();
+        invoice.invoice_no = Some("SS-2026-015".to_string());
+        let second = db
+            .insert_invoice(att_id, "heuristics", None, &invoice)
+            .unwrap();
+        assert_ne!(first, second);
+
+        let all = db.list_invoices().unwrap();
+        assert_eq!(all.len(), 1);
+
+        let stored = db.get_invoices_for_attachment(att_id).unwrap();
+        assert_eq!(stored.len(), 1);
+        let stored = stored.into_iter().next().unwrap();
+        assert_eq!(stored.backend, "heuristics");
+        assert_eq!(stored.model, None);
+        assert_eq!(stored.invoice.invoice_no.as_deref(), Some("SS-2026-015"));
+        assert!(stored.invoice.line_items.is_empty());
+        assert_eq!(stored.invoice.packing_items.len(), 1);
+        assert_eq!(stored.invoice.packing_totals.unwrap().total_qty, 100);
+
+        assert!(
+            db.get_invoices_for_attachment(att_id + 1)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_search_messages_and_attachment_text() {
+        let db = MessageStore::new(":memory:").unwrap();
+        let uid = "m-elden".to_string();
+        let mut msg = StoredMessage {
+            uid: uid.clone(),
+            message_id: "m-elden".to_string(),
+            user: "user@example.com".to_string(),
+            date: "Mon, 16 Feb 2026 09:30:05 +0800 (SGT)".to_string(),
+            from_addr: Some("Billing <billing@softsource.sg>".to_string()),
+            subject: None,
+            plain_text: Some("Please find the invoice attached.".to_string()),
+            html: None,
+            has_attachments: true,
+            is_processed: false,
+        };
+        db.store_message(&msg, 0, &[]).unwrap();
+        msg.subject = Some("Invoice S-62779/02/26".to_string());
+        let attachment = StoredAttachment {
+            id: None,
+            message_uid: uid.clone(),
+            filename: "S-62779.pdf".to_string(),
+            attachment_id: Some("att-1".to_string()),
+            pdf_data: Blob::new(b"%PDF-1.4".to_vec()),
+            is_processed: false,
+            content_type: None,
+            extracted_text: None,
+        };
+        let att_id = db.store_message(&msg, 1, &[attachment]).unwrap()[0].unwrap();
+        db.set_attachment_extraction(att_id, "text", Some("2 x ELDEN RING PS5 @ 59.90"))
+            .unwrap();
+
+        let mut query = SearchQuery {
+            text: "elden ps5".to_string(),
+            limit: 10,
+            ..Default::default()
+        };
+        let hits = db.search(&query, ("[", "]")).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(
+            (hits[0].message_uid.as_str(), hits[0].attachment_id),
+            (uid.as_str(), Some(att_id))
+        );
+        assert_eq!(hits[0].snippet, "2 x [ELDEN] RING [PS5] @ 59.90");
+
+        // Invoice numbers match despite their punctuation; the subject edit
+        // above reached the index
+        query.text = "S-62779/02/26".to_string();
+        assert_eq!(db.search(&query, ("", "")).unwrap()[0].attachment_id, None);
+
+        query.text = "ELDEN".to_string();
+        query.sender = Some("SOFTSOURCE".to_string());
+        query.since = Some(Date::from_calendar_date(2026, time::Month::February, 16).unwrap());
+        assert_eq!(db.search(&query, ("", "")).unwrap().len(), 1);
+        query.content_type = Some("ocr".to_string());
+        assert!(db.search(&query, ("", "")).unwrap().is_empty());
+        query.content_type = None;
+        query.since = Some(Date::from_calendar_date(2026, time::Month::February, 17).unwrap());
+        assert!(db.search(&query, ("", "")).unwrap().is_empty());
+
+        // Re-extraction replaces the indexed text
+        query.since = None;
+        db.set_attachment_extraction(att_id, "text", Some("no games here"))
+            .unwrap();
+        assert!(db.search(&query, ("", "")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invoice_query_filters_sorts_and_pages() {
+        let db = MessageStore::new(":memory:").unwrap();
+        let att_id = insert_sample_attachment(&db);
+        let mut acme = sample_invoice();
+        acme.vendor = Some("ACME 100% LTD".to_string());
+        acme.invoice_no = Some("A-9".to_string());
+        acme.invoice_date = Some("03/02/2026".to_string());
+        acme.currency = Some("JPY".to_string());
+        let jpy = |minor| Money::new(minor, Currency::of(Some("JPY")));
+        acme.total_amount = Some(jpy(1500));
+        acme.line_items[0] = LineItem {
+            description: "USB-C CABLE".to_string(),
+            qty: 100,
+            unit_price: jpy(15),
+            amount: jpy(1500),
+        };
+        let mut undated = sample_invoice();
+        undated.invoice_no = Some("SS-2026-015".to_string());
+        undated.invoice_date = None;
+        undated.total_amount = None;
+        let page = |n| Some(PageRange { first: n, last: n });
+        db.insert_invoices(
+            att_id,
+            "heuristics",
+            None,
+            &[
+                (page(1), sample_invoice()),
+                (page(2), acme),
+                (page(3), undated),
+            ],
+        )
+        .unwrap();
+        let numbers = |query: &InvoiceQuery| -> Vec<String> {
+            db.query_invoices(query)
+                .unwrap()
+                .into_iter()
+                .map(|s| s.invoice.invoice_no.unwrap())
+                .collect()
+        };
+
+        assert_eq!(numbers(&InvoiceQuery::new().vendor("soft source")).len(), 2);
+        // LIKE wildcards in the filter are literal
+        assert_eq!(numbers(&InvoiceQuery::new().vendor("100%")), ["A-9"]);
+        assert_eq!(numbers(&InvoiceQuery::new().vendor("1_0")).len(), 0);
+        assert_eq!(numbers(&InvoiceQuery::new().currency("jpy")), ["A-9"]);
+        assert_eq!(numbers(&InvoiceQuery::new().item("elden ring")).len(), 2);
+        // Amounts compare at face value: 1500 JPY is stored as 1500, 2540.00 USD as 254000
+        assert_eq!(
+            numbers(&InvoiceQuery::new().min_amount("1000").max_amount("2000")),
+            ["A-9"]
+        );
+        assert_eq!(
+            numbers(&InvoiceQuery::new().min_amount("2540.00")),
+            ["SS-2026-014"]
+        );
+        // Bounds are read in yen for yen invoices, so 1499.6 rounds to 1500
+        assert_eq!(numbers(&InvoiceQuery::new().max_amount("1499.6")), ["A-9"]);
+        let yen = InvoiceQuery::new().currency("JPY");
+        assert_eq!(numbers(&yen.clone().min_amount("1000")), ["A-9"]);
+        assert_eq!(numbers(&yen.clone().min_amount("1500.4")), ["A-9"]);
+        assert!(numbers(&yen.clone().min_amount("1501")).is_empty());
+        assert!(numbers(&yen.min_amount("lots")).is_empty());
+        assert_eq!(
+            numbers(&InvoiceQuery::new().currency("usd").max_amount("2540")),
+            ["SS-2026-014"]
+        );
+
+        // Dates compare by their normalized value, whatever the printed form
+        let march = Date::from_calendar_date(2026, time::Month::March, 1).unwrap();
+        assert!(numbers(&InvoiceQuery::new().since(march)).is_empty());
+        let by_date = InvoiceQuery::new().sort(InvoiceSort::Date);
+        assert_eq!(numbers(&by_date), ["A-9", "SS-2026-014", "SS-2026-015"]);
+
+        // ACME writes month first: 03/02/2026 is 2 March, not 3 February
+        let db = db.with_date_hints(DateHints {
+            vendors: vec![crate::dates::VendorDayOrder {
+                vendor: "acme".to_string(),
+                order: crate::dates::DayOrder::MonthFirst,
+            }],
+            ..Default::default()
+        });
+        assert_eq!(db.normalize_invoice_dates().unwrap(), 1);
+        assert_eq!(db.normalize_invoice_dates().unwrap(), 0);
+        let numbers = |query: &InvoiceQuery| -> Vec<String> {
+            db.query_invoices(query)
+                .unwrap()
+                .into_iter()
+                .map(|s| s.invoice.invoice_no.unwrap())
+                .collect()
+        };
+        assert_eq!(numbers(&InvoiceQuery::new().since(march)), ["A-9"]);
+        assert_eq!(numbers(&InvoiceQuery::new().until(march)), ["SS-2026-014"]);
+
+        let by_amount = InvoiceQuery::new().sort(InvoiceSort::Amount);
+        assert_eq!(numbers(&by_amount), ["A-9", "SS-2026-014", "SS-2026-015"]);
+        assert_eq!(
+            numbers(&by_amount.clone().descending()),
+            ["SS-2026-014", "A-9", "SS-2026-015"]
+        );
+        let page_two = by_amount.limit(2).offset(2);
+        assert_eq!(numbers(&page_two), ["SS-2026-015"]);
+        assert_eq!(db.count_invoices(&page_two).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_bundled_documents_migrate_and_replace() {
+        let path =
+            std::env::temp_dir().join(format!("invoice_search_docs_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            // Schema from before page ranges: one invoice per attachment
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE invoices (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    attachment_id INTEGER NOT NULL UNIQUE,
+                    backend TEXT NOT NULL, model TEXT, vendor TEXT, buyer TEXT,
+                    invoice_no TEXT, invoice_date TEXT, currency TEXT, total_amount REAL,
+                    total_pieces INTEGER, ship_from TEXT, ship_to TEXT, shipping_method TEXT,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                 );
+                 CREATE TABLE invoice_line_items (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    invoice_id INTEGER NOT NULL, position INTEGER NOT NULL,
+                    description TEXT NOT NULL, qty INTEGER NOT NULL,
+                    unit_price REAL NOT NULL, amount REAL NOT NULL
+                 );
+                 INSERT INTO invoices (id, attachment_id, backend, invoice_no, currency, total_amount)
+                 VALUES (7, 1, 'ollama', 'OLD-1', 'JPY', 1499.6);
+                 INSERT INTO invoice_line_items
+                    (invoice_id, position, description, qty, unit_price, amount)
+                 VALUES (7, 0, 'CABLE', 3, 499.87, 1499.6);",
+            )
+            .unwrap();
+        }
+
+        let db = MessageStore::new(&path).unwrap();
+        let old = db.get_invoices_for_attachment(1).unwrap();
+        assert_eq!((old[0].id, old[0].pages), (7, None));
+        // REAL amounts become whole minor units of the invoice's currency
+        let jpy = |minor| Some(Money::new(minor, Currency::of(Some("JPY"))));
+        assert_eq!(old[0].invoice.total_amount, jpy(1500));
+        let item = &old[0].invoice.line_items[0];
+        assert_eq!(
+            (Some(item.unit_price), Some(item.amount)),
+            (jpy(500), jpy(1500))
+        );
+
+        let att_id = insert_sample_attachment(&db);
+        let mut second = sample_invoice();
+        second.invoice_no = Some("SS-2026-016".to_string());
+        let ranges = [
+            PageRange { first: 3, last: 4 },
+            PageRange { first: 1, last: 2 },
+        ];
+        db.insert_invoices(
+            att_id,
+            "heuristics",
+            None,
+            &[
+                (Some(ranges[0]), second),
+                (Some(ranges[1]), sample_invoice()),
+            ],
+        )
+        .unwrap();
+
+        let stored = db.get_invoices_for_attachment(att_id).unwrap();
+        let pages: Vec<_> = stored.iter().map(|s| s.pages.unwrap()).collect();
+        assert_eq!(pages, [ranges[1], ranges[0]]);
+        assert_eq!(stored[1].invoice.invoice_no.as_deref(), Some("SS-2026-016"));
+        assert_eq!(stored[1].invoice.line_items.len(), 1);
+
+        // Re-extraction as a single document replaces both
+        db.insert_invoice(att_id, "ollama", None, &sample_invoice())
+            .unwrap();
+        assert_eq!(db.get_invoices_for_attachment(att_id).unwrap().len(), 1);
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_inline_pdfs_move_to_blob_store() {
+        let path =
+            std::env::temp_dir().join(format!("invoice_search_blobs_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            // Schema from before the blob store: bytes inline, one copy each
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE attachments (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    message_uid TEXT NOT NULL,
+                    filename TEXT NOT NULL,
+                    attachment_id TEXT,
+                    pdf_data BLOB NOT NULL,
+                    is_processed BOOLEAN NOT NULL DEFAULT 0,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                 );
+                 INSERT INTO attachments (message_uid, filename, attachment_id, pdf_data)
+                 VALUES ('m1', 'a.pdf', 'x', X'255044462D31'),
+                        ('m2', 'a (1).pdf', 'y', X'255044462D31'),
+                        ('m3', 'b.pdf', 'z', X'255044462D32');",
+            )
+            .unwrap();
+        }
+
+        let db = MessageStore::new(&path).unwrap();
+        assert_eq!(db.get_blob_counts().unwrap(), (2, 12));
+        assert_eq!(db.get_duplicate_count().unwrap(), 1);
+        let mut att = db.get_attachment_by_id(2).unwrap().unwrap();
+        assert_eq!(att.pdf_data.sha256, sha256_hex(b"%PDF-1"));
+        assert_eq!(att.pdf_data.load(&db).unwrap(), b"%PDF-1");
+        assert!(db.conn.prepare("SELECT pdf_data FROM attachments").is_err());
+
+        // New attachments with known content add no blob
+        att.attachment_id = Some("w".to_string());
+        att.pdf_data = Blob::new(b"%PDF-1".to_vec());
+        let msg = StoredMessage {
+            uid: att.message_uid.clone(),
+            message_id: "m1".to_string(),
+            user: "user@example.com".to_string(),
+            date: "2025-01-01".to_string(),
+            from_addr: None,
+            subject: None,
+            plain_text: None,
+            html: None,
+            has_attachments: true,
+            is_processed: false,
+        };
+        assert!(db.store_message(&msg, 2, &[att]).unwrap()[0].is_some());
+        assert_eq!(db.get_blob_counts().unwrap().0, 2);
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_foreign_keys_enforced_on_current_schema() {
+        let path =
+            std::env::temp_dir().join(format!("invoice_search_fks_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        drop(MessageStore::new(&path).unwrap());
+
+        // Reopening a current database runs no migration
+        let foreign_keys = |db: &MessageStore| -> bool {
+            db.conn
+                .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+                .unwrap()
+        };
+        let db = MessageStore::new(&path).unwrap();
+        assert!(foreign_keys(&db));
+        let orphan = db.conn.execute(
+            "INSERT INTO attachments (message_uid, filename, sha256) VALUES ('missing', 'a.pdf', 'x')",
+            [],
+        );
+        let err = orphan.unwrap_err().to_string();
+        assert!(err.contains("FOREIGN KEY"), "{err}");
+        drop(db);
+        assert!(foreign_keys(&MessageStore::open_read_only(&path).unwrap()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,262 @@
+// src/message_db/invoice_query.rs
+
+use super::{MessageStore, StoredInvoice};
+use crate::money::{self, Currency, Money};
+use rusqlite::types::Value;
+use rusqlite::{Result as SqliteResult, params_from_iter};
+use time::Date;
+
+/// Order of [`MessageStore::query_invoices`] results. Invoices missing the
+/// sort field come last either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum InvoiceSort {
+    /// When the invoice was extracted
+    #[default]
+    Created,
+    /// Invoice date
+    Date,
+    /// Total amount
+    Amount,
+    Vendor,
+    InvoiceNo,
+}
+
+/// Filters, order and page for [`MessageStore::query_invoices`]. Text
+/// filters match anywhere in the field, ignoring ASCII case.
+#[derive(Debug, Clone, Default)]
+pub struct InvoiceQuery {
+    vendor: Option<String>,
+    buyer: Option<String>,
+    invoice_no: Option<String>,
+    since: Option<Date>,
+    until: Option<Date>,
+    currency: Option<String>,
+    min_amount: Option<String>,
+    max_amount: Option<String>,
+    item: Option<String>,
+    sort: InvoiceSort,
+    descending: bool,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+impl InvoiceQuery {
+    /// Every invoice, most recently extracted first.
+    pub fn new() -> Self {
+        Self {
+            descending: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn vendor(mut self, vendor: impl Into<String>) -> Self {
+        self.vendor = Some(vendor.into());
+        self
+    }
+
+    pub fn buyer(mut self, buyer: impl Into<String>) -> Self {
+        self.buyer = Some(buyer.into());
+        self
+    }
+
+    pub fn invoice_no(mut self, invoice_no: impl Into<String>) -> Self {
+        self.invoice_no = Some(invoice_no.into());
+        self
+    }
+
+    /// Invoices dated on or after `day`. Invoices whose date could not be
+    /// read never match a date filter.
+    pub fn since(mut self, day: Date) -> Self {
+        self.since = Some(day);
+        self
+    }
+
+    /// Invoices dated on or before `day`.
+    pub fn until(mut self, day: Date) -> Self {
+        self.until = Some(day);
+        self
+    }
+
+    /// ISO 4217 code, e.g. "USD".
+    pub fn currency(mut self, currency: impl Into<String>) -> Self {
+        self.currency = Some(currency.into());
+        self
+    }
+
+    /// Total amount at least `amount`, a decimal such as `2540.00`. It is
+    /// read in the [`currency`](Self::currency) filter's minor unit, or else
+    /// in each invoice's own: 1500 matches 1500 JPY and 1500.00 USD. Text
+    /// that is not a decimal matches nothing.
+    pub fn min_amount(mut self, amount: impl Into<String>) -> Self {
+        self.min_amount = Some(amount.into());
+        self
+    }
+
+    /// Total amount at most `amount`.
+    pub fn max_amount(mut self, amount: impl Into<String>) -> Self {
+        self.max_amount = Some(amount.into());
+        self
+    }
+
+    /// Invoices with a line item whose description contains `description`.
+    pub fn item(mut self, description: impl Into<String>) -> Self {
+        self.item = Some(description.into());
+        self
+    }
+
+    /// Sort ascending by `key`; follow with [`descending`](Self::descending)
+    /// to reverse.
+    pub fn sort(mut self, key: InvoiceSort) -> Self {
+        self.sort = key;
+        self.descending = false;
+        self
+    }
+
+    pub fn descending(mut self) -> Self {
+        self.descending = true;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// WHERE clause over `invoices` and its arguments.
+    fn filter(&self) -> (String, Vec<Value>) {
+        let mut clauses = vec!["1 = 1".to_string()];
+        let mut args = Vec::new();
+        let text_fields = [
+            ("vendor", &self.vendor),
+            ("buyer", &self.buyer),
+            ("invoice_no", &self.invoice_no),
+        ];
+        for (column, value) in text_fields {
+            if let Some(value) = value {
+                clauses.push(format!("{column} LIKE ? ESCAPE '\\'"));
+                args.push(Value::Text(contains_pattern(value)));
+            }
+        }
+        if let Some(currency) = &self.currency {
+            clauses.push("currency = ? COLLATE NOCASE".to_string());
+            args.push(Value::Text(currency.clone()));
+        }
+        if let Some(since) = self.since {
+            clauses.push("invoice_date_iso >= ?".to_string());
+            args.push(Value::Text(since.to_string()));
+        }
+        if let Some(until) = self.until {
+            clauses.push("invoice_date_iso <= ?".to_string());
+            args.push(Value::Text(until.to_string()));
+        }
+        let bounds = [(&self.min_amount, ">="), (&self.max_amount, "<=")];
+        for (bound, op) in bounds {
+            if let Some(bound) = bound {
+                clauses.push(self.amount_clause(bound, op, &mut args));
+            }
+        }
+        if let Some(item) = &self.item {
+            clauses.push(
+                "EXISTS (SELECT 1 FROM invoice_line_items li
+                         WHERE li.invoice_id = invoices.id
+                           AND li.description LIKE ? ESCAPE '\\')"
+                    .to_string(),
+            );
+            args.push(Value::Text(contains_pattern(item)));
+        }
+        (clauses.join(" AND "), args)
+    }
+
+    /// `total_amount_minor {op} bound`, with `bound` in minor units of the
+    /// filtered currency, or of each row's own currency without a filter.
+    fn amount_clause(&self, bound: &str, op: &str, args: &mut Vec<Value>) -> String {
+        if let Some(currency) = &self.currency {
+            // Every row that passes the currency filter is in this currency
+            let Some(bound) = Money::parse(bound, Currency::of(Some(currency))) else {
+                return "0 = 1".to_string();
+            };
+            args.push(Value::Integer(bound.minor));
+            return format!("total_amount_minor {op} ?");
+        }
+        // A bound that does not parse at some exponent matches no row there
+        let sql = money::sql_by_exponent("currency", |exponent| {
+            match money::parse_minor(bound, exponent) {
+                Some(minor) => {
+                    args.push(Value::Integer(minor));
+                    format!("total_amount_minor {op} ?")
+                }
+                None => "0".to_string(),
+            }
+        });
+        format!("({sql})")
+    }
+
+    fn order_by(&self) -> String {
+        let key = match self.sort {
+            InvoiceSort::Created => "created_at".to_string(),
+            InvoiceSort::Date => "invoice_date_iso".to_string(),
+            InvoiceSort::Amount => scaled_total(),
+            InvoiceSort::Vendor => "vendor COLLATE NOCASE".to_string(),
+            InvoiceSort::InvoiceNo => "invoice_no COLLATE NOCASE".to_string(),
+        };
+        let direction = if self.descending { "DESC" } else { "ASC" };
+        format!("{key} {direction} NULLS LAST, id {direction}")
+    }
+}
+
+/// The invoice total at a scale shared by every currency, so amounts
+/// compare by their face value whatever each currency's minor unit.
+fn scaled_total() -> String {
+    money::sql_scaled("total_amount_minor", "currency")
+}
+
+/// LIKE pattern matching `text` anywhere, with its wildcards taken literally.
+fn contains_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+impl MessageStore {
+    /// Stored invoices matching `query`, in its order and page, with their
+    /// line items and packing lists.
+    pub fn query_invoices(&self, query: &InvoiceQuery) -> SqliteResult<Vec<StoredInvoice>> {
+        let (filter, mut args) = query.filter();
+        args.push(Value::Integer(query.limit.map_or(-1, |l| l as i64)));
+        args.push(Value::Integer(query.offset as i64));
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, attachment_id, backend, model, created_at, vendor, buyer, invoice_no,
+                    invoice_date, currency, total_amount_minor, total_pieces, ship_from, ship_to,
+                    shipping_method, first_page, last_page, invoice_date_iso
+             FROM invoices
+             WHERE {filter}
+             ORDER BY {}
+             LIMIT ? OFFSET ?",
+            query.order_by()
+        ))?;
+        let invoices = stmt
+            .query_map(params_from_iter(args), Self::row_to_invoice)?
+            .collect::<SqliteResult<Vec<_>>>()?;
+        invoices
+            .into_iter()
+            .map(|inv| self.load_invoice_children(inv))
+            .collect()
+    }
+
+    /// How many invoices match `query`, ignoring its page.
+    pub fn count_invoices(&self, query: &InvoiceQuery) -> SqliteResult<usize> {
+        let (filter, args) = query.filter();
+        self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM invoices WHERE {filter}"),
+            params_from_iter(args),
+            |row| row.get(0),
+        )
+    }
+}
@@ -0,0 +1,711 @@
+// src/message_db/migrations.rs
+
+use super::{MessageStore, sha256_hex};
+use crate::dates::{self, DateHints};
+use crate::money;
+use rusqlite::{Connection, Result as SqliteResult, ffi, params};
+use tracing::info;
+
+/// One schema change. `apply` runs inside a transaction together with the
+/// `user_version` bump, so a migration either lands completely or not at all.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    apply: fn(&Connection) -> SqliteResult<()>,
+}
+
+/// Every schema change, oldest first. Append only: a released migration
+/// must never change, since databases already at its version skip it.
+///
+/// Databases created before versioning sit at version 0 with any prefix of
+/// 1–5 applied by the old startup probes, so those check before altering.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        apply: initial_schema,
+    },
+    Migration {
+        version: 2,
+        name: "unique_attachments",
+        apply: unique_attachments,
+    },
+    Migration {
+        version: 3,
+        name: "invoice_page_ranges",
+        apply: invoice_page_ranges,
+    },
+    Migration {
+        version: 4,
+        name: "pdf_metadata",
+        apply: pdf_metadata,
+    },
+    Migration {
+        version: 5,
+        name: "blob_store",
+        apply: blob_store,
+    },
+    Migration {
+        version: 6,
+        name: "full_text_search",
+        apply: full_text_search,
+    },
+    Migration {
+        version: 7,
+        name: "normalized_dates",
+        apply: normalized_dates,
+    },
+    Migration {
+        version: 8,
+        name: "exact_amounts",
+        apply: exact_amounts,
+    },
+    Migration {
+        version: 9,
+        name: "mixed_attachments",
+        apply: mixed_attachments,
+    },
+    Migration {
+        version: 10,
+        name: "attachment_counts",
+        apply: attachment_counts,
+    },
+    Migration {
+        version: 11,
+        name: "scoped_fetch_failures",
+        apply: scoped_fetch_failures,
+    },
+];
+
+/// Schema version this build brings databases to.
+pub fn latest() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Schema version recorded in the database (`PRAGMA user_version`).
+pub fn schema_version(conn: &Connection) -> SqliteResult<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Apply every migration above the database's version, in order. Refuses a
+/// database written by a newer build rather than guess at its schema.
+pub fn migrate(conn: &mut Connection) -> SqliteResult<Vec<&'static Migration>> {
+    let current = schema_version(conn)?;
+    if current > latest() {
+        return Err(schema_error(format!(
+            "database schema version {current} is newer than this build supports ({}); \
+             upgrade invoice_search to open it",
+            latest()
+        )));
+    }
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    if pending.is_empty() {
+        return Ok(pending);
+    }
+
+    // Table rebuilds drop tables that others reference; the pragma is a
+    // no-op inside a transaction, so it is switched around the whole run
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let applied = apply_all(conn, &pending);
+    conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+    applied?;
+    Ok(pending)
+}
+
+/// Refuse a database whose schema is not this build's, without changing
+/// it: for read-only opens, where pending migrations cannot run.
+pub fn ensure_current(conn: &Connection) -> SqliteResult<()> {
+    let current = schema_version(conn)?;
+    if current > latest() {
+        return Err(schema_error(format!(
+            "database schema version {current} is newer than this build supports ({}); \
+             upgrade invoice_search to open it",
+            latest()
+        )));
+    }
+    if current < latest() {
+        return Err(schema_error(format!(
+            "database schema version {current} has {} migration(s) pending; \
+             run `invoice_search db migrate` first",
+            latest() - current
+        )));
+    }
+    Ok(())
+}
+
+fn schema_error(message: String) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_CANTOPEN), Some(message))
+}
+
+fn apply_all(conn: &mut Connection, pending: &[&Migration]) -> SqliteResult<()> {
+    for migration in pending {
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        info!(
+            version = migration.version,
+            name = migration.name,
+            "Applied schema migration"
+        );
+    }
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> bool {
+    conn.prepare(&format!("SELECT {column} FROM {table} LIMIT 0"))
+        .is_ok()
+}
+
+fn initial_schema(conn: &Connection) -> SqliteResult<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS messages (
+            uid TEXT PRIMARY KEY,
+            message_id TEXT NOT NULL,
+            user TEXT NOT NULL,
+            date TEXT NOT NULL,
+            from_addr TEXT,
+            subject TEXT,
+            plain_text TEXT,
+            html TEXT,
+            has_attachments INTEGER NOT NULL DEFAULT 0,
+            is_processed INTEGER NOT NULL DEFAULT 0,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        );
+
+        CREATE TABLE IF NOT EXISTS attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_uid TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            attachment_id TEXT,
+            pdf_data BLOB NOT NULL,
+            is_processed INTEGER NOT NULL DEFAULT 0,
+            content_type TEXT NOT NULL DEFAULT 'unknown',
+            extracted_text TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (message_uid) REFERENCES messages(uid) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS processed_messages (
+            uid TEXT PRIMARY KEY,
+            processed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (uid) REFERENCES messages(uid)
+        );
+
+        CREATE TABLE IF NOT EXISTS processed_attachments (
+            attachment_id INTEGER PRIMARY KEY,
+            processed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (attachment_id) REFERENCES attachments(id)
+        );
+
+        CREATE TABLE IF NOT EXISTS invoices (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            attachment_id INTEGER NOT NULL UNIQUE,
+            backend TEXT NOT NULL,
+            model TEXT,
+            vendor TEXT,
+            buyer TEXT,
+            invoice_no TEXT,
+            invoice_date TEXT,
+            currency TEXT,
+            total_amount REAL,
+            total_pieces INTEGER,
+            ship_from TEXT,
+            ship_to TEXT,
+            shipping_method TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (attachment_id) REFERENCES attachments(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS invoice_line_items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            invoice_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            description TEXT NOT NULL,
+            qty INTEGER NOT NULL,
+            unit_price REAL NOT NULL,
+            amount REAL NOT NULL,
+            FOREIGN KEY (invoice_id) REFERENCES invoices(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS packing_items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            invoice_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            carton TEXT NOT NULL,
+            description TEXT NOT NULL,
+            ctns INTEGER NOT NULL,
+            qty INTEGER NOT NULL,
+            net_wt_per_ctn REAL NOT NULL,
+            gross_wt_per_ctn REAL NOT NULL,
+            measurement TEXT NOT NULL,
+            FOREIGN KEY (invoice_id) REFERENCES invoices(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS packing_totals (
+            invoice_id INTEGER PRIMARY KEY,
+            total_cartons INTEGER NOT NULL,
+            total_qty INTEGER NOT NULL,
+            total_net_wt REAL NOT NULL,
+            total_gross_wt REAL NOT NULL,
+            FOREIGN KEY (invoice_id) REFERENCES invoices(id) ON DELETE CASCADE
+        );
+
+        -- Gmail history position per user/query
+        CREATE TABLE IF NOT EXISTS sync_state (
+            user TEXT NOT NULL,
+            query TEXT NOT NULL,
+            history_id INTEGER NOT NULL,
+            synced_at INTEGER NOT NULL,
+            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (user, query)
+        );
+
+        -- Messages that exhausted their retries
+        CREATE TABLE IF NOT EXISTS fetch_failures (
+            user TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            error TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 1,
+            failed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (user, message_id)
+        );
+
+        -- Per-page classification and text
+        CREATE TABLE IF NOT EXISTS attachment_pages (
+            attachment_id INTEGER NOT NULL,
+            page INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            text TEXT,
+            PRIMARY KEY (attachment_id, page),
+            FOREIGN KEY (attachment_id) REFERENCES attachments(id) ON DELETE CASCADE
+        );
+
+        -- Per-page OCR text and confidence
+        CREATE TABLE IF NOT EXISTS ocr_pages (
+            attachment_id INTEGER NOT NULL,
+            page INTEGER NOT NULL,
+            confidence REAL NOT NULL,
+            text TEXT NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (attachment_id, page),
+            FOREIGN KEY (attachment_id) REFERENCES attachments(id) ON DELETE CASCADE
+        );
+
+        -- Which profiles matched each message
+        CREATE TABLE IF NOT EXISTS message_labels (
+            message_uid TEXT NOT NULL,
+            label TEXT NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (message_uid, label),
+            FOREIGN KEY (message_uid) REFERENCES messages(uid) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_messages_user ON messages(user);
+        CREATE INDEX IF NOT EXISTS idx_messages_is_processed ON messages(is_processed);
+        CREATE INDEX IF NOT EXISTS idx_messages_date ON messages(date);
+        CREATE INDEX IF NOT EXISTS idx_attachments_message_uid ON attachments(message_uid);
+        CREATE INDEX IF NOT EXISTS idx_attachments_is_processed ON attachments(is_processed);
+        CREATE INDEX IF NOT EXISTS idx_line_items_invoice_id ON invoice_line_items(invoice_id);
+        CREATE INDEX IF NOT EXISTS idx_packing_items_invoice_id ON packing_items(invoice_id);",
+    )?;
+
+    // Attachments tables from before classification
+    if !has_column(conn, "attachments", "content_type") {
+        conn.execute_batch(
+            "ALTER TABLE attachments ADD COLUMN content_type TEXT;
+             ALTER TABLE attachments ADD COLUMN extracted_text TEXT;",
+        )?;
+    }
+    Ok(())
+}
+
+/// Drop duplicate attachment rows left by earlier non-idempotent runs, then
+/// enforce one row per (message_uid, attachment_id). Foreign keys are off
+/// while migrating, so the duplicates' invoices and pages go first.
+fn unique_attachments(conn: &Connection) -> SqliteResult<()> {
+    let duplicates = "SELECT id FROM attachments
+         WHERE attachment_id IS NOT NULL
+           AND id NOT IN (
+               SELECT MIN(id) FROM attachments
+               WHERE attachment_id IS NOT NULL
+               GROUP BY message_uid, attachment_id
+           )";
+    let ids: Vec<i64> = conn
+        .prepare(duplicates)?
+        .query_map([], |row| row.get(0))?
+        .collect::<SqliteResult<_>>()?;
+    for id in ids {
+        MessageStore::delete_invoices(conn, id)?;
+    }
+    for table in ["processed_attachments", "attachment_pages", "ocr_pages"] {
+        conn.execute(
+            &format!("DELETE FROM {table} WHERE attachment_id IN ({duplicates})"),
+            [],
+        )?;
+    }
+    let removed = conn.execute(
+        &format!("DELETE FROM attachments WHERE id IN ({duplicates})"),
+        [],
+    )?;
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_attachments_unique
+         ON attachments(message_uid, attachment_id)",
+        [],
+    )?;
+    if removed > 0 {
+        info!(removed, "Removed duplicate attachment rows");
+    }
+    Ok(())
+}
+
+/// Several invoices per attachment (one per logical document), each with
+/// its page range. Dropping the UNIQUE constraint on attachment_id needs a
+/// table rebuild; ids are kept for child rows.
+fn invoice_page_ranges(conn: &Connection) -> SqliteResult<()> {
+    if !has_column(conn, "invoices", "first_page") {
+        let columns = "id, attachment_id, backend, model, vendor, buyer, invoice_no, \
+                       invoice_date, currency, total_amount, total_pieces, ship_from, \
+                       ship_to, shipping_method, created_at";
+        conn.execute_batch(&format!(
+            "CREATE TABLE invoices_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                attachment_id INTEGER NOT NULL,
+                backend TEXT NOT NULL,
+                model TEXT,
+                vendor TEXT,
+                buyer TEXT,
+                invoice_no TEXT,
+                invoice_date TEXT,
+                currency TEXT,
+                total_amount REAL,
+                total_pieces INTEGER,
+                ship_from TEXT,
+                ship_to TEXT,
+                shipping_method TEXT,
+                first_page INTEGER,
+                last_page INTEGER,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY (attachment_id) REFERENCES attachments(id) ON DELETE CASCADE
+             );
+             INSERT INTO invoices_new ({columns}) SELECT {columns} FROM invoices;
+             DROP TABLE invoices;
+             ALTER TABLE invoices_new RENAME TO invoices;"
+        ))?;
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_invoices_attachment_id ON invoices(attachment_id)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Document-level PDF facts and the SHA-256 fingerprint.
+fn pdf_metadata(conn: &Connection) -> SqliteResult<()> {
+    if !has_column(conn, "attachments", "sha256") {
+        conn.execute_batch(
+            "ALTER TABLE attachments ADD COLUMN page_count INTEGER;
+             ALTER TABLE attachments ADD COLUMN pdf_version TEXT;
+             ALTER TABLE attachments ADD COLUMN producer TEXT;
+             ALTER TABLE attachments ADD COLUMN creator TEXT;
+             ALTER TABLE attachments ADD COLUMN pdf_created TEXT;
+             ALTER TABLE attachments ADD COLUMN pdf_modified TEXT;
+             ALTER TABLE attachments ADD COLUMN encrypted INTEGER;
+             ALTER TABLE attachments ADD COLUMN byte_size INTEGER;
+             ALTER TABLE attachments ADD COLUMN sha256 TEXT;",
+        )?;
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_attachments_sha256 ON attachments(sha256)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Move inline PDF bytes into a content-addressed `blobs` table, one copy
+/// per distinct content. Rows are read one at a time to bound memory.
+fn blob_store(conn: &Connection) -> SqliteResult<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blobs (
+            sha256 TEXT PRIMARY KEY,
+            data BLOB NOT NULL,
+            size INTEGER NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+    if !has_column(conn, "attachments", "pdf_data") {
+        return Ok(());
+    }
+
+    let ids: Vec<i64> = conn
+        .prepare("SELECT id FROM attachments")?
+        .query_map([], |row| row.get(0))?
+        .collect::<SqliteResult<_>>()?;
+    let mut stored = 0;
+    for &id in &ids {
+        let data: Vec<u8> = conn.query_row(
+            "SELECT pdf_data FROM attachments WHERE id = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        let sha256 = sha256_hex(&data);
+        stored += conn.execute(
+            "INSERT OR IGNORE INTO blobs (sha256, data, size) VALUES (?1, ?2, ?3)",
+            params![sha256, data, data.len()],
+        )?;
+        conn.execute(
+            "UPDATE attachments SET sha256 = ?1 WHERE id = ?2",
+            params![sha256, id],
+        )?;
+    }
+    conn.execute("ALTER TABLE attachments DROP COLUMN pdf_data", [])?;
+    if !ids.is_empty() {
+        info!(
+            attachments = ids.len(),
+            blobs = stored,
+            "Moved attachment bytes to the blob store — VACUUM to reclaim the space"
+        );
+    }
+    Ok(())
+}
+
+/// FTS5 indexes over message text and extracted attachment text, kept in
+/// sync by triggers. Attachments index by their INTEGER PRIMARY KEY; message
+/// rowids are implicit and may change on VACUUM, so that index keeps the uid.
+fn full_text_search(conn: &Connection) -> SqliteResult<()> {
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE messages_fts USING fts5(
+            uid UNINDEXED, subject, plain_text, from_addr,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        INSERT INTO messages_fts (uid, subject, plain_text, from_addr)
+            SELECT uid, subject, plain_text, from_addr FROM messages;
+
+        CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (uid, subject, plain_text, from_addr)
+            VALUES (new.uid, new.subject, new.plain_text, new.from_addr);
+        END;
+        CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+            DELETE FROM messages_fts WHERE uid = old.uid;
+        END;
+        CREATE TRIGGER messages_fts_update AFTER UPDATE OF subject, plain_text, from_addr
+        ON messages
+        WHEN old.subject IS NOT new.subject
+          OR old.plain_text IS NOT new.plain_text
+          OR old.from_addr IS NOT new.from_addr
+        BEGIN
+            DELETE FROM messages_fts WHERE uid = old.uid;
+            INSERT INTO messages_fts (uid, subject, plain_text, from_addr)
+            VALUES (new.uid, new.subject, new.plain_text, new.from_addr);
+        END;
+
+        CREATE VIRTUAL TABLE attachments_fts USING fts5(
+            extracted_text,
+            content = 'attachments', content_rowid = 'id',
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        INSERT INTO attachments_fts (attachments_fts) VALUES ('rebuild');
+
+        CREATE TRIGGER attachments_fts_insert AFTER INSERT ON attachments BEGIN
+            INSERT INTO attachments_fts (rowid, extracted_text)
+            VALUES (new.id, new.extracted_text);
+        END;
+        CREATE TRIGGER attachments_fts_delete AFTER DELETE ON attachments BEGIN
+            INSERT INTO attachments_fts (attachments_fts, rowid, extracted_text)
+            VALUES ('delete', old.id, old.extracted_text);
+        END;
+        CREATE TRIGGER attachments_fts_update AFTER UPDATE OF extracted_text ON attachments
+        WHEN old.extracted_text IS NOT new.extracted_text
+        BEGIN
+            INSERT INTO attachments_fts (attachments_fts, rowid, extracted_text)
+            VALUES ('delete', old.id, old.extracted_text);
+            INSERT INTO attachments_fts (rowid, extracted_text)
+            VALUES (new.id, new.extracted_text);
+        END;",
+    )
+}
+
+/// Sortable copies of message and invoice dates next to the raw text:
+/// messages as UTC ISO 8601, invoices as YYYY-MM-DD. Invoice dates are read
+/// with the default day order; `process` re-reads them with configured hints.
+fn normalized_dates(conn: &Connection) -> SqliteResult<()> {
+    conn.execute_batch(
+        "ALTER TABLE messages ADD COLUMN date_iso TEXT;
+         ALTER TABLE invoices ADD COLUMN invoice_date_iso TEXT;
+         CREATE INDEX idx_messages_date_iso ON messages(date_iso);
+         CREATE INDEX idx_invoices_date_iso ON invoices(invoice_date_iso);",
+    )?;
+
+    let messages: Vec<(String, String)> = conn
+        .prepare("SELECT uid, date FROM messages")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<SqliteResult<_>>()?;
+    for (uid, date) in &messages {
+        conn.execute(
+            "UPDATE messages SET date_iso = ?1 WHERE uid = ?2",
+            params![dates::message_date_iso(date), uid],
+        )?;
+    }
+
+    let hints = DateHints::default();
+    let invoices: Vec<(i64, Option<String>, Option<String>)> = conn
+        .prepare("SELECT id, vendor, invoice_date FROM invoices WHERE invoice_date IS NOT NULL")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<SqliteResult<_>>()?;
+    for (id, vendor, raw) in &invoices {
+        let iso = raw
+            .as_deref()
+            .and_then(|raw| dates::parse_invoice_date(raw, hints.order_for(vendor.as_deref())));
+        conn.execute(
+            "UPDATE invoices SET invoice_date_iso = ?1 WHERE id = ?2",
+            params![iso.map(|d| d.to_string()), id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Amounts as whole minor units of the invoice's currency (cents, or yen
+/// for JPY) instead of REAL, so sums compare exactly.
+fn exact_amounts(conn: &Connection) -> SqliteResult<()> {
+    let invoice_scale = money::sql_minor_per_major("currency");
+    let line_scale = format!(
+        "(SELECT {} FROM invoices WHERE invoices.id = invoice_line_items.invoice_id)",
+        money::sql_minor_per_major("invoices.currency")
+    );
+    conn.execute_batch(&format!(
+        "ALTER TABLE invoices ADD COLUMN total_amount_minor INTEGER;
+         ALTER TABLE invoice_line_items ADD COLUMN unit_price_minor INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE invoice_line_items ADD COLUMN amount_minor INTEGER NOT NULL DEFAULT 0;
+
+         UPDATE invoices
+         SET total_amount_minor = CAST(round(total_amount * {invoice_scale}) AS INTEGER);
+         UPDATE invoice_line_items
+         SET unit_price_minor = CAST(round(unit_price * {line_scale}) AS INTEGER),
+             amount_minor = CAST(round(amount * {line_scale}) AS INTEGER);
+
+         ALTER TABLE invoices DROP COLUMN total_amount;
+         ALTER TABLE invoice_line_items DROP COLUMN unit_price;
+         ALTER TABLE invoice_line_items DROP COLUMN amount;"
+    ))
+}
+
+/// Scanned attachments that also have text pages become `mixed`, so those
+/// pages are extracted without waiting for OCR.
+fn mixed_attachments(conn: &Connection) -> SqliteResult<()> {
+    conn.execute(
+        "UPDATE attachments SET content_type = 'mixed'
+         WHERE content_type = 'scanned'
+           AND EXISTS (SELECT 1 FROM attachment_pages p
+                       WHERE p.attachment_id = attachments.id AND p.kind = 'text')",
+        [],
+    )?;
+    Ok(())
+}
+
+/// How many PDFs Gmail listed for a message, so a sync can tell a message
+/// stored with all of them from one that lost some. NULL for messages
+/// stored before this count existed.
+fn attachment_counts(conn: &Connection) -> SqliteResult<()> {
+    conn.execute_batch("ALTER TABLE messages ADD COLUMN attachment_count INTEGER")
+}
+
+/// Fetch failures keyed by the query that matched the message, so a retry
+/// only joins (and labels) that query's batch. Older failures don't say
+/// which query they came from: they are dropped and their mailbox's sync
+/// positions cleared, so the next sync lists every query in full.
+fn scoped_fetch_failures(conn: &Connection) -> SqliteResult<()> {
+    conn.execute_batch(
+        "DELETE FROM sync_state WHERE user IN (SELECT user FROM fetch_failures);
+         DROP TABLE fetch_failures;
+         CREATE TABLE fetch_failures (
+            user TEXT NOT NULL,
+            query TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            error TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 1,
+            failed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (user, query, message_id)
+         );",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_fresh_and_refuse_newer() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+        let applied = migrate(&mut conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        // Foreign key enforcement is left as the caller set it
+        let foreign_keys: bool = conn
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+        assert_eq!(schema_version(&conn).unwrap(), latest());
+        assert!(has_column(&conn, "invoices", "first_page"));
+        assert!(!has_column(&conn, "attachments", "pdf_data"));
+        assert!(!has_column(&conn, "invoices", "total_amount"));
+        assert!(has_column(&conn, "fetch_failures", "query"));
+
+        // Versions are consecutive, so none is skipped
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version as usize, i + 1, "{}", m.name);
+        }
+
+        // Already current: nothing to do
+        assert!(migrate(&mut conn).unwrap().is_empty());
+        assert!(ensure_current(&conn).is_ok());
+
+        // Read-only opens refuse a schema that is behind instead of migrating
+        conn.pragma_update(None, "user_version", latest() - 1)
+            .unwrap();
+        let Err(err) = ensure_current(&conn) else {
+            panic!("accepted a database with a pending migration");
+        };
+        assert!(err.to_string().contains("1 migration(s) pending"), "{err}");
+        assert_eq!(schema_version(&conn).unwrap(), latest() - 1);
+
+        conn.pragma_update(None, "user_version", latest() + 1)
+            .unwrap();
+        let Err(err) = migrate(&mut conn) else {
+            panic!("opened a newer database");
+        };
+        assert!(err.to_string().contains("newer than this build"), "{err}");
+    }
+
+    #[test]
+    fn test_unique_attachments_removes_duplicates_children() {
+        let conn = Connection::open_in_memory().unwrap();
+        // Off as during `migrate`; the attachments have no message row
+        conn.pragma_update(None, "foreign_keys", false).unwrap();
+        initial_schema(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO attachments (id, message_uid, filename, attachment_id, pdf_data)
+             VALUES (1, 'u', 'a.pdf', 'att-1', x''), (2, 'u', 'a.pdf', 'att-1', x'');
+             INSERT INTO invoices (id, attachment_id, backend) VALUES (10, 1, 'llm'), (20, 2, 'llm');
+             INSERT INTO invoice_line_items (invoice_id, position, description, qty, unit_price, amount)
+             VALUES (10, 0, 'kept', 1, 1.0, 1.0), (20, 0, 'orphan', 1, 1.0, 1.0);
+             INSERT INTO packing_totals VALUES (20, 1, 1, 1.0, 1.0);
+             INSERT INTO attachment_pages VALUES (1, 1, 'text', 'kept'), (2, 1, 'text', 'orphan');
+             INSERT INTO ocr_pages (attachment_id, page, confidence, text) VALUES (2, 1, 0.9, 'orphan');
+             INSERT INTO processed_attachments (attachment_id) VALUES (2);",
+        )
+        .unwrap();
+
+        unique_attachments(&conn).unwrap();
+        let count = |sql: &str| -> i64 { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM attachments"), 1);
+        assert_eq!(count("SELECT MIN(id) FROM invoices"), 10);
+        assert_eq!(count("SELECT COUNT(*) FROM invoices"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM invoice_line_items"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM packing_totals"), 0);
+        assert_eq!(count("SELECT COUNT(*) FROM attachment_pages"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM ocr_pages"), 0);
+        assert_eq!(count("SELECT COUNT(*) FROM processed_attachments"), 0);
+    }
+}
@@ -0,0 +1,290 @@
+// src/money.rs
+
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+/// ISO 4217 currencies whose minor unit is not the cent; everything else
+/// has two decimals.
+const EXPONENTS: &[(u32, &[&str])] = &[
+    (
+        0,
+        &[
+            "BIF", "CLP", "DJF", "GNF", "ISK", "JPY", "KMF", "KRW", "PYG", "RWF", "UGX", "UYI",
+            "VND", "VUV", "XAF", "XOF", "XPF",
+        ],
+    ),
+    (3, &["BHD", "IQD", "JOD", "KWD", "LYD", "OMR", "TND"]),
+    (4, &["CLF", "UYW"]),
+];
+
+/// Largest exponent in [`EXPONENTS`]; amounts in different currencies
+/// compare at this many decimals.
+const MAX_EXPONENT: u32 = 4;
+
+/// An ISO 4217 currency code.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    /// `XXX`, ISO 4217's "no currency": amounts whose currency is missing or
+    /// unrecognised. Counted in cents.
+    pub const NONE: Currency = Currency(*b"XXX");
+
+    /// Three ASCII letters, any case.
+    pub fn new(code: &str) -> Option<Self> {
+        let code: [u8; 3] = code.trim().as_bytes().try_into().ok()?;
+        code.iter()
+            .all(u8::is_ascii_alphabetic)
+            .then(|| Currency(code.map(|b| b.to_ascii_uppercase())))
+    }
+
+    /// The currency for an invoice's `currency` field, or [`Currency::NONE`].
+    pub fn of(code: Option<&str>) -> Self {
+        code.and_then(Self::new).unwrap_or(Self::NONE)
+    }
+
+    pub fn code(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("currency codes are ASCII")
+    }
+
+    /// Decimals of the minor unit: 2 for USD, 0 for JPY, 3 for KWD.
+    pub fn exponent(&self) -> u32 {
+        EXPONENTS
+            .iter()
+            .find(|(_, codes)| codes.contains(&self.code()))
+            .map_or(2, |&(exponent, _)| exponent)
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// An exact amount: a whole number of the currency's minor units (cents,
+/// or yen for JPY). Displays and serializes as a plain decimal string such
+/// as `2540.00`, without the code.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Money {
+    pub minor: i64,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub fn new(minor: i64, currency: Currency) -> Self {
+        Self { minor, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Self::new(0, currency)
+    }
+
+    /// Parse a decimal like `2540`, `2,540.00` or `-0.5`. Digits beyond the
+    /// currency's minor unit are rounded half away from zero, so a unit
+    /// price of `0.125` USD becomes `0.13`.
+    pub fn parse(text: &str, currency: Currency) -> Option<Self> {
+        parse_minor(text, currency.exponent()).map(|minor| Self::new(minor, currency))
+    }
+
+    /// `self * n`, or None on overflow.
+    pub fn times(self, n: u32) -> Option<Self> {
+        Some(Self::new(
+            self.minor.checked_mul(i64::from(n))?,
+            self.currency,
+        ))
+    }
+
+    /// `self / n` rounded half away from zero to the minor unit.
+    pub fn divide(self, n: u32) -> Option<Self> {
+        let n = i64::from(n);
+        if n == 0 {
+            return None;
+        }
+        let rounded = (self.minor.abs() + n / 2) / n;
+        Some(Self::new(rounded * self.minor.signum(), self.currency))
+    }
+
+    /// Sum of two amounts in the same currency.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        (self.currency == other.currency)
+            .then(|| self.minor.checked_add(other.minor))
+            .flatten()
+            .map(|minor| Self::new(minor, self.currency))
+    }
+}
+
+/// [`Money::parse`] for a minor unit of `exponent` decimals, giving the
+/// whole number of minor units.
+pub fn parse_minor(text: &str, exponent: u32) -> Option<i64> {
+    let text = text.trim();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let whole = whole.replace(',', "");
+    if (whole.is_empty() && fraction.is_empty())
+        || !whole.bytes().all(|b| b.is_ascii_digit())
+        || !fraction.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let exponent = exponent as usize;
+    let mut minor: i64 = 0;
+    let kept = fraction
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(exponent);
+    for b in whole.bytes().chain(kept) {
+        minor = minor.checked_mul(10)?.checked_add(i64::from(b - b'0'))?;
+    }
+    if fraction
+        .as_bytes()
+        .get(exponent)
+        .is_some_and(|&b| b >= b'5')
+    {
+        minor = minor.checked_add(1)?;
+    }
+    Some(if negative { -minor } else { minor })
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exponent = self.currency.exponent();
+        let sign = if self.minor < 0 { "-" } else { "" };
+        let minor = self.minor.unsigned_abs();
+        if exponent == 0 {
+            return write!(f, "{sign}{minor}");
+        }
+        let unit = 10_u64.pow(exponent);
+        write!(
+            f,
+            "{sign}{}.{:0width$}",
+            minor / unit,
+            minor % unit,
+            width = exponent as usize
+        )
+    }
+}
+
+impl fmt::Debug for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self} {}", self.currency)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// SQL `CASE` over a currency code column giving `then(exponent)` for the
+/// exponent of the row's currency. `then` is called once per branch, in
+/// the order the branches appear.
+pub fn sql_by_exponent(currency_column: &str, mut then: impl FnMut(u32) -> String) -> String {
+    let mut sql = "CASE".to_string();
+    for (exponent, codes) in EXPONENTS {
+        let codes: Vec<String> = codes.iter().map(|c| format!("'{c}'")).collect();
+        sql += &format!(
+            " WHEN upper(trim({currency_column})) IN ({}) THEN {}",
+            codes.join(", "),
+            then(*exponent)
+        );
+    }
+    sql + &format!(" ELSE {} END", then(2))
+}
+
+/// SQL `CASE` over a currency code column giving 10^`power(exponent)`.
+fn sql_powers(currency_column: &str, power: impl Fn(u32) -> u32) -> String {
+    sql_by_exponent(currency_column, |exponent| {
+        10_i64.pow(power(exponent)).to_string()
+    })
+}
+
+/// SQL expression for how many minor units make one major unit of the
+/// currency in `currency_column` (100 for USD, 1 for JPY).
+pub fn sql_minor_per_major(currency_column: &str) -> String {
+    sql_powers(currency_column, |exponent| exponent)
+}
+
+/// SQL expression for a minor-unit column in ten-thousandths of a major
+/// unit, whatever the currency, for comparing across currencies.
+pub fn sql_scaled(minor_column: &str, currency_column: &str) -> String {
+    format!(
+        "({minor_column} * {})",
+        sql_powers(currency_column, |exponent| MAX_EXPONENT - exponent)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_display_and_arithmetic() {
+        let usd = Currency::new("usd").unwrap();
+        let jpy = Currency::of(Some("JPY"));
+        let kwd = Currency::of(Some("KWD"));
+        assert_eq!(Currency::of(Some("US$")), Currency::NONE);
+        assert_eq!((usd.exponent(), jpy.exponent(), kwd.exponent()), (2, 0, 3));
+
+        let parse = |text, currency| Money::parse(text, currency).map(|m| m.minor);
+        assert_eq!(parse("2,540.00", usd), Some(254_000));
+        assert_eq!(parse("25.4", usd), Some(2540));
+        assert_eq!(parse(".5", usd), Some(50));
+        assert_eq!(parse("0.125", usd), Some(13));
+        assert_eq!(parse("-0.125", usd), Some(-13));
+        assert_eq!(parse("1500", jpy), Some(1500));
+        assert_eq!(parse("1500.6", jpy), Some(1501));
+        assert_eq!(parse("1.5", kwd), Some(1500));
+        assert_eq!(parse("12a", usd), None);
+        assert_eq!(parse("", usd), None);
+        assert_eq!(parse("99999999999999999999", usd), None);
+
+        assert_eq!(Money::new(254_000, usd).to_string(), "2540.00");
+        assert_eq!(Money::new(-5, usd).to_string(), "-0.05");
+        assert_eq!(Money::new(1500, jpy).to_string(), "1500");
+        assert_eq!(Money::new(1500, kwd).to_string(), "1.500");
+        assert_eq!(format!("{:?}", Money::new(1500, jpy)), "1500 JPY");
+        assert_eq!(
+            serde_json::to_string(&Money::new(2540, usd)).unwrap(),
+            "\"25.40\""
+        );
+
+        // 0.1 + 0.2 is exactly 0.3, and a line total splits into unit prices
+        let sum = Money::new(10, usd).checked_add(Money::new(20, usd));
+        assert_eq!(sum, Some(Money::new(30, usd)));
+        assert_eq!(Money::new(10, usd).checked_add(Money::new(20, jpy)), None);
+        assert_eq!(
+            Money::new(254_000, usd).divide(100),
+            Some(Money::new(2540, usd))
+        );
+        assert_eq!(Money::new(100, usd).divide(3), Some(Money::new(33, usd)));
+        assert_eq!(Money::new(-5, usd).divide(2), Some(Money::new(-3, usd)));
+        assert_eq!(
+            Money::new(2540, usd).times(100),
+            Some(Money::new(254_000, usd))
+        );
+
+        // 1500 JPY compares as 1500, not as 15.00
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let sql = |expr: String, currency: Option<&str>| -> i64 {
+            conn.query_row(&format!("SELECT {expr}"), [currency], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(sql(sql_minor_per_major("?1"), Some(" jpy")), 1);
+        assert_eq!(sql(sql_minor_per_major("?1"), None), 100);
+        assert_eq!(sql(sql_scaled("1500", "?1"), Some("JPY")), 15_000_000);
+        assert_eq!(sql(sql_scaled("149900", "?1"), Some("USD")), 14_990_000);
+        assert_eq!(sql(sql_scaled("1500", "?1"), Some("KWD")), 15_000);
+    }
+}
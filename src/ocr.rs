@@ -0,0 +1,133 @@
+// src/ocr.rs
+
+use crate::config::OcrConfig;
+use crate::render::{self, PageSelection, WorkDir};
+use std::fs;
+use std::process::Command;
+use tracing::debug;
+
+/// OCR result for a single rasterized page.
+#[derive(Debug, Clone)]
+pub struct OcrPage {
+    /// 1-based page number
+    pub page: u32,
+    pub text: String,
+    /// Mean word confidence reported by the engine, 0.0–1.0
+    pub confidence: f32,
+}
+
+/// Rasterize the selected pages of a PDF with `pdftoppm` and OCR them with
+/// `tesseract`. Encrypted PDFs must already be decrypted.
+pub fn ocr_pdf(
+    pdf_bytes: &[u8],
+    cfg: &OcrConfig,
+    selection: PageSelection,
+) -> Result<Vec<OcrPage>, Box<dyn std::error::Error>> {
+    let rendered = render::render_pages(pdf_bytes, &cfg.pdftoppm, cfg.dpi, selection)?;
+    let work = WorkDir::create()?;
+
+    let mut pages = Vec::with_capacity(rendered.len());
+    for rendered_page in rendered {
+        let page = rendered_page.page;
+        let image = work.path.join(format!("page-{page}.png"));
+        fs::write(&image, &rendered_page.png)?;
+
+        let tsv = render::run_tool(
+            Command::new(&cfg.tesseract)
+                .arg(&image)
+                .arg("stdout")
+                .arg("-l")
+                .arg(&cfg.lang)
+                .arg("tsv"),
+        )?;
+        let (text, confidence) = parse_tsv(&tsv);
+        debug!(
+            page,
+            chars = text.len(),
+            confidence = format!("{confidence:.2}"),
+            "OCR page"
+        );
+        pages.push(OcrPage {
+            page,
+            text,
+            confidence,
+        });
+    }
+
+    Ok(pages)
+}
+
+/// Join OCR'd pages into a single document, pages separated by a blank line.
+pub fn join_pages(pages: &[OcrPage]) -> String {
+    pages
+        .iter()
+        .map(|p| p.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Rebuild page text from tesseract's TSV output and compute the mean word
+/// confidence.
+///
+/// TSV columns: level, page_num, block_num, par_num, line_num, word_num,
+/// left, top, width, height, conf, text. Words (level 5) are joined with
+/// spaces, lines with newlines, and blocks with a blank line.
+fn parse_tsv(tsv: &str) -> (String, f32) {
+    let mut text = String::new();
+    let mut last_line: Option<(&str, &str, &str)> = None;
+    let mut conf_sum = 0.0f32;
+    let mut words = 0usize;
+
+    for row in tsv.lines().skip(1) {
+        let cols: Vec<&str> = row.splitn(12, '\t').collect();
+        if cols.len() < 12 || cols[0] != "5" {
+            continue;
+        }
+        let word = cols[11].trim();
+        let conf: f32 = cols[10].parse().unwrap_or(-1.0);
+        if word.is_empty() || conf < 0.0 {
+            continue;
+        }
+
+        let line = (cols[2], cols[3], cols[4]);
+        match last_line {
+            Some(prev) if prev == line => text.push(' '),
+            Some(prev) if prev.0 != line.0 => text.push_str("\n\n"),
+            Some(_) => text.push('\n'),
+            None => {}
+        }
+        text.push_str(word);
+        last_line = Some(line);
+
+        conf_sum += conf;
+        words += 1;
+    }
+
+    let confidence = if words == 0 {
+        0.0
+    } else {
+        conf_sum / words as f32 / 100.0
+    };
+    (text, confidence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tsv_lines_blocks_and_confidence() {
+        let tsv = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n\
+                   1\t1\t0\t0\t0\t0\t0\t0\t2480\t3508\t-1\t\n\
+                   5\t1\t1\t1\t1\t1\t10\t10\t100\t20\t96.5\tCOMMERCIAL\n\
+                   5\t1\t1\t1\t1\t2\t120\t10\t100\t20\t93.5\tINVOICE\n\
+                   5\t1\t1\t1\t2\t1\t10\t40\t100\t20\t90\tNo.\n\
+                   5\t1\t1\t1\t2\t2\t10\t40\t100\t20\t-1\t \n\
+                   5\t1\t2\t1\t1\t1\t10\t90\t100\t20\t80\tTOTAL\n";
+        let (text, confidence) = parse_tsv(tsv);
+        assert_eq!(text, "COMMERCIAL INVOICE\nNo.\n\nTOTAL");
+        assert!((confidence - 0.9).abs() < 1e-6);
+
+        assert_eq!(parse_tsv("level\tpage_num\n"), (String::new(), 0.0));
+    }
+}
@@ -0,0 +1,551 @@
+// src/pdf_extract/einvoice.rs
+
+use crate::heuristics::{InvoiceData, LineItem};
+use crate::money::{Currency, Money};
+use lopdf::{Dictionary, Document, Object};
+use roxmltree::Node;
+
+/// Structured invoice XML standard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// UN/CEFACT Cross Industry Invoice — Factur-X, ZUGFeRD, XRechnung (CII)
+    Cii,
+    /// OASIS UBL 2.x Invoice / CreditNote — XRechnung (UBL), Peppol
+    Ubl,
+}
+
+impl Syntax {
+    /// Lowercase name as recorded in `invoices.model`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cii => "cii",
+            Self::Ubl => "ubl",
+        }
+    }
+}
+
+/// An e-invoice XML found in a PDF's attachments, already mapped.
+#[derive(Debug)]
+pub struct EmbeddedInvoice {
+    /// Name of the embedded file, e.g. `factur-x.xml`
+    pub filename: String,
+    pub syntax: Syntax,
+    pub xml: String,
+    pub invoice: InvoiceData,
+}
+
+/// Depth limit for the `EmbeddedFiles` name tree (guards against cycles).
+const MAX_NAME_TREE_DEPTH: usize = 8;
+
+/// Look through the document's `EmbeddedFiles` name tree for a CII or UBL
+/// invoice. Other attachments and XML that is not an invoice are ignored.
+pub fn find_embedded_invoice(doc: &Document) -> Option<EmbeddedInvoice> {
+    let tree = doc
+        .catalog()
+        .ok()?
+        .get_deref(b"Names", doc)
+        .and_then(Object::as_dict)
+        .ok()?
+        .get_deref(b"EmbeddedFiles", doc)
+        .and_then(Object::as_dict)
+        .ok()?;
+
+    let mut specs = Vec::new();
+    collect_file_specs(doc, tree, 0, &mut specs);
+
+    specs.into_iter().find_map(|spec| {
+        let filename = spec_filename(spec)?;
+        if !filename.to_lowercase().ends_with(".xml") {
+            return None;
+        }
+        let stream = spec
+            .get_deref(b"EF", doc)
+            .and_then(Object::as_dict)
+            .and_then(|ef| {
+                ef.get_deref(b"UF", doc)
+                    .or_else(|_| ef.get_deref(b"F", doc))
+            })
+            .and_then(Object::as_stream)
+            .ok()?;
+        let bytes = stream.get_plain_content().ok()?;
+        let xml = String::from_utf8(bytes).ok()?;
+        let (syntax, invoice) = parse_invoice_xml(&xml).ok()?;
+        Some(EmbeddedInvoice {
+            filename,
+            syntax,
+            xml,
+            invoice,
+        })
+    })
+}
+
+/// File specification dictionaries from a name tree node: `Names` holds
+/// `[key spec key spec ...]` pairs, `Kids` holds child nodes.
+fn collect_file_specs<'a>(
+    doc: &'a Document,
+    node: &'a Dictionary,
+    depth: usize,
+    specs: &mut Vec<&'a Dictionary>,
+) {
+    if depth > MAX_NAME_TREE_DEPTH {
+        return;
+    }
+    if let Ok(names) = node.get_deref(b"Names", doc).and_then(Object::as_array) {
+        for value in names.iter().skip(1).step_by(2) {
+            if let Ok((_, spec)) = doc.dereference(value)
+                && let Ok(spec) = spec.as_dict()
+            {
+                specs.push(spec);
+            }
+        }
+    }
+    if let Ok(kids) = node.get_deref(b"Kids", doc).and_then(Object::as_array) {
+        for kid in kids {
+            if let Ok((_, kid)) = doc.dereference(kid)
+                && let Ok(kid) = kid.as_dict()
+            {
+                collect_file_specs(doc, kid, depth + 1, specs);
+            }
+        }
+    }
+}
+
+/// `UF` (Unicode) or `F` file name of a file specification.
+fn spec_filename(spec: &Dictionary) -> Option<String> {
+    let bytes = spec
+        .get(b"UF")
+        .or_else(|_| spec.get(b"F"))
+        .ok()?
+        .as_str()
+        .ok()?;
+    Some(match bytes {
+        [0xFE, 0xFF, rest @ ..] => {
+            let units: Vec<u16> = rest
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => bytes.iter().map(|&b| b as char).collect(),
+    })
+}
+
+/// Parse a CII or UBL invoice document into `InvoiceData`. Credit notes
+/// come back with a negative total and line amounts.
+pub fn parse_invoice_xml(xml: &str) -> Result<(Syntax, InvoiceData), String> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| format!("Invalid XML: {e}"))?;
+    let root = doc.root_element();
+    let (syntax, invoice, credit_note) = match root.tag_name().name() {
+        "CrossIndustryInvoice" => (
+            Syntax::Cii,
+            parse_cii(root),
+            is_credit_note_code(text(root, &["ExchangedDocument", "TypeCode"])),
+        ),
+        "Invoice" => (
+            Syntax::Ubl,
+            parse_ubl(root),
+            is_credit_note_code(text(root, &["InvoiceTypeCode"])),
+        ),
+        "CreditNote" => (Syntax::Ubl, parse_ubl(root), true),
+        other => return Err(format!("Not an e-invoice document: <{other}>")),
+    };
+    let invoice = if credit_note {
+        negate_credit(invoice)
+    } else {
+        invoice
+    };
+    Ok((syntax, invoice))
+}
+
+/// First descendant reached by following child elements named `path`
+/// (local names, any namespace).
+fn find<'a, 'i>(node: Node<'a, 'i>, path: &[&str]) -> Option<Node<'a, 'i>> {
+    path.iter().try_fold(node, |node, name| {
+        node.children()
+            .find(|c| c.is_element() && c.tag_name().name() == *name)
+    })
+}
+
+fn text(node: Node, path: &[&str]) -> Option<String> {
+    find(node, path)
+        .and_then(|n| n.text())
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+}
+
+fn amount(node: Node, path: &[&str], currency: Currency) -> Option<Money> {
+    text(node, path).and_then(|t| Money::parse(&t, currency))
+}
+
+fn children<'a, 'i>(node: Node<'a, 'i>, name: &'static str) -> impl Iterator<Item = Node<'a, 'i>> {
+    node.children()
+        .filter(move |c| c.is_element() && c.tag_name().name() == name)
+}
+
+/// UN/ECE unit codes for "piece" / "one" — quantities that count items.
+fn is_piece_unit(node: Option<Node>) -> bool {
+    node.and_then(|n| n.attribute("unitCode"))
+        .is_some_and(|code| matches!(code, "C62" | "H87" | "PCE" | "EA"))
+}
+
+/// Sum of line quantities when every line is counted in whole pieces.
+/// `None` when a quantity or the sum does not fit a `u32`.
+fn total_pieces(quantities: &[(f64, bool)]) -> Option<u32> {
+    if quantities.is_empty() {
+        return None;
+    }
+    quantities.iter().try_fold(0_u32, |sum, &(q, piece)| {
+        let whole = piece && q.fract() == 0.0 && (0.0..=f64::from(u32::MAX)).contains(&q);
+        whole.then(|| sum.checked_add(q as u32)).flatten()
+    })
+}
+
+/// UNTDID 1001 document type codes for credit notes.
+fn is_credit_note_code(code: Option<String>) -> bool {
+    matches!(code.as_deref(), Some("381" | "261" | "396"))
+}
+
+/// Credit notes state their amounts as positive refunds; store them as
+/// negatives so totals net them off the invoices they credit.
+fn negate_credit(mut invoice: InvoiceData) -> InvoiceData {
+    let negate = |m: Money| Money::new(m.minor.saturating_neg(), m.currency);
+    invoice.total_amount = invoice.total_amount.map(negate);
+    for item in &mut invoice.line_items {
+        item.amount = negate(item.amount);
+    }
+    invoice
+}
+
+/// CII `DateTimeString` in format 102 (`YYYYMMDD`) as `YYYY-MM-DD`; other
+/// formats are returned as-is.
+fn cii_date(raw: String) -> String {
+    if raw.len() == 8 && raw.bytes().all(|b| b.is_ascii_digit()) {
+        format!("{}-{}-{}", &raw[..4], &raw[4..6], &raw[6..])
+    } else {
+        raw
+    }
+}
+
+fn parse_cii(root: Node) -> InvoiceData {
+    let transaction = find(root, &["SupplyChainTradeTransaction"]);
+    let agreement = transaction.and_then(|t| find(t, &["ApplicableHeaderTradeAgreement"]));
+    let delivery = transaction.and_then(|t| find(t, &["ApplicableHeaderTradeDelivery"]));
+    let settlement = transaction.and_then(|t| find(t, &["ApplicableHeaderTradeSettlement"]));
+    let country =
+        |party: &str| agreement.and_then(|a| text(a, &[party, "PostalTradeAddress", "CountryID"]));
+    let currency_code = settlement.and_then(|s| text(s, &["InvoiceCurrencyCode"]));
+    let currency = Currency::of(currency_code.as_deref());
+
+    let mut quantities = Vec::new();
+    let line_items = transaction
+        .into_iter()
+        .flat_map(|t| children(t, "IncludedSupplyChainTradeLineItem"))
+        .map(|line| {
+            let billed = find(line, &["SpecifiedLineTradeDelivery", "BilledQuantity"]);
+            let qty: f64 = billed
+                .and_then(|b| b.text())
+                .and_then(|t| t.trim().parse().ok())
+                .unwrap_or(0.0);
+            quantities.push((qty, is_piece_unit(billed)));
+            LineItem {
+                description: text(line, &["SpecifiedTradeProduct", "Name"]).unwrap_or_default(),
+                qty: qty as u32,
+                unit_price: amount(
+                    line,
+                    &[
+                        "SpecifiedLineTradeAgreement",
+                        "NetPriceProductTradePrice",
+                        "ChargeAmount",
+                    ],
+                    currency,
+                )
+                .unwrap_or(Money::zero(currency)),
+                amount: amount(
+                    line,
+                    &[
+                        "SpecifiedLineTradeSettlement",
+                        "SpecifiedTradeSettlementLineMonetarySummation",
+                        "LineTotalAmount",
+                    ],
+                    currency,
+                )
+                .unwrap_or(Money::zero(currency)),
+            }
+        })
+        .collect();
+
+    let summation =
+        settlement.and_then(|s| find(s, &["SpecifiedTradeSettlementHeaderMonetarySummation"]));
+    InvoiceData {
+        vendor: agreement.and_then(|a| text(a, &["SellerTradeParty", "Name"])),
+        buyer: agreement.and_then(|a| text(a, &["BuyerTradeParty", "Name"])),
+        invoice_no: text(root, &["ExchangedDocument", "ID"]),
+        invoice_date: text(
+            root,
+            &["ExchangedDocument", "IssueDateTime", "DateTimeString"],
+        )
+        .map(cii_date),
+        currency: currency_code,
+        total_amount: summation.and_then(|s| {
+            amount(s, &["GrandTotalAmount"], currency)
+                .or_else(|| amount(s, &["DuePayableAmount"], currency))
+        }),
+        total_pieces: total_pieces(&quantities),
+        ship_from: delivery
+            .and_then(|d| {
+                text(
+                    d,
+                    &["ShipFromTradeParty", "PostalTradeAddress", "CountryID"],
+                )
+            })
+            .or_else(|| country("SellerTradeParty")),
+        ship_to: delivery
+            .and_then(|d| text(d, &["ShipToTradeParty", "PostalTradeAddress", "CountryID"]))
+            .or_else(|| country("BuyerTradeParty")),
+        shipping_method: None,
+        line_items,
+        packing_items: Vec::new(),
+        packing_totals: None,
+    }
+}
+
+fn parse_ubl(root: Node) -> InvoiceData {
+    let credit_note = root.tag_name().name() == "CreditNote";
+    let (line_tag, qty_tag) = if credit_note {
+        ("CreditNoteLine", "CreditedQuantity")
+    } else {
+        ("InvoiceLine", "InvoicedQuantity")
+    };
+    let party_name = |role: &str| {
+        text(
+            root,
+            &[role, "Party", "PartyLegalEntity", "RegistrationName"],
+        )
+        .or_else(|| text(root, &[role, "Party", "PartyName", "Name"]))
+    };
+
+    let currency_code = text(root, &["DocumentCurrencyCode"]);
+    let currency = Currency::of(currency_code.as_deref());
+
+    let mut quantities = Vec::new();
+    let line_items = children(root, line_tag)
+        .map(|line| {
+            let invoiced = find(line, &[qty_tag]);
+            let qty: f64 = invoiced
+                .and_then(|q| q.text())
+                .and_then(|t| t.trim().parse().ok())
+                .unwrap_or(0.0);
+            quantities.push((qty, is_piece_unit(invoiced)));
+            LineItem {
+                description: text(line, &["Item", "Name"])
+                    .or_else(|| text(line, &["Item", "Description"]))
+                    .unwrap_or_default(),
+                qty: qty as u32,
+                unit_price: amount(line, &["Price", "PriceAmount"], currency)
+                    .unwrap_or(Money::zero(currency)),
+                amount: amount(line, &["LineExtensionAmount"], currency)
+                    .unwrap_or(Money::zero(currency)),
+            }
+        })
+        .collect();
+
+    InvoiceData {
+        vendor: party_name("AccountingSupplierParty"),
+        buyer: party_name("AccountingCustomerParty"),
+        invoice_no: text(root, &["ID"]),
+        invoice_date: text(root, &["IssueDate"]),
+        currency: currency_code,
+        total_amount: amount(root, &["LegalMonetaryTotal", "PayableAmount"], currency).or_else(
+            || {
+                amount(
+                    root,
+                    &["LegalMonetaryTotal", "TaxInclusiveAmount"],
+                    currency,
+                )
+            },
+        ),
+        total_pieces: total_pieces(&quantities),
+        ship_from: text(
+            root,
+            &[
+                "AccountingSupplierParty",
+                "Party",
+                "PostalAddress",
+                "Country",
+                "IdentificationCode",
+            ],
+        ),
+        ship_to: text(
+            root,
+            &[
+                "Delivery",
+                "DeliveryLocation",
+                "Address",
+                "Country",
+                "IdentificationCode",
+            ],
+        )
+        .or_else(|| {
+            text(
+                root,
+                &[
+                    "AccountingCustomerParty",
+                    "Party",
+                    "PostalAddress",
+                    "Country",
+                    "IdentificationCode",
+                ],
+            )
+        }),
+        shipping_method: None,
+        line_items,
+        packing_items: Vec::new(),
+        packing_totals: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Stream, dictionary};
+
+    const CII: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rsm:CrossIndustryInvoice xmlns:rsm="urn:un:unece:uncefact:data:standard:CrossIndustryInvoice:100"
+    xmlns:ram="urn:un:unece:uncefact:data:standard:ReusableAggregateBusinessInformationEntity:100"
+    xmlns:udt="urn:un:unece:uncefact:data:standard:UnqualifiedDataType:100">
+  <rsm:ExchangedDocument>
+    <ram:ID>RE-2026-0042</ram:ID>
+    <ram:IssueDateTime><udt:DateTimeString format="102">20260216</udt:DateTimeString></ram:IssueDateTime>
+  </rsm:ExchangedDocument>
+  <rsm:SupplyChainTradeTransaction>
+    <ram:IncludedSupplyChainTradeLineItem>
+      <ram:SpecifiedTradeProduct><ram:Name>Kabelbinder 200mm</ram:Name></ram:SpecifiedTradeProduct>
+      <ram:SpecifiedLineTradeAgreement>
+        <ram:NetPriceProductTradePrice><ram:ChargeAmount>0.25</ram:ChargeAmount></ram:NetPriceProductTradePrice>
+      </ram:SpecifiedLineTradeAgreement>
+      <ram:SpecifiedLineTradeDelivery><ram:BilledQuantity unitCode="H87">400</ram:BilledQuantity></ram:SpecifiedLineTradeDelivery>
+      <ram:SpecifiedLineTradeSettlement>
+        <ram:SpecifiedTradeSettlementLineMonetarySummation><ram:LineTotalAmount>100.00</ram:LineTotalAmount></ram:SpecifiedTradeSettlementLineMonetarySummation>
+      </ram:SpecifiedLineTradeSettlement>
+    </ram:IncludedSupplyChainTradeLineItem>
+    <ram:ApplicableHeaderTradeAgreement>
+      <ram:SellerTradeParty><ram:Name>Muster GmbH</ram:Name>
+        <ram:PostalTradeAddress><ram:CountryID>DE</ram:CountryID></ram:PostalTradeAddress></ram:SellerTradeParty>
+      <ram:BuyerTradeParty><ram:Name>Acme SARL</ram:Name>
+        <ram:PostalTradeAddress><ram:CountryID>FR</ram:CountryID></ram:PostalTradeAddress></ram:BuyerTradeParty>
+    </ram:ApplicableHeaderTradeAgreement>
+    <ram:ApplicableHeaderTradeSettlement>
+      <ram:InvoiceCurrencyCode>EUR</ram:InvoiceCurrencyCode>
+      <ram:SpecifiedTradeSettlementHeaderMonetarySummation>
+        <ram:GrandTotalAmount>119.00</ram:GrandTotalAmount>
+      </ram:SpecifiedTradeSettlementHeaderMonetarySummation>
+    </ram:ApplicableHeaderTradeSettlement>
+  </rsm:SupplyChainTradeTransaction>
+</rsm:CrossIndustryInvoice>"#;
+
+    #[test]
+    fn test_embedded_cii_invoice() {
+        let mut doc = Document::with_version("1.7");
+        let file_id = doc.add_object(Stream::new(
+            dictionary! { "Type" => "EmbeddedFile" },
+            CII.as_bytes().to_vec(),
+        ));
+        let spec_id = doc.add_object(dictionary! {
+            "Type" => "Filespec",
+            "F" => Object::string_literal("factur-x.xml"),
+            "EF" => dictionary! { "F" => file_id },
+        });
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Names" => dictionary! {
+                "EmbeddedFiles" => dictionary! {
+                    "Names" => vec![Object::string_literal("factur-x.xml"), spec_id.into()],
+                },
+            },
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        let embedded = find_embedded_invoice(&doc).unwrap();
+        assert_eq!(embedded.filename, "factur-x.xml");
+        assert_eq!(embedded.syntax, Syntax::Cii);
+        let invoice = embedded.invoice;
+        assert_eq!(invoice.invoice_no.as_deref(), Some("RE-2026-0042"));
+        assert_eq!(invoice.invoice_date.as_deref(), Some("2026-02-16"));
+        assert_eq!(invoice.vendor.as_deref(), Some("Muster GmbH"));
+        assert_eq!(invoice.buyer.as_deref(), Some("Acme SARL"));
+        assert_eq!(invoice.currency.as_deref(), Some("EUR"));
+        let eur = |minor| Money::new(minor, Currency::of(Some("EUR")));
+        assert_eq!(invoice.total_amount, Some(eur(11_900)));
+        assert_eq!(invoice.total_pieces, Some(400));
+        assert_eq!(
+            (invoice.ship_from.as_deref(), invoice.ship_to.as_deref()),
+            (Some("DE"), Some("FR"))
+        );
+        assert_eq!(invoice.line_items.len(), 1);
+        assert_eq!(invoice.line_items[0].description, "Kabelbinder 200mm");
+        assert_eq!(
+            (invoice.line_items[0].qty, invoice.line_items[0].unit_price),
+            (400, eur(25))
+        );
+
+        let ubl = r#"<Invoice xmlns="urn:oasis:names:specification:ubl:schema:xsd:Invoice-2"
+            xmlns:cac="urn:oasis:names:specification:ubl:schema:xsd:CommonAggregateComponents-2"
+            xmlns:cbc="urn:oasis:names:specification:ubl:schema:xsd:CommonBasicComponents-2">
+          <cbc:ID>INV-7</cbc:ID><cbc:IssueDate>2026-03-01</cbc:IssueDate>
+          <cbc:DocumentCurrencyCode>EUR</cbc:DocumentCurrencyCode>
+          <cac:AccountingSupplierParty><cac:Party><cac:PartyLegalEntity>
+            <cbc:RegistrationName>Lieferant AG</cbc:RegistrationName></cac:PartyLegalEntity></cac:Party></cac:AccountingSupplierParty>
+          <cac:LegalMonetaryTotal><cbc:PayableAmount currencyID="EUR">59.50</cbc:PayableAmount></cac:LegalMonetaryTotal>
+          <cac:InvoiceLine><cbc:InvoicedQuantity unitCode="KGM">2.5</cbc:InvoicedQuantity>
+            <cbc:LineExtensionAmount currencyID="EUR">50.00</cbc:LineExtensionAmount>
+            <cac:Item><cbc:Name>Schrauben</cbc:Name></cac:Item>
+            <cac:Price><cbc:PriceAmount currencyID="EUR">20.00</cbc:PriceAmount></cac:Price></cac:InvoiceLine>
+        </Invoice>"#;
+        let (syntax, invoice) = parse_invoice_xml(ubl).unwrap();
+        assert_eq!(syntax, Syntax::Ubl);
+        assert_eq!(invoice.invoice_no.as_deref(), Some("INV-7"));
+        assert_eq!(invoice.vendor.as_deref(), Some("Lieferant AG"));
+        assert_eq!(invoice.total_amount, Some(eur(5950)));
+        assert_eq!(invoice.total_pieces, None);
+        assert_eq!(invoice.line_items[0].amount, eur(5000));
+
+        // A credit note (type 381) refunds: its amounts are stored negative
+        let credit = CII.replace(
+            "<ram:ID>RE-2026-0042</ram:ID>",
+            "<ram:ID>GS-2026-0003</ram:ID><ram:TypeCode>381</ram:TypeCode>",
+        );
+        let (_, invoice) = parse_invoice_xml(&credit).unwrap();
+        assert_eq!(invoice.total_amount, Some(eur(-11_900)));
+        assert_eq!(
+            (
+                invoice.line_items[0].unit_price,
+                invoice.line_items[0].amount
+            ),
+            (eur(25), eur(-10_000))
+        );
+        assert_eq!(invoice.total_pieces, Some(400));
+        let ubl_credit = ubl
+            .replace(
+                "<Invoice xmlns=\"urn:oasis:names:specification:ubl:schema:xsd:Invoice-2\"",
+                "<CreditNote xmlns=\"urn:oasis:names:specification:ubl:schema:xsd:CreditNote-2\"",
+            )
+            .replace("</Invoice>", "</CreditNote>")
+            .replace("InvoiceLine", "CreditNoteLine")
+            .replace("InvoicedQuantity", "CreditedQuantity");
+        let (_, invoice) = parse_invoice_xml(&ubl_credit).unwrap();
+        assert_eq!(invoice.total_amount, Some(eur(-5950)));
+        assert_eq!(invoice.line_items[0].amount, eur(-5000));
+
+        // Piece counts that overflow a u32 are unknown rather than wrapped
+        assert_eq!(total_pieces(&[(4e9, true), (4e9, true)]), None);
+        assert_eq!(total_pieces(&[(1e12, true)]), None);
+        assert_eq!(total_pieces(&[(-1.0, true)]), None);
+        assert_eq!(
+            total_pieces(&[(4e9, true), (2.0, true)]),
+            Some(4_000_000_002)
+        );
+
+        assert!(parse_invoice_xml("<Order/>").is_err());
+    }
+}
@@ -0,0 +1,165 @@
+// src/pdf_extract/metadata.rs
+
+use crate::message_db::sha256_hex;
+use lopdf::{Document, Object};
+
+/// Document-level facts about a PDF, recorded on its attachment row.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PdfMetadata {
+    /// `None` when the PDF could not be parsed (or stays encrypted)
+    pub page_count: Option<u32>,
+    /// Header version, e.g. "1.7"
+    pub version: Option<String>,
+    /// Info dictionary `Producer`: the library that wrote the file
+    pub producer: Option<String>,
+    /// Info dictionary `Creator`: the application the document came from
+    pub creator: Option<String>,
+    /// `CreationDate` / `ModDate` as ISO 8601 when they parse, else verbatim
+    pub created: Option<String>,
+    pub modified: Option<String>,
+    pub encrypted: bool,
+    pub byte_size: usize,
+    /// Hex SHA-256 of the raw bytes; equal for the same file re-sent
+    pub sha256: String,
+}
+
+/// Read metadata from a PDF's bytes and, when it could be loaded, the
+/// parsed document. `encrypted` comes from the loader because decrypting
+/// removes the trailer's `Encrypt` entry.
+pub fn read(pdf_bytes: &[u8], doc: Option<&Document>, encrypted: bool) -> PdfMetadata {
+    let mut meta = PdfMetadata {
+        encrypted,
+        byte_size: pdf_bytes.len(),
+        sha256: sha256_hex(pdf_bytes),
+        ..Default::default()
+    };
+    let Some(doc) = doc else {
+        return meta;
+    };
+
+    meta.page_count = Some(doc.get_pages().len() as u32);
+    meta.version = Some(doc.version.clone());
+    let info = doc
+        .trailer
+        .get(b"Info")
+        .ok()
+        .and_then(|o| doc.dereference(o).ok())
+        .and_then(|(_, o)| o.as_dict().ok());
+    if let Some(info) = info {
+        // lopdf only decrypts top-level strings, so an encrypted file's Info
+        // entries would come out as ciphertext
+        let entry = |key: &[u8]| {
+            (!encrypted)
+                .then(|| info.get(key).ok())
+                .flatten()
+                .and_then(|o| doc.dereference(o).ok())
+                .and_then(|(_, o)| text_string(o))
+        };
+        meta.producer = entry(b"Producer");
+        meta.creator = entry(b"Creator");
+        meta.created = entry(b"CreationDate").map(|d| iso_date(&d));
+        meta.modified = entry(b"ModDate").map(|d| iso_date(&d));
+    }
+    meta
+}
+
+/// A PDF text string: UTF-16BE with a byte order mark, else PDFDocEncoding
+/// (read as Latin-1, which matches it for printable characters).
+fn text_string(obj: &Object) -> Option<String> {
+    let Object::String(bytes, _) = obj else {
+        return None;
+    };
+    let text = match bytes.strip_prefix(&[0xFE, 0xFF]) {
+        Some(utf16) => String::from_utf16_lossy(
+            &utf16
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        None => bytes.iter().map(|&b| b as char).collect(),
+    };
+    let text = text.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// `D:YYYYMMDDHHmmSSOHH'mm'` → `YYYY-MM-DDTHH:mm:SS±HH:mm`. Missing trailing
+/// fields default as the spec says (month and day 01, time 00, UTC unknown).
+/// Anything that doesn't parse is returned unchanged.
+fn iso_date(raw: &str) -> String {
+    let s = raw.trim().trim_start_matches("D:");
+    let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+    if digits < 4 || digits % 2 != 0 || digits > 14 {
+        return raw.to_string();
+    }
+    let field = |i: usize, default: &str| {
+        s.get(i..i + 2)
+            .filter(|_| i + 2 <= digits)
+            .unwrap_or(default)
+            .to_string()
+    };
+    let mut out = format!(
+        "{}-{}-{}T{}:{}:{}",
+        &s[..4],
+        field(4, "01"),
+        field(6, "01"),
+        field(8, "00"),
+        field(10, "00"),
+        field(12, "00"),
+    );
+
+    let zone = &s[digits..];
+    match zone.chars().next() {
+        Some('Z') => out.push('Z'),
+        Some(sign @ ('+' | '-')) => {
+            let offset: Vec<&str> = zone[1..].split('\'').filter(|p| !p.is_empty()).collect();
+            match offset.as_slice() {
+                [h, m, ..] if h.len() == 2 && m.len() == 2 => {
+                    out.push_str(&format!("{sign}{h}:{m}"))
+                }
+                [h] if h.len() == 2 => out.push_str(&format!("{sign}{h}:00")),
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    #[test]
+    fn test_info_dictionary_and_fingerprint() {
+        let mut doc = Document::with_version("1.6");
+        let info_id = doc.add_object(dictionary! {
+            "Producer" => Object::string_literal("Soft Source ERP"),
+            // UTF-16BE with a BOM
+            "Creator" => Object::String(
+                vec![0xFE, 0xFF, 0x00, b'W', 0x00, b'o', 0x00, b'r', 0x00, b'd'],
+                lopdf::StringFormat::Literal,
+            ),
+            "CreationDate" => Object::string_literal("D:20260216093005+08'00'"),
+            "ModDate" => Object::string_literal("D:2026"),
+        });
+        doc.trailer.set("Info", info_id);
+
+        let bytes = b"%PDF-1.6 stand-in";
+        let meta = read(bytes, Some(&doc), false);
+        assert_eq!(meta.page_count, Some(0));
+        assert_eq!(meta.version.as_deref(), Some("1.6"));
+        assert_eq!(meta.producer.as_deref(), Some("Soft Source ERP"));
+        assert_eq!(meta.creator.as_deref(), Some("Word"));
+        assert_eq!(meta.created.as_deref(), Some("2026-02-16T09:30:05+08:00"));
+        assert_eq!(meta.modified.as_deref(), Some("2026-01-01T00:00:00"));
+        assert_eq!(meta.byte_size, bytes.len());
+        assert_eq!(meta.sha256, sha256_hex(bytes));
+        assert_eq!(iso_date("yesterday"), "yesterday");
+
+        // Unreadable PDFs still get a fingerprint
+        let meta = read(bytes, None, true);
+        assert!(meta.encrypted && meta.producer.is_none());
+        assert_eq!(meta.sha256.len(), 64);
+    }
+}
@@ -0,0 +1,1241 @@
+// src/pdf_extract/mod.rs
+
+pub mod einvoice;
+pub mod metadata;
+pub mod segment;
+pub mod tables;
+
+use crate::config::{LlmBackend, LlmSection, OcrConfig, PdfConfig, ProfileConfig, TextMode};
+use crate::heuristics::{self, HeuristicTemplate, InvoiceData};
+use crate::layout;
+use crate::llm_extract;
+use crate::message_db::{MessageStore, StoredAttachment};
+use crate::money::Currency;
+use crate::ocr;
+use crate::render::{self, PageSelection};
+use lopdf::Document;
+use lopdf::encryption::DecryptionError;
+use segment::{PageRange, Segment};
+use std::borrow::Cow;
+use tracing::{info, warn};
+
+/// How a single page was classified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    /// Has extractable text
+    Text,
+    /// Image-only (or nearly) — needs OCR / vision model
+    Scanned,
+    /// No text and no images, e.g. a blank separator page
+    Empty,
+    /// Text extraction failed and there is nothing to OCR
+    Error,
+}
+
+impl PageKind {
+    /// Lowercase name as recorded in `attachment_pages.kind`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Scanned => "scanned",
+            Self::Empty => "empty",
+            Self::Error => "error",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(Self::Text),
+            "scanned" => Some(Self::Scanned),
+            "empty" => Some(Self::Empty),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Classification and text of one PDF page.
+#[derive(Debug, Clone)]
+pub struct PageContent {
+    /// 1-based page number
+    pub page: u32,
+    pub kind: PageKind,
+    /// Extracted text for `Text` pages, the error message for `Error` pages
+    pub text: Option<String>,
+}
+
+/// Backend recorded for invoices read from embedded Factur-X / ZUGFeRD /
+/// XRechnung XML.
+pub const EINVOICE_BACKEND: &str = "einvoice";
+
+/// Minimum number of non-whitespace characters we expect from a "real"
+/// text page. Pages with images and less text than this are treated as
+/// scanned (e.g. a scan with a small text stamp).
+const MIN_TEXT_CHARS: usize = 30;
+
+/// Content type recorded for PDFs that none of the known passwords open.
+pub const ENCRYPTED: &str = "encrypted";
+
+/// Why a PDF could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// Encrypted, and neither the empty password nor any configured one works
+    Encrypted,
+    Invalid(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Encrypted => write!(f, "PDF is encrypted and no known password opens it"),
+            Self::Invalid(e) => write!(f, "Failed to parse PDF: {e}"),
+        }
+    }
+}
+
+/// Parse raw PDF bytes. Encrypted PDFs are opened with the empty user
+/// password (owner-restricted statements) or else the first of `passwords`
+/// that works.
+pub fn load_pdf(pdf_bytes: &[u8], passwords: &[&str]) -> Result<Document, LoadError> {
+    open_pdf(pdf_bytes, passwords).map(|(doc, _)| doc)
+}
+
+/// `load_pdf`, also returning the password that decrypted the file: `None`
+/// if it was not encrypted, `Some("")` if the empty user password opened it.
+fn open_pdf<'a>(
+    pdf_bytes: &[u8],
+    passwords: &[&'a str],
+) -> Result<(Document, Option<&'a str>), LoadError> {
+    let mut doc = match Document::load_mem(pdf_bytes) {
+        Ok(doc) => doc,
+        // Encrypted object streams can't be parsed before decryption
+        Err(_) if contains(pdf_bytes, b"/Encrypt") => return Err(LoadError::Encrypted),
+        Err(e) => return Err(LoadError::Invalid(e.to_string())),
+    };
+    if !doc.is_encrypted() {
+        return Ok((doc, None));
+    }
+
+    for password in std::iter::once("").chain(passwords.iter().copied()) {
+        // A wrong password is rejected before anything is decrypted
+        match doc.decrypt(password) {
+            Ok(()) => {
+                info!(empty_password = password.is_empty(), "Decrypted PDF");
+                // Plain text; stops pdf-extract treating it as still encrypted
+                doc.trailer.remove(b"Encrypt");
+                return Ok((doc, Some(password)));
+            }
+            Err(lopdf::Error::Decryption(DecryptionError::IncorrectPassword)) => {}
+            Err(e) => {
+                // e.g. AES (V4/V5), which lopdf can't decrypt — no password will help
+                warn!(error = %e, "Cannot decrypt PDF");
+                break;
+            }
+        }
+    }
+    Err(LoadError::Encrypted)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Main entry point: classify every page of a parsed PDF, laying out the
+/// text of text pages according to `mode`.
+fn classify_pages(doc: &Document, mode: TextMode) -> Vec<PageContent> {
+    let pages: Vec<PageContent> = doc
+        .get_pages()
+        .into_iter()
+        .map(|(page, object_id)| classify_page(doc, page, object_id, mode))
+        .collect();
+
+    let count = |kind| pages.iter().filter(|p| p.kind == kind).count();
+    info!(
+        total_pages = pages.len(),
+        text = count(PageKind::Text),
+        scanned = count(PageKind::Scanned),
+        empty = count(PageKind::Empty),
+        error = count(PageKind::Error),
+        "Per-page classification"
+    );
+
+    pages
+}
+
+/// Extract one page's text and decide whether it is text, scanned or empty.
+fn classify_page(
+    doc: &Document,
+    page: u32,
+    object_id: lopdf::ObjectId,
+    mode: TextMode,
+) -> PageContent {
+    let has_images = page_has_images(doc, object_id);
+
+    // pdf-extract panics on some malformed fonts; contain it to this page
+    let extracted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        if mode == TextMode::Layout {
+            match layout::page_text(doc, page, object_id) {
+                Ok(text) => return Ok(text),
+                Err(e) => warn!(page, error = %e, "Layout extraction failed — using plain text"),
+            }
+        }
+        let mut text = String::new();
+        pdf_extract::output_doc_page(doc, &mut pdf_extract::PlainTextOutput::new(&mut text), page)
+            .map(|()| text)
+    }));
+
+    let (kind, text) = match extracted {
+        Ok(Ok(text)) => {
+            let chars = text.chars().filter(|c| !c.is_whitespace()).count();
+            if chars >= MIN_TEXT_CHARS || (chars > 0 && !has_images) {
+                (PageKind::Text, Some(text))
+            } else if has_images {
+                (PageKind::Scanned, None)
+            } else {
+                (PageKind::Empty, None)
+            }
+        }
+        Ok(Err(_)) | Err(_) if has_images => (PageKind::Scanned, None),
+        Ok(Err(e)) => (
+            PageKind::Error,
+            Some(format!("Text extraction failed: {e}")),
+        ),
+        Err(_) => (
+            PageKind::Error,
+            Some("Text extraction panicked".to_string()),
+        ),
+    };
+
+    if kind == PageKind::Error {
+        warn!(page, "Failed to extract page text");
+    }
+    PageContent { page, kind, text }
+}
+
+/// Whether a page's resources (inherited from parent page-tree nodes if
+/// needed) include any XObjects — for scans, the page image.
+fn page_has_images(doc: &Document, object_id: lopdf::ObjectId) -> bool {
+    let mut node = doc.get_dictionary(object_id).ok();
+    while let Some(dict) = node {
+        if let Ok(resources) = dict.get(b"Resources") {
+            return doc
+                .dereference(resources)
+                .ok()
+                .and_then(|(_, resolved)| resolved.as_dict().ok())
+                .and_then(|res| res.get(b"XObject").ok())
+                .and_then(|x| doc.dereference(x).ok())
+                .and_then(|(_, resolved)| resolved.as_dict().ok())
+                .is_some_and(|xobjs| !xobjs.is_empty());
+        }
+        node = dict
+            .get(b"Parent")
+            .and_then(|p| p.as_reference())
+            .and_then(|id| doc.get_dictionary(id))
+            .ok();
+    }
+    false
+}
+
+/// Every page's text positioned and grouped into lines, for reading grids
+/// from. Built once per attachment and shared by its segments.
+fn document_lines(doc: &Document) -> Vec<layout::Line> {
+    let runs: Vec<layout::TextRun> = doc
+        .get_pages()
+        .into_iter()
+        .filter_map(|(page, object_id)| layout::extract_runs(doc, page, object_id).ok())
+        .flatten()
+        .collect();
+    layout::group_lines(&runs)
+}
+
+/// [`document_lines`] of a stored PDF; empty when it can't be opened.
+fn pdf_lines(pdf_bytes: &[u8], passwords: &[&str]) -> Vec<layout::Line> {
+    load_pdf(pdf_bytes, passwords)
+        .map(|doc| document_lines(&doc))
+        .unwrap_or_default()
+}
+
+/// Line-item and packing grids reconstructed from the positions of the
+/// PDF's text, on `pages` only if given, with prices in `currency`. Empty
+/// for scans and for PDFs without recognisable headers.
+pub fn pdf_tables(
+    lines: &[layout::Line],
+    pages: Option<PageRange>,
+    currency: Currency,
+) -> tables::Tables {
+    let Some(range) = pages else {
+        return tables::detect_tables(lines, currency);
+    };
+    let lines: Vec<layout::Line> = lines
+        .iter()
+        .filter(|l| (range.first..=range.last).contains(&l.page))
+        .cloned()
+        .collect();
+    tables::detect_tables(&lines, currency)
+}
+
+/// Heuristic extraction, with line items and packing rows read from the
+/// PDF's grids where it has them instead of paired up from regex matches.
+fn extract_with_tables(
+    template: HeuristicTemplate,
+    text: &str,
+    lines: &[layout::Line],
+    pages: Option<PageRange>,
+) -> InvoiceData {
+    let mut invoice = heuristics::extract_invoice_with(template, text);
+    let currency = Currency::of(invoice.currency.as_deref());
+    let tables = pdf_tables(lines, pages, currency);
+    if !tables.line_items.is_empty() {
+        invoice.line_items = tables.line_items;
+    }
+    if !tables.packing_items.is_empty() {
+        invoice.packing_items = tables.packing_items;
+    }
+    invoice
+}
+
+/// Configured passwords for the sender of an attachment's message.
+fn attachment_passwords<'a>(
+    db: &MessageStore,
+    att: &StoredAttachment,
+    pdf_config: &'a PdfConfig,
+) -> rusqlite::Result<Vec<&'a str>> {
+    if pdf_config.passwords.is_empty() {
+        return Ok(Vec::new());
+    }
+    let from = db
+        .get_message_by_uid(&att.message_uid)?
+        .and_then(|m| m.from_addr);
+    Ok(pdf_config.passwords_for(from.as_deref()))
+}
+
+/// An attachment's PDF for external tools such as `pdftoppm`: decrypted
+/// here when a configured password opens it, so the password never goes
+/// on their command line. The stored bytes otherwise.
+pub fn pdf_for_tools<'b>(
+    db: &MessageStore,
+    att: &StoredAttachment,
+    pdf_config: &PdfConfig,
+    pdf_bytes: &'b [u8],
+) -> rusqlite::Result<Cow<'b, [u8]>> {
+    let passwords = attachment_passwords(db, att, pdf_config)?;
+    if passwords.is_empty() {
+        return Ok(Cow::Borrowed(pdf_bytes));
+    }
+    Ok(match open_pdf(pdf_bytes, &passwords) {
+        Ok((mut doc, Some(password))) if !password.is_empty() => {
+            decrypted_copy(&mut doc).map_or(Cow::Borrowed(pdf_bytes), Cow::Owned)
+        }
+        _ => Cow::Borrowed(pdf_bytes),
+    })
+}
+
+/// A document `open_pdf` decrypted, saved back out as a plain PDF.
+fn decrypted_copy(doc: &mut Document) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match doc.save_to(&mut bytes) {
+        Ok(()) => Some(bytes),
+        Err(e) => {
+            warn!(error = %e, "Failed to write decrypted PDF");
+            None
+        }
+    }
+}
+
+/// Attachment-level content type for a set of classified pages: `mixed`
+/// if some pages have text and others need OCR, `scanned` if only OCR can
+/// read it, `text` if any page has text, `empty` if the pages are blank,
+/// else `error`. Mixed attachments are extracted from their text pages
+/// straight away and become `ocr` once the scans are read.
+pub fn document_kind(pages: &[PageContent]) -> &'static str {
+    let has = |kind| pages.iter().any(|p| p.kind == kind);
+    if has(PageKind::Scanned) && has(PageKind::Text) {
+        "mixed"
+    } else if has(PageKind::Scanned) {
+        "scanned"
+    } else if has(PageKind::Text) {
+        "text"
+    } else if !pages.is_empty() && pages.iter().all(|p| p.kind == PageKind::Empty) {
+        "empty"
+    } else {
+        "error"
+    }
+}
+
+/// Text of the `Text` pages, in page order, separated by blank lines.
+pub fn joined_text(pages: &[PageContent]) -> String {
+    pages
+        .iter()
+        .filter(|p| p.kind == PageKind::Text)
+        .filter_map(|p| p.text.as_deref())
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Process all unprocessed PDF attachments.
+///
+/// Attachments of messages labelled by one of `profiles` are extracted with
+/// that profile's strategy; when `include_unlabelled` is set, the remaining
+/// attachments are extracted with the default `llm_config`. Attachments
+/// that already have an invoice are skipped unless `reextract` is set.
+pub async fn process_pdfs(
+    db: &MessageStore,
+    llm_config: &LlmSection,
+    ocr_config: &OcrConfig,
+    pdf_config: &PdfConfig,
+    profiles: &[&ProfileConfig],
+    include_unlabelled: bool,
+    reextract: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let redated = db.normalize_invoice_dates()?;
+    if redated > 0 {
+        info!(
+            count = redated,
+            "Re-read invoice dates with the configured day order"
+        );
+    }
+
+    let (total_msgs, processed_msgs, total_pdfs, processed_pdfs) = db.get_counts()?;
+    info!(
+        messages_total = total_msgs,
+        messages_processed = processed_msgs,
+        pdfs_total = total_pdfs,
+        pdfs_processed = processed_pdfs,
+        "Database statistics"
+    );
+
+    run_pdf_extraction(db, pdf_config)?;
+    run_ocr(db, ocr_config, pdf_config)?;
+
+    for profile in profiles {
+        let span = tracing::info_span!("profile", name = %profile.name);
+        let _guard = span.enter();
+
+        let mut profile_llm = llm_config.clone();
+        if let Some(backend) = &profile.extraction.backend {
+            profile_llm.backend = backend.clone();
+        }
+        run_extraction(
+            db,
+            Some(profile.label()),
+            &profile_llm,
+            profile.extraction.template,
+            pdf_config,
+            reextract,
+        )
+        .await?;
+    }
+
+    if include_unlabelled {
+        run_extraction(
+            db,
+            None,
+            llm_config,
+            HeuristicTemplate::default(),
+            pdf_config,
+            reextract,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Extract invoices from the attachments labelled `label` (or unlabelled)
+/// with the configured backend, falling back to the heuristics `template` if
+/// the LLM is unavailable. Scanned attachments that OCR could not handle go
+/// to the vision model when one is enabled. Only attachments without an
+/// invoice are extracted, unless `reextract` is set.
+async fn run_extraction(
+    db: &MessageStore,
+    label: Option<&str>,
+    llm_config: &LlmSection,
+    template: HeuristicTemplate,
+    pdf_config: &PdfConfig,
+    reextract: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let attachments = db.get_text_attachments_for_label(label, reextract)?;
+    let attachments = attachments.as_slice();
+
+    if llm_config.backend != LlmBackend::Heuristics && llm_config.vision.enabled {
+        let scanned = db.get_scanned_attachments_for_label(label, reextract)?;
+        if !scanned.is_empty()
+            && let Err(e) =
+                llm_extract::run_vision_extraction(db, &scanned, llm_config, pdf_config).await
+        {
+            warn!(error = %e, "Vision extraction failed — scanned attachments left for later");
+        }
+    }
+
+    if attachments.is_empty() {
+        return Ok(());
+    }
+
+    match llm_config.backend {
+        LlmBackend::Heuristics => {
+            info!("Backend set to heuristics — using regex extraction");
+            run_heuristics(db, attachments, template, pdf_config)?;
+        }
+        _ => {
+            info!(backend = ?llm_config.backend, "Using LLM-based extraction");
+            match llm_extract::run_llm_extraction(db, attachments, llm_config).await {
+                Ok(()) => {}
+                Err(e) => {
+                    warn!(error = %e, "LLM extraction failed — falling back to heuristics");
+                    run_heuristics(db, attachments, template, pdf_config)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Test extraction + LLM on a single attachment by its DB id.
+///
+/// Usage: `cargo run -- extract --attachment <id>`
+pub async fn test_single_pdf(
+    db: &MessageStore,
+    att_id: i64,
+    llm_config: &LlmSection,
+    pdf_config: &PdfConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(att_id = att_id, "Testing single PDF attachment");
+
+    let att = db
+        .get_attachment_by_id(att_id)?
+        .ok_or_else(|| format!("No attachment found with id {att_id}"))?;
+
+    info!(
+        id = att_id,
+        filename = %att.filename,
+        content_type = ?att.content_type,
+        has_text = att.extracted_text.is_some(),
+        sha256 = %att.pdf_data.sha256,
+        "Loaded attachment from DB"
+    );
+
+    if let Some(meta) = db.get_attachment_metadata(att_id)? {
+        let or_dash = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+        println!("\n--- Metadata ---");
+        println!(
+            "pages {}, PDF {}, {} bytes{}",
+            meta.page_count.map_or("-".to_string(), |n| n.to_string()),
+            or_dash(&meta.version),
+            meta.byte_size,
+            if meta.encrypted { ", encrypted" } else { "" }
+        );
+        println!("producer: {}", or_dash(&meta.producer));
+        println!("creator:  {}", or_dash(&meta.creator));
+        println!("created:  {}", or_dash(&meta.created));
+        println!("modified: {}", or_dash(&meta.modified));
+        println!("sha256:   {}", meta.sha256);
+        if let Some(original) = db.find_attachment_by_sha256(&meta.sha256, att_id)? {
+            println!("same file as attachment {original}");
+        }
+        println!("--- End ---");
+    }
+
+    // Phase 1: text extraction (re-run even if already done, for testing)
+    let passwords = attachment_passwords(db, &att, pdf_config)?;
+    let pdf_bytes = att.pdf_data.load(db)?;
+    let (mut doc, password) = match open_pdf(pdf_bytes, &passwords) {
+        Ok((doc, password)) => (doc, password.filter(|p| !p.is_empty())),
+        Err(e) => {
+            tracing::error!(error = %e, "PDF extraction failed");
+            println!("\n✗ Error: {e}\n");
+            return Ok(());
+        }
+    };
+    if let Some(embedded) = einvoice::find_embedded_invoice(&doc) {
+        println!(
+            "\n--- Embedded e-invoice ({}, {}) ---",
+            embedded.filename,
+            embedded.syntax.as_str()
+        );
+        println!("{}", serde_json::to_string_pretty(&embedded.invoice)?);
+        println!("--- End (exact data — heuristics and LLM skipped) ---\n");
+        return Ok(());
+    }
+    let pages = classify_pages(&doc, pdf_config.text_mode);
+    println!("\n--- Pages ---");
+    for page in &pages {
+        println!("page {}: {}", page.page, page.kind.as_str());
+    }
+    println!("--- End ---\n");
+
+    let text = if att.content_type.as_deref() == Some("ocr") {
+        info!("PDF has scanned pages — using stored OCR text");
+        for (page, confidence) in db.get_ocr_confidence(att_id)? {
+            println!("page {page}: OCR confidence {confidence:.2}");
+        }
+        att.extracted_text.clone().unwrap_or_default()
+    } else {
+        joined_text(&pages)
+    };
+
+    let extracted_text = if !text.is_empty() {
+        info!(chars = text.len(), "Extracted text from PDF");
+        println!("\n--- Extracted Text (first 2000 chars) ---");
+        println!("{}", &text[..text.floor_char_boundary(2000)]);
+        println!("--- End ---\n");
+        Some(text.as_str())
+    } else if document_kind(&pages) == "scanned" {
+        info!("PDF is scanned — no text to extract");
+        println!("\n⚠ PDF is scanned/image-only — cannot extract text.\n");
+        if llm_config.backend != LlmBackend::Heuristics && llm_config.vision.enabled {
+            println!("--- Vision Extraction ({:?}) ---", llm_config.backend);
+            let decrypted = password.and_then(|_| decrypted_copy(&mut doc));
+            let pdf_bytes = decrypted.as_deref().unwrap_or(pdf_bytes);
+            match llm_extract::run_vision_extraction_single(pdf_bytes, llm_config).await {
+                Ok(invoice) => {
+                    let (filled, total) = invoice.coverage();
+                    println!("{}", serde_json::to_string_pretty(&invoice)?);
+                    println!("--- End Vision ({filled}/{total} fields) ---\n");
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Vision extraction failed");
+                    println!("✗ Vision error: {e}\n");
+                }
+            }
+        }
+        None
+    } else {
+        println!("\n⚠ PDF has no extractable text.\n");
+        None
+    };
+
+    if extracted_text.is_none() {
+        return Ok(());
+    }
+
+    let lines = document_lines(&doc);
+    let segments = if att.content_type.as_deref() == Some("ocr") {
+        segment::attachment_segments(db, &att)?
+    } else {
+        let texts: Vec<(u32, &str)> = pages
+            .iter()
+            .filter(|p| p.kind == PageKind::Text)
+            .filter_map(|p| p.text.as_deref().map(|t| (p.page, t)))
+            .collect();
+        segment::split_documents(&texts)
+    };
+
+    for seg in &segments {
+        if let Some(pages) = seg.pages
+            && segments.len() > 1
+        {
+            println!("=== Document: pages {}-{} ===\n", pages.first, pages.last);
+        }
+
+        // Phase 2: heuristic extraction
+        println!("--- Heuristic Extraction ---");
+        let invoice =
+            extract_with_tables(HeuristicTemplate::default(), &seg.text, &lines, seg.pages);
+        let (filled, total) = invoice.coverage();
+        info!(filled, total, "Heuristic coverage");
+        println!("{}", serde_json::to_string_pretty(&invoice)?);
+        println!("--- End Heuristics ({filled}/{total} fields) ---\n");
+
+        // Phase 3: LLM extraction
+        match llm_config.backend {
+            LlmBackend::Heuristics => {
+                info!("Backend set to heuristics — skipping LLM");
+            }
+            _ => {
+                println!(
+                    "--- LLM Extraction ({:?} / {}) ---",
+                    llm_config.backend,
+                    match llm_config.backend {
+                        LlmBackend::Ollama => &llm_config.ollama.model,
+                        LlmBackend::Cliproxy => &llm_config.cliproxy.model,
+                        LlmBackend::Remote => &llm_config.remote.model,
+                        LlmBackend::Heuristics => unreachable!(),
+                    }
+                );
+                match llm_extract::run_llm_extraction_single(&seg.text, llm_config).await {
+                    Ok(invoice) => {
+                        let (filled, total) = invoice.coverage();
+                        println!("{}", serde_json::to_string_pretty(&invoice)?);
+                        println!("--- End LLM ({filled}/{total} fields) ---\n");
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, "LLM extraction failed");
+                        println!("✗ LLM error: {e}\n");
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Iterate over unprocessed attachments, classify them, and persist results.
+/// Attachments left `encrypted` by an earlier run are retried, so adding a
+/// password to the config is enough to pick them up. So are `error` ones
+/// whose metadata shows an encrypted PDF, as older builds stored those.
+pub fn run_pdf_extraction(
+    db: &MessageStore,
+    pdf_config: &PdfConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    backfill_metadata(db, pdf_config)?;
+
+    let mut unprocessed = db.get_unprocessed_attachments()?;
+    let encrypted = db.get_encrypted_attachments()?;
+    info!(
+        count = unprocessed.len(),
+        encrypted = encrypted.len(),
+        "Unprocessed attachments to extract"
+    );
+    unprocessed.extend(encrypted);
+
+    for att in &unprocessed {
+        let att_id = att.id.expect("attachment must have an id from DB");
+        let span = tracing::info_span!("pdf", filename = %att.filename);
+        let _guard = span.enter();
+
+        let passwords = attachment_passwords(db, att, pdf_config)?;
+        let pdf_bytes = att.pdf_data.read(db)?;
+        let opened = open_pdf(&pdf_bytes, &passwords);
+        let meta = pdf_metadata(&pdf_bytes, &opened);
+        info!(
+            pages = ?meta.page_count,
+            producer = ?meta.producer,
+            sha256 = %meta.sha256,
+            "PDF metadata"
+        );
+        db.set_attachment_metadata(att_id, &meta)?;
+        if let Some(original) = db.find_attachment_by_sha256(&meta.sha256, att_id)? {
+            info!(original, "Same file as an earlier attachment");
+        }
+
+        let doc = match opened {
+            Ok((doc, _)) => doc,
+            Err(LoadError::Encrypted) => {
+                warn!(
+                    tried = passwords.len() + 1,
+                    "PDF is encrypted — add the sender's password under [[pdf.passwords]]"
+                );
+                db.set_attachment_extraction(att_id, ENCRYPTED, None)?;
+                continue;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to process PDF");
+                db.set_attachment_extraction(att_id, "error", Some(&e.to_string()))?;
+                continue;
+            }
+        };
+
+        // Factur-X / ZUGFeRD / XRechnung: the XML is the invoice, exactly
+        if let Some(embedded) = einvoice::find_embedded_invoice(&doc) {
+            info!(
+                file = %embedded.filename,
+                syntax = embedded.syntax.as_str(),
+                invoice_no = ?embedded.invoice.invoice_no,
+                "Embedded e-invoice found — skipping text extraction"
+            );
+            db.set_attachment_extraction(att_id, "einvoice", Some(&embedded.xml))?;
+            db.insert_invoice(
+                att_id,
+                EINVOICE_BACKEND,
+                Some(embedded.syntax.as_str()),
+                &embedded.invoice,
+            )?;
+            continue;
+        }
+
+        let pages = classify_pages(&doc, pdf_config.text_mode);
+        let kind = document_kind(&pages);
+        let text = joined_text(&pages);
+        info!(
+            pages = pages.len(),
+            chars = text.len(),
+            content_type = kind,
+            "Classified PDF pages"
+        );
+        db.set_attachment_pages(att_id, &pages)?;
+        let text = (!text.is_empty()).then_some(text);
+        db.set_attachment_extraction(att_id, kind, text.as_deref())?;
+    }
+
+    // Summary
+    let text_count = db.get_text_attachments()?.len();
+    let scanned_count = db.get_scanned_attachments()?.len();
+    let encrypted_count = db.get_attachments_by_content_type(ENCRYPTED)?.len();
+    info!(
+        text = text_count,
+        scanned = scanned_count,
+        encrypted = encrypted_count,
+        "Extraction complete — ready for heuristics / OCR"
+    );
+
+    Ok(())
+}
+
+/// Metadata for a PDF from the outcome of opening it.
+fn pdf_metadata(
+    pdf_bytes: &[u8],
+    opened: &Result<(Document, Option<&str>), LoadError>,
+) -> metadata::PdfMetadata {
+    match opened {
+        Ok((doc, password)) => metadata::read(pdf_bytes, Some(doc), password.is_some()),
+        Err(e) => metadata::read(pdf_bytes, None, matches!(e, LoadError::Encrypted)),
+    }
+}
+
+/// Record metadata for attachments classified before it was captured.
+fn backfill_metadata(
+    db: &MessageStore,
+    pdf_config: &PdfConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let missing = db.get_attachments_without_metadata()?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    info!(
+        count = missing.len(),
+        "Recording metadata of earlier attachments"
+    );
+    for att in &missing {
+        let att_id = att.id.expect("attachment must have an id from DB");
+        let passwords = attachment_passwords(db, att, pdf_config)?;
+        let pdf_bytes = att.pdf_data.read(db)?;
+        let opened = open_pdf(&pdf_bytes, &passwords);
+        db.set_attachment_metadata(att_id, &pdf_metadata(&pdf_bytes, &opened))?;
+    }
+    Ok(())
+}
+
+/// OCR the scanned pages of every `scanned` attachment and reclassify it as
+/// `ocr` so the heuristics / LLM stages pick up its text. Text pages keep
+/// their extracted text. Attachments that fail stay `scanned` and are
+/// retried on the next run. Encrypted PDFs are rendered with the configured
+/// password that opened them.
+pub fn run_ocr(
+    db: &MessageStore,
+    cfg: &OcrConfig,
+    pdf_config: &PdfConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let scanned = db.get_scanned_attachments()?;
+    if scanned.is_empty() {
+        return Ok(());
+    }
+    if let Some(missing) = [&cfg.pdftoppm, &cfg.tesseract]
+        .into_iter()
+        .find(|tool| !render::tool_available(tool))
+    {
+        warn!(
+            tool = %missing,
+            pending = scanned.len(),
+            "OCR tool not found — scanned attachments left for later"
+        );
+        return Ok(());
+    }
+    info!(count = scanned.len(), "Scanned attachments for OCR");
+
+    for att in &scanned {
+        let att_id = att.id.expect("attachment must have an id from DB");
+        let span = tracing::info_span!("ocr", id = att_id, filename = %att.filename);
+        let _guard = span.enter();
+
+        // Attachments classified before per-page results were stored have
+        // no page rows: OCR the whole document
+        let classified = db.get_attachment_pages(att_id)?;
+        let scanned_pages: Vec<u32> = classified
+            .iter()
+            .filter(|p| p.kind == PageKind::Scanned)
+            .map(|p| p.page)
+            .collect();
+        let selection = if classified.is_empty() {
+            PageSelection::All
+        } else {
+            PageSelection::Only(&scanned_pages)
+        };
+
+        let pdf_bytes = att.pdf_data.read(db)?;
+        let pdf_bytes = pdf_for_tools(db, att, pdf_config, &pdf_bytes)?;
+        match ocr::ocr_pdf(&pdf_bytes, cfg, selection) {
+            Ok(pages) => {
+                let text = merge_ocr_text(&classified, &pages);
+                let min_confidence = pages.iter().map(|p| p.confidence).fold(1.0, f32::min);
+                info!(
+                    pages = pages.len(),
+                    chars = text.len(),
+                    min_confidence = format!("{min_confidence:.2}"),
+                    "OCR complete"
+                );
+                db.set_attachment_ocr(att_id, &pages, &text)?;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "OCR failed");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Interleave native page text with OCR text in page order.
+fn merge_ocr_text(classified: &[PageContent], ocr_pages: &[ocr::OcrPage]) -> String {
+    if classified.is_empty() {
+        return ocr::join_pages(ocr_pages);
+    }
+    classified
+        .iter()
+        .filter_map(|page| match page.kind {
+            PageKind::Text => page.text.as_deref().map(str::trim_end),
+            PageKind::Scanned => ocr_pages
+                .iter()
+                .find(|o| o.page == page.page)
+                .map(|o| o.text.as_str()),
+            PageKind::Empty | PageKind::Error => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Run heuristic extraction on text-classified attachments.
+pub fn run_heuristics(
+    db: &MessageStore,
+    text_attachments: &[StoredAttachment],
+    template: HeuristicTemplate,
+    pdf_config: &PdfConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(
+        count = text_attachments.len(),
+        template = ?template,
+        "Text attachments for heuristic parsing"
+    );
+
+    for att in text_attachments {
+        let att_id = att.id.expect("attachment must have an id from DB");
+        let span = tracing::info_span!("heuristics", id = att_id, filename = %att.filename);
+        let _guard = span.enter();
+
+        let segments = segment::attachment_segments(db, att)?;
+        if segments.is_empty() {
+            tracing::warn!("No extracted text despite content_type = text");
+            continue;
+        }
+
+        let passwords = attachment_passwords(db, att, pdf_config)?;
+        let lines = pdf_lines(&att.pdf_data.read(db)?, &passwords);
+        let mut invoices = Vec::with_capacity(segments.len());
+        for Segment { pages, text } in &segments {
+            let invoice = extract_with_tables(template, text, &lines, *pages);
+            let (filled, total) = invoice.coverage();
+            info!(
+                pages = ?pages,
+                filled = filled,
+                total = total,
+                invoice_no = ?invoice.invoice_no,
+                vendor = ?invoice.vendor,
+                buyer = ?invoice.buyer,
+                total_amount = ?invoice.total_amount,
+                currency = ?invoice.currency,
+                line_items = invoice.line_items.len(),
+                packing_items = invoice.packing_items.len(),
+                "Extraction result"
+            );
+
+            // Log line items
+            for (i, item) in invoice.line_items.iter().enumerate() {
+                info!(
+                    idx = i,
+                    desc = %item.description,
+                    qty = item.qty,
+                    unit_price = %item.unit_price,
+                    amount = %item.amount,
+                    "Line item"
+                );
+            }
+
+            // Log packing items
+            for (i, item) in invoice.packing_items.iter().enumerate() {
+                info!(
+                    idx = i,
+                    carton = %item.carton,
+                    desc = %item.description,
+                    ctns = item.ctns,
+                    qty = item.qty,
+                    net_wt = item.net_wt_per_ctn,
+                    gross_wt = item.gross_wt_per_ctn,
+                    measurement = %item.measurement,
+                    "Packing item"
+                );
+            }
+
+            if let Some(ref totals) = invoice.packing_totals {
+                info!(
+                    cartons = totals.total_cartons,
+                    qty = totals.total_qty,
+                    net_wt = totals.total_net_wt,
+                    gross_wt = totals.total_gross_wt,
+                    "Packing totals"
+                );
+            }
+
+            if let (Some(total), Some(lines)) = (invoice.total_amount, invoice.line_total())
+                && total != lines
+            {
+                info!(%total, %lines, "Line items do not add up to the total");
+            }
+
+            invoices.push((*pages, invoice));
+        }
+
+        db.insert_invoices(att_id, LlmBackend::Heuristics.as_str(), None, &invoices)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_garbage_bytes() {
+        assert!(matches!(
+            load_pdf(b"this is not a pdf", &[]),
+            Err(LoadError::Invalid(_))
+        ));
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// One-page PDF with 40-bit RC4 (V1 / R2) encryption, user password
+    /// "s3cret". The content stream was encrypted offline with that key.
+    fn encrypted_pdf() -> Vec<u8> {
+        use lopdf::{Object, Stream, dictionary};
+
+        let mut doc = Document::with_version("1.4");
+        // Object 1 0 — the object number is part of the RC4 key
+        let content_id = doc.add_object(Stream::new(
+            dictionary! {},
+            hex("08c9e2418dde42d52f8ecaf8026c947c26211e92e13cc547dba5fcb7d7cabceb76a7b7ed90a52e46"),
+        ));
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        });
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "Resources" => dictionary! { "Font" => dictionary! { "F1" => font_id } },
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+        set_encryption(&mut doc);
+
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Mark `doc` as RC4 (V1 / R2) encrypted with user password "s3cret".
+    fn set_encryption(doc: &mut Document) {
+        use lopdf::{Object, StringFormat, dictionary};
+
+        let encrypt_id = doc.add_object(dictionary! {
+            "Filter" => "Standard",
+            "V" => 1,
+            "R" => 2,
+            "O" => Object::String(
+                hex("92a80f4454ad4c9644693f33c07cb54f587dce1e2682fe9ecea6107a1ef630dd"),
+                StringFormat::Hexadecimal,
+            ),
+            "U" => Object::String(
+                hex("54ca8bc9bda11e42091afd3e6ecf6e3502116c881d4b638ad8f8903fc1af0f75"),
+                StringFormat::Hexadecimal,
+            ),
+            "P" => -4,
+        });
+        let id = Object::string_literal("0123456789abcdef");
+        doc.trailer.set("Encrypt", encrypt_id);
+        doc.trailer.set("ID", vec![id.clone(), id]);
+    }
+
+    /// One-page scan — a single image, no text — encrypted like
+    /// `encrypted_pdf`. RC4 is symmetric, so lopdf's decryption encrypts it.
+    fn encrypted_scanned_pdf() -> Vec<u8> {
+        use lopdf::{Object, Stream, dictionary, encryption};
+
+        let mut doc = Document::with_version("1.4");
+        let content_id = doc.add_object(Stream::new(
+            dictionary! {},
+            b"q 595 0 0 842 0 0 cm /Im0 Do Q".to_vec(),
+        ));
+        let image_id = doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => 1,
+                "Height" => 1,
+                "ColorSpace" => "DeviceGray",
+                "BitsPerComponent" => 8,
+            },
+            vec![0x80],
+        ));
+        let pages_id = doc.new_object_id();
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image_id } },
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+        set_encryption(&mut doc);
+
+        let key = encryption::get_encryption_key(&doc, "s3cret", true).unwrap();
+        for id in [content_id, image_id] {
+            let encrypted = encryption::decrypt_object(&key, id, doc.get_object(id).unwrap());
+            doc.get_object_mut(id)
+                .and_then(Object::as_stream_mut)
+                .unwrap()
+                .set_content(encrypted.unwrap());
+        }
+
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// A scan behind a password reaches `pdftoppm` decrypted, with no
+    /// password in its arguments. The stand-in `pdftoppm` writes whether its
+    /// input is still encrypted, then its arguments, as the page image.
+    #[cfg(unix)]
+    #[test]
+    fn test_encrypted_scan_is_rendered_decrypted() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bytes = encrypted_scanned_pdf();
+        assert!(matches!(load_pdf(&bytes, &[]), Err(LoadError::Encrypted)));
+        let (mut doc, password) = open_pdf(&bytes, &["wrong", "s3cret"]).unwrap();
+        assert_eq!(password, Some("s3cret"));
+        let pages = classify_pages(&doc, TextMode::Plain);
+        assert_eq!(document_kind(&pages), "scanned");
+        let decrypted = decrypted_copy(&mut doc).unwrap();
+        assert!(load_pdf(&decrypted, &[]).is_ok());
+
+        let bin = tempfile::tempdir().unwrap();
+        let pdftoppm = bin.path().join("pdftoppm");
+        std::fs::write(
+            &pdftoppm,
+            "#!/bin/sh\nfor out; do pdf=$last; last=$out; done\n\
+             if grep -q /Encrypt \"$pdf\"; then state=encrypted; else state=plain; fi\n\
+             printf '%s %s' \"$state\" \"$*\" > \"$out-1.png\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&pdftoppm, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let render = |pdf: &[u8]| {
+            let rendered =
+                render::render_pages(pdf, pdftoppm.to_str().unwrap(), 150, PageSelection::All)
+                    .unwrap();
+            assert_eq!(rendered.len(), 1);
+            String::from_utf8(rendered[0].png.clone()).unwrap()
+        };
+
+        assert!(render(&bytes).starts_with("encrypted "));
+        let args = render(&decrypted);
+        assert!(args.starts_with("plain -r 150 -png "), "{args}");
+        assert!(!args.contains("s3cret"), "{args}");
+    }
+
+    #[test]
+    fn test_encrypted_pdf_needs_sender_password() {
+        let bytes = encrypted_pdf();
+        assert!(matches!(load_pdf(&bytes, &[]), Err(LoadError::Encrypted)));
+        assert!(matches!(
+            load_pdf(&bytes, &["wrong"]),
+            Err(LoadError::Encrypted)
+        ));
+
+        let config: PdfConfig = toml::from_str(
+            "[[passwords]]\nsender = \"@Bank.example\"\npasswords = [\"wrong\", \"s3cret\"]\n",
+        )
+        .unwrap();
+        let passwords = config.passwords_for(Some("Statements <noreply@bank.example>"));
+        assert_eq!(passwords, ["wrong", "s3cret"]);
+        assert!(config.passwords_for(Some("other@example.com")).is_empty());
+
+        let doc = load_pdf(&bytes, &passwords).unwrap();
+        let pages = classify_pages(&doc, TextMode::Plain);
+        assert_eq!(pages[0].kind, PageKind::Text);
+        assert!(joined_text(&pages).contains("Statement"));
+    }
+
+    fn page(page: u32, kind: PageKind, text: Option<&str>) -> PageContent {
+        PageContent {
+            page,
+            kind,
+            text: text.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_mixed_document_keeps_text_and_ocrs_scans() {
+        let pages = vec![
+            page(1, PageKind::Text, Some("COMMERCIAL INVOICE\n")),
+            page(2, PageKind::Scanned, None),
+            page(3, PageKind::Empty, None),
+            page(4, PageKind::Scanned, None),
+        ];
+        assert_eq!(document_kind(&pages), "mixed");
+        assert_eq!(document_kind(&pages[1..]), "scanned");
+        assert_eq!(joined_text(&pages), "COMMERCIAL INVOICE");
+
+        let ocr_pages = vec![
+            ocr::OcrPage {
+                page: 4,
+                text: "DELIVERY NOTE 2".to_string(),
+                confidence: 0.8,
+            },
+            ocr::OcrPage {
+                page: 2,
+                text: "DELIVERY NOTE 1".to_string(),
+                confidence: 0.9,
+            },
+        ];
+        assert_eq!(
+            merge_ocr_text(&pages, &ocr_pages),
+            "COMMERCIAL INVOICE\n\nDELIVERY NOTE 1\n\nDELIVERY NOTE 2"
+        );
+
+        assert_eq!(document_kind(&pages[..1]), "text");
+        assert_eq!(document_kind(&pages[2..3]), "empty");
+        assert_eq!(
+            document_kind(&[page(1, PageKind::Error, Some("x"))]),
+            "error"
+        );
+    }
+}
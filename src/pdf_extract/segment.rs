@@ -0,0 +1,212 @@
+// src/pdf_extract/segment.rs
+
+use super::{PageContent, PageKind};
+use crate::heuristics;
+use crate::message_db::{MessageStore, StoredAttachment};
+use regex::Regex;
+use rusqlite::Result as SqliteResult;
+use serde::Serialize;
+
+/// Inclusive, 1-based range of pages within an attachment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PageRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+/// One logical document bundled in a PDF: an invoice with its packing list,
+/// a credit note, a second invoice, ...
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// `None` when the attachment has no per-page text to split on
+    pub pages: Option<PageRange>,
+    pub text: String,
+}
+
+/// Document titles, longest first so `COMMERCIAL INVOICE` wins over `INVOICE`.
+const TITLES: &[&str] = &[
+    "COMMERCIAL INVOICE",
+    "PROFORMA INVOICE",
+    "TAX INVOICE",
+    "PACKING LIST",
+    "CREDIT NOTE",
+    "DEBIT NOTE",
+    "STATEMENT",
+    "INVOICE",
+];
+
+/// Non-blank lines at the top of a page searched for a title.
+const HEADER_LINES: usize = 8;
+
+/// What a page says about where it belongs.
+#[derive(Debug, Default)]
+struct PageMarks {
+    /// `n` from "Page n of m" / "Page n/m"
+    number: Option<u32>,
+    invoice_no: Option<String>,
+    /// First title found in the page header
+    title: Option<&'static str>,
+}
+
+fn page_marks(text: &str) -> PageMarks {
+    let page_re = Regex::new(r"(?i)\bpage\s*(\d+)\s*(?:of|/)\s*\d+\b").unwrap();
+    let title = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .take(HEADER_LINES)
+        .find_map(|line| {
+            let upper = line.to_uppercase();
+            TITLES.iter().copied().find(|t| upper.contains(t))
+        });
+    PageMarks {
+        number: page_re.captures(text).and_then(|c| c[1].parse().ok()),
+        invoice_no: heuristics::invoice_no(text),
+        title,
+    }
+}
+
+/// Whether a page opens a new document rather than continuing the one whose
+/// invoice number (so far) and opening title are given.
+fn starts_document(invoice_no: Option<&str>, title: Option<&str>, page: &PageMarks) -> bool {
+    // A shared invoice number ties an invoice and its packing list together
+    if let (Some(current), Some(next)) = (invoice_no, page.invoice_no.as_deref()) {
+        return !current.eq_ignore_ascii_case(next);
+    }
+    // Page numbering restarting at 1
+    if let Some(number) = page.number {
+        return number == 1;
+    }
+    // The same header again, with nothing saying it is a continuation
+    page.title.is_some() && page.title == title
+}
+
+/// Split page texts (page number, text — pages without text left out) into
+/// logical documents. A document's range runs to the page before the next
+/// document, so blank separator pages stay with the document before them.
+pub fn split_documents(pages: &[(u32, &str)]) -> Vec<Segment> {
+    struct Open<'a> {
+        first: u32,
+        texts: Vec<&'a str>,
+        invoice_no: Option<String>,
+        title: Option<&'static str>,
+    }
+
+    let mut open: Vec<Open> = Vec::new();
+    for &(page, text) in pages {
+        let marks = page_marks(text);
+        match open.last_mut() {
+            Some(doc) if !starts_document(doc.invoice_no.as_deref(), doc.title, &marks) => {
+                doc.texts.push(text);
+                doc.invoice_no = doc.invoice_no.take().or(marks.invoice_no);
+            }
+            _ => open.push(Open {
+                first: page,
+                texts: vec![text],
+                invoice_no: marks.invoice_no,
+                title: marks.title,
+            }),
+        }
+    }
+
+    let last_page = pages.last().map_or(0, |&(page, _)| page);
+    let starts: Vec<u32> = open.iter().map(|d| d.first).collect();
+    open.into_iter()
+        .enumerate()
+        .map(|(i, doc)| Segment {
+            pages: Some(PageRange {
+                first: doc.first,
+                last: starts.get(i + 1).map_or(last_page, |next| next - 1),
+            }),
+            text: doc
+                .texts
+                .iter()
+                .map(|t| t.trim_end())
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+        })
+        .collect()
+}
+
+/// The logical documents of a classified (and possibly OCR'd) attachment.
+/// Attachments classified before page text was recorded come back as one
+/// segment holding all of their extracted text.
+pub fn attachment_segments(
+    db: &MessageStore,
+    att: &StoredAttachment,
+) -> SqliteResult<Vec<Segment>> {
+    let att_id = att.id.expect("attachment must have an id from DB");
+    let pages: Vec<PageContent> = db.get_attachment_pages(att_id)?;
+    let ocr_pages = if att.content_type.as_deref() == Some("ocr") {
+        db.get_ocr_pages(att_id)?
+    } else {
+        Vec::new()
+    };
+
+    let texts: Vec<(u32, &str)> = if pages.is_empty() {
+        ocr_pages
+            .iter()
+            .map(|o| (o.page, o.text.as_str()))
+            .collect()
+    } else {
+        pages
+            .iter()
+            .filter_map(|p| match p.kind {
+                PageKind::Text => p.text.as_deref().map(|t| (p.page, t)),
+                PageKind::Scanned => ocr_pages
+                    .iter()
+                    .find(|o| o.page == p.page)
+                    .map(|o| (p.page, o.text.as_str())),
+                PageKind::Empty | PageKind::Error => None,
+            })
+            .filter(|(_, text)| !text.trim().is_empty())
+            .collect()
+    };
+
+    if texts.is_empty() {
+        return Ok(att
+            .extracted_text
+            .iter()
+            .map(|text| Segment {
+                pages: None,
+                text: text.clone(),
+            })
+            .collect());
+    }
+    Ok(split_documents(&texts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_bundled_invoices() {
+        let pages = [
+            (
+                1,
+                "SOFT SOURCE PTE LTD\nCOMMERCIAL INVOICE\nInvoice No.: SS-1\nPage 1 of 2",
+            ),
+            (2, "COMMERCIAL INVOICE\nTOTAL 2540.00\nPage 2 of 2"),
+            // Packing list for the same invoice: stays with it
+            (3, "PACKING LIST\nInvoice No.: SS-1\nCARTON 1-6"),
+            // Next invoice in the bundle
+            (5, "ACME LTD\nTAX INVOICE\nInvoice No.: A-9\nTOTAL 8.00"),
+            // Header repeats without invoice numbers or page marks
+            (6, "ACME LTD\nTAX INVOICE\nTOTAL 10.00"),
+            (7, "ACME LTD\nTAX INVOICE\nTOTAL 12.00"),
+        ];
+        let segments = split_documents(&pages);
+        let ranges: Vec<(u32, u32)> = segments
+            .iter()
+            .map(|s| s.pages.map(|p| (p.first, p.last)).unwrap())
+            .collect();
+        assert_eq!(ranges, [(1, 4), (5, 5), (6, 6), (7, 7)]);
+        assert!(segments[0].text.contains("TOTAL 2540.00"));
+        assert!(segments[0].text.contains("CARTON 1-6"));
+        assert!(!segments[0].text.contains("A-9"));
+
+        // A single document stays whole
+        let single = split_documents(&pages[..3]);
+        assert_eq!(single.len(), 1);
+    }
+}
@@ -0,0 +1,550 @@
+// src/pdf_extract/tables.rs
+
+use crate::heuristics::{LineItem, PackingItem};
+use crate::layout::{Cell, Line};
+use crate::money::{Currency, Money};
+
+/// Rows recovered from the invoice's line-item and packing-list grids.
+#[derive(Debug, Default)]
+pub struct Tables {
+    pub line_items: Vec<LineItem>,
+    pub packing_items: Vec<PackingItem>,
+}
+
+/// What a grid column holds, recognised from its header text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    /// Shipping marks; recognised only so they stay out of the description
+    Marks,
+    Description,
+    Qty,
+    UnitPrice,
+    Amount,
+    Carton,
+    Ctns,
+    NetWeight,
+    GrossWeight,
+    Measurement,
+}
+
+/// Header spellings for each column. Longer keywords are matched first, so
+/// `UNIT PRICE` wins over `UNIT` and `CARTONS` over `CARTON`.
+const KEYWORDS: &[(Column, &str)] = &[
+    (Column::Marks, "MARKS"),
+    (Column::Description, "DESCRIPTION"),
+    (Column::Description, "PARTICULARS"),
+    (Column::Description, "ITEM"),
+    (Column::Qty, "QUANTITY"),
+    (Column::Qty, "Q'TY"),
+    (Column::Qty, "QTY"),
+    (Column::UnitPrice, "UNIT PRICE"),
+    (Column::UnitPrice, "PRICE"),
+    (Column::UnitPrice, "UNIT"),
+    (Column::Amount, "AMOUNT"),
+    (Column::Carton, "CARTON"),
+    (Column::Carton, "C/NO"),
+    (Column::Ctns, "CARTONS"),
+    (Column::Ctns, "CTNS"),
+    (Column::NetWeight, "NET WEIGHT"),
+    (Column::NetWeight, "NET WT"),
+    (Column::NetWeight, "N.W."),
+    (Column::GrossWeight, "GROSS WEIGHT"),
+    (Column::GrossWeight, "GROSS WT"),
+    (Column::GrossWeight, "G.W."),
+    (Column::Measurement, "MEASUREMENT"),
+    (Column::Measurement, "MEASURMENT"),
+    (Column::Measurement, "DIMENSIONS"),
+];
+
+/// Digit-free lines right below a header that may continue it (e.g. `CTNS`
+/// under `NO OF`, units under weights).
+const MAX_HEADER_CONTINUATION: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Grid {
+    LineItems,
+    Packing,
+}
+
+/// Horizontal extent of one column's header keyword.
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    column: Column,
+    x0: f32,
+    x1: f32,
+}
+
+/// Find line-item and packing grids in a document's lines and read their
+/// rows. A grid starts at a header line naming its columns and runs until a
+/// `TOTAL` line, a new header, or the end of the page; rows without the
+/// numbers a row needs (notes, HS codes, blank spacers) are skipped.
+pub fn detect_tables(lines: &[Line], currency: Currency) -> Tables {
+    let mut tables = Tables::default();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let Some((grid, anchors, header_lines)) = header_at(lines, i) else {
+            i += 1;
+            continue;
+        };
+        let page = lines[i].page;
+        i += header_lines;
+
+        while let Some(line) = lines.get(i) {
+            if line.page != page || ends_table(line) || header_at(lines, i).is_some() {
+                break;
+            }
+            let row = Row::assign(line, &anchors);
+            match grid {
+                Grid::LineItems => tables.line_items.extend(row.line_item(currency)),
+                Grid::Packing => tables.packing_items.extend(row.packing_item()),
+            }
+            i += 1;
+        }
+    }
+
+    tables
+}
+
+/// If `lines[i]` is a grid header, its kind, column anchors and the number
+/// of lines the header occupies.
+fn header_at(lines: &[Line], i: usize) -> Option<(Grid, Vec<Anchor>, usize)> {
+    let first = &lines[i];
+    let mut anchors = Vec::new();
+    for cell in &first.cells {
+        add_anchors(cell, &mut anchors);
+    }
+    // Keywords spread over at least three cells — not a sentence that
+    // happens to mention quantity and amount
+    let cells_with_keywords = first
+        .cells
+        .iter()
+        .filter(|c| !keyword_spans(&c.text).is_empty())
+        .count();
+    if cells_with_keywords < 3 {
+        return None;
+    }
+
+    let mut header_lines = 1;
+    for line in lines[i + 1..].iter().take(MAX_HEADER_CONTINUATION) {
+        if line.page != first.page
+            || line
+                .cells
+                .iter()
+                .any(|c| c.text.contains(|ch: char| ch.is_ascii_digit()))
+        {
+            break;
+        }
+        for cell in &line.cells {
+            add_anchors(cell, &mut anchors);
+        }
+        header_lines += 1;
+    }
+
+    let has = |column| anchors.iter().any(|a| a.column == column);
+    let grid = if has(Column::Carton)
+        && [
+            Column::Ctns,
+            Column::NetWeight,
+            Column::GrossWeight,
+            Column::Measurement,
+        ]
+        .into_iter()
+        .any(has)
+    {
+        Grid::Packing
+    } else if has(Column::Description) && has(Column::Qty) && has(Column::Amount) {
+        Grid::LineItems
+    } else {
+        return None;
+    };
+    Some((grid, anchors, header_lines))
+}
+
+/// Header keywords in `text` as (column, start char, end char), longest
+/// keyword first and without overlaps.
+fn keyword_spans(text: &str) -> Vec<(Column, usize, usize)> {
+    let upper = text.to_uppercase();
+    let mut keywords: Vec<&(Column, &str)> = KEYWORDS.iter().collect();
+    keywords.sort_by_key(|(_, kw)| std::cmp::Reverse(kw.len()));
+
+    let mut spans: Vec<(Column, usize, usize)> = Vec::new();
+    for &(column, keyword) in keywords {
+        if spans.iter().any(|s| s.0 == column) {
+            continue;
+        }
+        for (pos, _) in upper.match_indices(keyword) {
+            let end = pos + keyword.len();
+            let bounded = |c: Option<char>| c.is_none_or(|c| !c.is_alphanumeric());
+            if !bounded(upper[..pos].chars().next_back()) || !bounded(upper[end..].chars().next()) {
+                continue;
+            }
+            let (start, end) = (upper[..pos].chars().count(), upper[..end].chars().count());
+            if spans.iter().all(|s| end <= s.1 || start >= s.2) {
+                spans.push((column, start, end));
+                break;
+            }
+        }
+    }
+    spans
+}
+
+fn add_anchors(cell: &Cell, anchors: &mut Vec<Anchor>) {
+    for (column, start, end) in keyword_spans(&cell.text) {
+        if anchors.iter().any(|a| a.column == column) {
+            continue;
+        }
+        let (x0, x1) = char_span(cell, start, end);
+        anchors.push(Anchor { column, x0, x1 });
+    }
+}
+
+/// Approximate horizontal extent of characters `start..end` of a cell,
+/// assuming evenly wide characters.
+fn char_span(cell: &Cell, start: usize, end: usize) -> (f32, f32) {
+    let chars = cell.text.chars().count().max(1) as f32;
+    let width = cell.x_end - cell.x;
+    (
+        cell.x + width * start as f32 / chars,
+        cell.x + width * end as f32 / chars,
+    )
+}
+
+fn ends_table(line: &Line) -> bool {
+    line.cells.iter().any(|c| {
+        let upper = c.text.to_uppercase();
+        upper.contains("TOTAL") || upper.contains("PACKING LIST")
+    })
+}
+
+/// Distance between two horizontal extents; negative when they overlap (by
+/// that much).
+fn gap(a: (f32, f32), b: (f32, f32)) -> f32 {
+    a.0.max(b.0) - a.1.min(b.1)
+}
+
+/// Anchor closest to (or overlapping most with) an extent.
+fn nearest(anchors: &[Anchor], span: (f32, f32)) -> Option<Column> {
+    anchors
+        .iter()
+        .min_by(|a, b| gap((a.x0, a.x1), span).total_cmp(&gap((b.x0, b.x1), span)))
+        .map(|a| a.column)
+}
+
+/// One data line's text, split by column.
+struct Row {
+    cells: Vec<(Column, String)>,
+}
+
+impl Row {
+    /// Put each cell under the header it sits below. A cell spanning several
+    /// headers (a quantity run together with its unit, say) is split into
+    /// words and each word placed on its own.
+    fn assign(line: &Line, anchors: &[Anchor]) -> Self {
+        let mut cells: Vec<(Column, String)> = Vec::new();
+        let mut push = |column: Column, text: &str| match cells.iter_mut().find(|c| c.0 == column) {
+            Some((_, existing)) => {
+                existing.push(' ');
+                existing.push_str(text);
+            }
+            None => cells.push((column, text.to_string())),
+        };
+
+        for cell in &line.cells {
+            let span = (cell.x, cell.x_end);
+            let overlapping = anchors
+                .iter()
+                .filter(|a| gap((a.x0, a.x1), span) < 0.0)
+                .count();
+            if overlapping <= 1 {
+                if let Some(column) = nearest(anchors, span) {
+                    push(column, &cell.text);
+                }
+                continue;
+            }
+
+            let mut offset = 0;
+            for word in cell.text.split(' ') {
+                let len = word.chars().count();
+                if !word.is_empty()
+                    && let Some(column) = nearest(anchors, char_span(cell, offset, offset + len))
+                {
+                    push(column, word);
+                }
+                offset += len + 1;
+            }
+        }
+
+        Self { cells }
+    }
+
+    fn text(&self, column: Column) -> &str {
+        self.cells
+            .iter()
+            .find(|c| c.0 == column)
+            .map_or("", |c| c.1.trim())
+    }
+
+    fn number(&self, column: Column) -> Option<f64> {
+        parse_number(self.text(column))
+    }
+
+    fn count(&self, column: Column) -> Option<u32> {
+        self.number(column)
+            .filter(|n| *n >= 0.0 && n.fract() == 0.0)
+            .map(|n| n as u32)
+    }
+
+    fn money(&self, column: Column, currency: Currency) -> Option<Money> {
+        number_token(self.text(column)).and_then(|token| Money::parse(&token, currency))
+    }
+
+    fn line_item(&self, currency: Currency) -> Option<LineItem> {
+        let amount = self.money(Column::Amount, currency)?;
+        let description = self.text(Column::Description).to_string();
+        let mut qty = self.count(Column::Qty);
+        let mut unit_price = self.money(Column::UnitPrice, currency);
+        if qty.is_none() && description.is_empty() {
+            return None;
+        }
+
+        match (qty, unit_price) {
+            (Some(q), None) if q > 0 => unit_price = amount.divide(q),
+            (None, Some(p)) if p.minor > 0 => {
+                qty = u32::try_from((amount.minor + p.minor / 2) / p.minor).ok()
+            }
+            _ => {}
+        }
+
+        Some(LineItem {
+            description,
+            qty: qty.unwrap_or(0),
+            unit_price: unit_price.unwrap_or(Money::zero(currency)),
+            amount,
+        })
+    }
+
+    fn packing_item(&self) -> Option<PackingItem> {
+        let carton = self.text(Column::Carton).to_string();
+        let ctns = self.count(Column::Ctns);
+        let qty = self.count(Column::Qty);
+        let net = self.number(Column::NetWeight);
+        let gross = self.number(Column::GrossWeight);
+        if (carton.is_empty() && ctns.is_none())
+            || (qty.is_none() && net.is_none() && gross.is_none())
+        {
+            return None;
+        }
+
+        Some(PackingItem {
+            carton,
+            description: self.text(Column::Description).to_string(),
+            ctns: ctns.unwrap_or(0),
+            qty: qty.unwrap_or(0),
+            net_wt_per_ctn: net.unwrap_or(0.0),
+            gross_wt_per_ctn: gross.unwrap_or(0.0),
+            measurement: self.text(Column::Measurement).to_string(),
+        })
+    }
+}
+
+/// First token that reads as a number once thousands separators and
+/// currency marks are stripped (`1,250.00`, `US$25.40`). An accounting
+/// negative in parentheses, `(25.00)`, reads as -25.00.
+fn parse_number(text: &str) -> Option<f64> {
+    number_token(text).and_then(|token| token.replace(',', "").parse().ok())
+}
+
+/// The digits of [`parse_number`]'s token, separators included.
+fn number_token(text: &str) -> Option<String> {
+    text.split_whitespace().find_map(|token| {
+        let start = token.trim_start_matches(|c: char| !c.is_ascii_digit() && c != '-');
+        let digits = start.trim_end_matches(|c: char| !c.is_ascii_digit());
+        let prefix = &token[..token.len() - start.len()];
+        let suffix = &start[digits.len()..];
+        let parenthesised = prefix.contains('(') && suffix.contains(')');
+        if digits.replace(',', "").parse::<f64>().is_err() {
+            return None;
+        }
+        Some(if parenthesised && !digits.starts_with('-') {
+            format!("-{digits}")
+        } else {
+            digits.to_string()
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(y: f32, cells: &[(f32, f32, &str)]) -> Line {
+        Line {
+            page: 1,
+            y,
+            font_size: 8.0,
+            cells: cells
+                .iter()
+                .map(|&(x, x_end, text)| Cell {
+                    x,
+                    x_end,
+                    text: text.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_detect_line_item_and_packing_grids() {
+        // Positions as laid out by a real supplier invoice
+        let lines = vec![
+            line(700.0, &[(15.0, 156.0, "SOFT SOURCE PTE LTD")]),
+            line(
+                500.0,
+                &[
+                    (12.0, 115.0, "MARKS & NOS. ITEM"),
+                    (363.0, 385.0, "Q'TY"),
+                    (397.0, 418.0, "UNIT"),
+                    (512.0, 550.0, "AMOUNT"),
+                ],
+            ),
+            line(
+                490.0,
+                &[
+                    (12.0, 55.0, "C/NO. 1-6"),
+                    (86.0, 284.0, "TALES OF BERSERIA REMASTERED - PS5 ASI"),
+                    (368.0, 417.0, "100 PIECE"),
+                    (435.0, 490.0, "25.40 US$"),
+                    (505.0, 551.0, "2540.00"),
+                ],
+            ),
+            // Description misses the platform tag the regex path relies on
+            line(
+                480.0,
+                &[
+                    (86.0, 200.0, "ARTBOOK"),
+                    (373.0, 417.0, "80 PIECE"),
+                    (505.0, 551.0, "2032.00"),
+                ],
+            ),
+            line(470.0, &[(86.0, 257.0, "VIDEO GAME SOFTWARE: 8523.49.9900")]),
+            line(
+                460.0,
+                &[
+                    (280.0, 331.0, "TOTAL PCS"),
+                    (368.0, 383.0, "180"),
+                    (505.0, 551.0, "4572.00"),
+                ],
+            ),
+            line(400.0, &[(15.0, 80.0, "PACKING LIST")]),
+            line(
+                390.0,
+                &[
+                    (14.0, 118.0, "CARTON # DESCRIPTION"),
+                    (301.0, 320.0, "QTY"),
+                    (336.0, 362.0, "NO OF"),
+                    (376.0, 459.0, "NET WT GROSS WT"),
+                    (478.0, 536.0, "MEASURMENT"),
+                ],
+            ),
+            line(
+                380.0,
+                &[
+                    (338.0, 360.0, "CTNS"),
+                    (374.0, 409.0, "PER CTN"),
+                    (420.0, 455.0, "PER CTN"),
+                ],
+            ),
+            line(
+                370.0,
+                &[
+                    (34.0, 38.0, "1"),
+                    (61.0, 226.0, "TALES OF BERSERIA REMASTERED - NS A"),
+                    (307.0, 325.0, "80"),
+                    (345.0, 358.0, "1"),
+                    (379.0, 404.0, "5.10"),
+                    (423.0, 448.0, "5.60"),
+                    (475.0, 536.0, "59 X 25 X 20 CM"),
+                ],
+            ),
+            line(
+                360.0,
+                &[
+                    (30.0, 42.0, "2-6"),
+                    (61.0, 237.0, "TALES OF BERSERIA REMASTERED - PS5 ASI"),
+                    (304.0, 325.0, "100"),
+                    (345.0, 358.0, "5"),
+                    (379.0, 404.0, "1.60"),
+                    (423.0, 448.0, "2.10"),
+                    (475.0, 536.0, "59 X 25 X 20 CM"),
+                ],
+            ),
+            line(
+                350.0,
+                &[
+                    (246.0, 272.0, "TOTAL"),
+                    (305.0, 327.0, "180"),
+                    (344.0, 358.0, "6"),
+                ],
+            ),
+        ];
+
+        let tables = detect_tables(&lines, Currency::of(Some("USD")));
+
+        let items = &tables.line_items;
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0].description,
+            "TALES OF BERSERIA REMASTERED - PS5 ASI"
+        );
+        let usd = |minor| Money::new(minor, Currency::of(Some("USD")));
+        assert_eq!(
+            (items[0].qty, items[0].unit_price, items[0].amount),
+            (100, usd(2540), usd(254_000))
+        );
+        assert_eq!(items[1].description, "ARTBOOK");
+        assert_eq!(
+            (items[1].qty, items[1].unit_price, items[1].amount),
+            (80, usd(2540), usd(203_200))
+        );
+
+        let packing = &tables.packing_items;
+        assert_eq!(packing.len(), 2);
+        assert_eq!(packing[0].carton, "1");
+        assert_eq!(
+            packing[0].description,
+            "TALES OF BERSERIA REMASTERED - NS A"
+        );
+        assert_eq!((packing[0].ctns, packing[0].qty), (1, 80));
+        assert_eq!(
+            (packing[0].net_wt_per_ctn, packing[0].gross_wt_per_ctn),
+            (5.10, 5.60)
+        );
+        assert_eq!(packing[0].measurement, "59 X 25 X 20 CM");
+        assert_eq!(packing[1].carton, "2-6");
+        assert_eq!((packing[1].ctns, packing[1].qty), (5, 100));
+
+        // Prose mentioning the column names is not a header
+        let prose = [line(
+            100.0,
+            &[(
+                10.0,
+                400.0,
+                "Please check the description, quantity and amount",
+            )],
+        )];
+        assert!(detect_tables(&prose, Currency::NONE).line_items.is_empty());
+    }
+
+    #[test]
+    fn test_number_token_reads_accounting_negatives() {
+        assert_eq!(number_token("US$1,250.00").as_deref(), Some("1,250.00"));
+        assert_eq!(number_token("(25.00)").as_deref(), Some("-25.00"));
+        assert_eq!(number_token("$(1,250.00)").as_deref(), Some("-1,250.00"));
+        assert_eq!(number_token("-25.00").as_deref(), Some("-25.00"));
+        // An opening parenthesis alone is not a sign
+        assert_eq!(number_token("(2 pcs)").as_deref(), Some("2"));
+        assert_eq!(parse_number("(3.5)"), Some(-3.5));
+        assert_eq!(number_token("n/a"), None);
+    }
+}
@@ -0,0 +1,76 @@
+// src/rate_limit.rs
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token-bucket limiter for Gmail API quota units.
+///
+/// Gmail charges each call a number of quota units (e.g. 5 for
+/// `messages.get`) against a per-user budget per second. The bucket holds up
+/// to `capacity` units and refills continuously at `refill_per_sec`.
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket that refills at `units_per_sec`, allowing bursts
+    /// of up to one second's worth of units.
+    pub fn new(units_per_sec: u32) -> Self {
+        let rate = f64::from(units_per_sec.max(1));
+        Self {
+            capacity: rate,
+            refill_per_sec: rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `units` are available, then take them.
+    pub async fn acquire(&self, units: u32) {
+        // A request larger than the bucket could never be satisfied; clamp it
+        let units = f64::from(units).min(self.capacity);
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+                state.tokens = (state.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+                state.last_refill = now;
+
+                if state.tokens >= units {
+                    state.tokens -= units;
+                    return;
+                }
+                Duration::from_secs_f64((units - state.tokens) / self.refill_per_sec)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bucket_throttles_after_burst() {
+        let bucket = TokenBucket::new(1000);
+
+        let start = Instant::now();
+        bucket.acquire(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(20));
+
+        // Bucket is empty: 50 units at 1000/s needs ~50ms of refill
+        bucket.acquire(50).await;
+        assert!(start.elapsed() >= Duration::from_millis(45));
+    }
+}
@@ -0,0 +1,161 @@
+// src/render.rs
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::info;
+
+/// One PDF page rendered to PNG.
+#[derive(Debug, Clone)]
+pub struct RenderedPage {
+    /// 1-based page number
+    pub page: u32,
+    pub png: Vec<u8>,
+}
+
+/// Which pages of a PDF to render.
+#[derive(Debug, Clone, Copy)]
+pub enum PageSelection<'a> {
+    All,
+    /// The first N pages
+    First(u32),
+    /// Specific 1-based page numbers
+    Only(&'a [u32]),
+}
+
+/// Rasterize the selected pages of a PDF to PNG with poppler's `pdftoppm`.
+/// Encrypted PDFs are passed in already decrypted, so no password ever
+/// appears on the tool's command line.
+pub fn render_pages(
+    pdf_bytes: &[u8],
+    pdftoppm: &str,
+    dpi: u32,
+    selection: PageSelection,
+) -> Result<Vec<RenderedPage>, Box<dyn std::error::Error>> {
+    let work = WorkDir::create()?;
+    let pdf_path = work.path.join("input.pdf");
+    fs::write(&pdf_path, pdf_bytes)?;
+
+    // pdftoppm writes page-1.png, page-2.png, ... (zero-padded for long docs)
+    let pdftoppm_range = |first: Option<u32>, last: Option<u32>| {
+        let mut cmd = Command::new(pdftoppm);
+        cmd.arg("-r").arg(dpi.to_string()).arg("-png");
+        if let Some(first) = first {
+            cmd.arg("-f").arg(first.to_string());
+        }
+        if let Some(last) = last {
+            cmd.arg("-l").arg(last.to_string());
+        }
+        run_tool(cmd.arg(&pdf_path).arg(work.path.join("page")))
+    };
+    match selection {
+        PageSelection::All => {
+            pdftoppm_range(None, None)?;
+        }
+        PageSelection::First(n) => {
+            pdftoppm_range(None, Some(n))?;
+        }
+        PageSelection::Only(pages) => {
+            for &page in pages {
+                pdftoppm_range(Some(page), Some(page))?;
+            }
+        }
+    }
+
+    let mut images: Vec<(u32, PathBuf)> = fs::read_dir(&work.path)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            let page = stem.strip_prefix("page-")?.parse().ok()?;
+            (path.extension()? == "png").then_some((page, path))
+        })
+        .collect();
+    images.sort_by_key(|(page, _)| *page);
+    info!(pages = images.len(), dpi, "Rendered PDF pages");
+
+    images
+        .into_iter()
+        .map(|(page, path)| {
+            Ok(RenderedPage {
+                page,
+                png: fs::read(path)?,
+            })
+        })
+        .collect()
+}
+
+/// Run an external tool, returning its stdout. A missing binary is reported
+/// with the tool name so the user knows what to install.
+pub fn run_tool(cmd: &mut Command) -> Result<String, Box<dyn std::error::Error>> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let output = cmd.output().map_err(|e| -> Box<dyn std::error::Error> {
+        if e.kind() == io::ErrorKind::NotFound {
+            format!("Tool '{program}' not found — install poppler-utils / tesseract").into()
+        } else {
+            format!("Failed to run {program}: {e}").into()
+        }
+    })?;
+    if !output.status.success() {
+        return Err(format!(
+            "{program} exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Whether `program` exists (as a path or on `PATH`), for an early
+/// "tool unavailable" check.
+pub fn tool_available(program: &str) -> bool {
+    let candidate = Path::new(program);
+    if candidate.components().count() > 1 {
+        return candidate.is_file();
+    }
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+}
+
+/// Scratch directory for external tool input/output, removed on drop.
+/// Private to the current user, as it holds decrypted PDFs and page images.
+pub struct WorkDir {
+    pub path: PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl WorkDir {
+    pub fn create() -> io::Result<Self> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("invoice_search_render_");
+        // tempfile leaves directories at 0777 minus the umask
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o700));
+        let dir = builder.tempdir()?;
+        Ok(Self {
+            path: dir.path().to_path_buf(),
+            _dir: dir,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_work_dir_is_private_and_removed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let work = WorkDir::create().unwrap();
+        let path = work.path.clone();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert_ne!(WorkDir::create().unwrap().path, path);
+
+        drop(work);
+        assert!(!path.exists());
+    }
+}
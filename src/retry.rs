@@ -0,0 +1,159 @@
+// src/retry.rs
+
+use crate::config::FetchConfig;
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// Retry policy for Gmail API calls: jittered exponential backoff on
+/// rate-limit (429 / 403 rateLimitExceeded), 5xx and connection errors.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(cfg: &FetchConfig) -> Self {
+        Self {
+            max_attempts: cfg.max_attempts.max(1),
+            base_delay: Duration::from_millis(cfg.retry_base_delay_ms),
+            max_delay: Duration::from_secs(32),
+        }
+    }
+
+    /// Run `op` until it succeeds, fails with a non-transient error, or
+    /// `max_attempts` is reached. `op` must build a fresh request each call.
+    pub async fn run<T, F, Fut>(&self, what: &str, mut op: F) -> Result<T, google_gmail1::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, google_gmail1::Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.max_attempts && is_transient(&e) => {
+                    let delay = self.backoff(attempt);
+                    warn!(
+                        call = what,
+                        attempt,
+                        max_attempts = self.max_attempts,
+                        delay_ms = delay.as_millis() as u64,
+                        error = %e,
+                        "Transient Gmail error — retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// "Full jitter" backoff: a random delay in `[0, min(max, base * 2^(attempt-1))]`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1u32 << (attempt - 1).min(16))
+            .min(self.max_delay);
+        let millis = exp.as_millis() as u64;
+        Duration::from_millis(rand::rng().random_range(0..=millis))
+    }
+}
+
+/// HTTP status code carried by a Gmail API error, if any.
+pub fn status_code(err: &google_gmail1::Error) -> Option<u16> {
+    match err {
+        google_gmail1::Error::BadRequest(body) => body["error"]["code"]
+            .as_u64()
+            .and_then(|c| u16::try_from(c).ok()),
+        google_gmail1::Error::Failure(response) => Some(response.status().as_u16()),
+        _ => None,
+    }
+}
+
+/// Whether a Gmail API error is worth retrying.
+pub fn is_transient(err: &google_gmail1::Error) -> bool {
+    match err {
+        // Connection resets, timeouts and other transport failures
+        google_gmail1::Error::HttpError(_) | google_gmail1::Error::Io(_) => true,
+        _ => match status_code(err) {
+            Some(429) | Some(500) | Some(502) | Some(503) | Some(504) => true,
+            // Gmail reports per-user rate limiting as 403 with a rate-limit reason
+            Some(403) => is_rate_limit_reason(err),
+            _ => false,
+        },
+    }
+}
+
+fn is_rate_limit_reason(err: &google_gmail1::Error) -> bool {
+    let google_gmail1::Error::BadRequest(body) = err else {
+        return false;
+    };
+    body["error"]["errors"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e["reason"].as_str())
+        .any(|r| r == "rateLimitExceeded" || r == "userRateLimitExceeded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::cell::Cell;
+
+    fn bad_request(code: u16, reason: &str) -> google_gmail1::Error {
+        google_gmail1::Error::BadRequest(json!({
+            "error": { "code": code, "errors": [{ "reason": reason }] }
+        }))
+    }
+
+    #[test]
+    fn test_transient_classification() {
+        assert!(is_transient(&bad_request(429, "rateLimitExceeded")));
+        assert!(is_transient(&bad_request(503, "backendError")));
+        assert!(is_transient(&bad_request(403, "userRateLimitExceeded")));
+        assert!(!is_transient(&bad_request(403, "insufficientPermissions")));
+        assert!(!is_transient(&bad_request(404, "notFound")));
+        assert_eq!(status_code(&bad_request(404, "notFound")), Some(404));
+    }
+
+    #[tokio::test]
+    async fn test_run_retries_until_success() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        };
+        let calls = Cell::new(0);
+        let result = policy
+            .run("test", || {
+                calls.set(calls.get() + 1);
+                let n = calls.get();
+                async move {
+                    if n < 3 {
+                        Err(bad_request(500, "backendError"))
+                    } else {
+                        Ok(n)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        calls.set(0);
+        let result: Result<(), _> = policy
+            .run("test", || {
+                calls.set(calls.get() + 1);
+                async { Err(bad_request(400, "invalidArgument")) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -1,27 +1,190 @@
 use async_trait::async_trait;
-use yup_oauth2::storage::{TokenInfo, TokenStorage};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use tracing::info;
 use yup_oauth2::error::TokenStorageError;
-use time::{Duration, OffsetDateTime};
+use yup_oauth2::storage::{TokenInfo, TokenStorage};
+
+/// Token storage backed by a JSON file on disk.
+///
+/// Holds a single token regardless of the requested scopes (we only ever ask
+/// for Gmail readonly). The authenticator refreshes expired tokens itself and
+/// hands the result to `set`, which rewrites the file atomically with
+/// owner-only permissions.
+pub struct FileTokenStore {
+    path: PathBuf,
+    token: Mutex<Option<TokenInfo>>,
+    /// When false, refreshed tokens are kept in memory only (`--dry-run`)
+    persist: bool,
+}
+
+impl FileTokenStore {
+    /// Open the token file at `path`.
+    ///
+    /// If the file does not exist yet the store starts with `seed` (if any);
+    /// see [`seed_token`] for turning a config refresh token into one.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        seed: Option<TokenInfo>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.into();
+        let token = match Self::read_token(&path)? {
+            Some(token) => {
+                info!(path = %path.display(), expires_at = ?token.expires_at, "Loaded OAuth token");
+                Some(token)
+            }
+            None => {
+                if seed.is_some() {
+                    info!(path = %path.display(), "No token file — seeding from config");
+                }
+                seed
+            }
+        };
+
+        Ok(Self {
+            path,
+            token: Mutex::new(token),
+            persist: true,
+        })
+    }
+
+    /// Keep refreshed tokens in memory instead of writing the file.
+    pub fn without_writes(self) -> Self {
+        Self {
+            persist: false,
+            ..self
+        }
+    }
+
+    /// A store that ignores any existing token file, forcing the
+    /// authenticator through the interactive flow. The file is overwritten
+    /// once the flow completes.
+    pub fn empty(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            token: Mutex::new(None),
+            persist: true,
+        }
+    }
+
+    /// Read a token file, returning `None` if it does not exist.
+    pub fn read_token(path: &Path) -> Result<Option<TokenInfo>, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    fn write_token(path: &Path, token: &TokenInfo) -> io::Result<()> {
+        write_private(path, &serde_json::to_vec_pretty(token)?)
+    }
+}
+
+/// Replace `path` with `contents` atomically: write a temp file next to it
+/// with owner-only permissions, sync it, then rename it into place. A crash
+/// part way leaves the old file untouched.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
 
-pub struct SimpleTokenStore {
-    pub access_token: String,
-    pub refresh_token: String,
-    pub expires_in: i64,
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Build a seed token from config values. The access token is marked as
+/// already expired so the first API call refreshes and persists a real one.
+/// Returns `None` when there is no refresh token to seed from.
+pub fn seed_token(refresh_token: &str, access_token: &str) -> Option<TokenInfo> {
+    if refresh_token.is_empty() {
+        return None;
+    }
+    Some(TokenInfo {
+        access_token: Some(access_token.to_string()).filter(|t| !t.is_empty()),
+        refresh_token: Some(refresh_token.to_string()),
+        expires_at: Some(OffsetDateTime::UNIX_EPOCH),
+        id_token: None,
+    })
 }
 
 #[async_trait]
-impl TokenStorage for SimpleTokenStore {
-    async fn set(&self, _scopes: &[&str], _token: TokenInfo) -> Result<(), TokenStorageError> {
-        // In production, persist updated tokens here
+impl TokenStorage for FileTokenStore {
+    async fn set(&self, _scopes: &[&str], token: TokenInfo) -> Result<(), TokenStorageError> {
+        if self.persist {
+            Self::write_token(&self.path, &token)?;
+            info!(path = %self.path.display(), expires_at = ?token.expires_at, "Persisted OAuth token");
+        }
+        *self.token.lock().unwrap() = Some(token);
         Ok(())
     }
 
     async fn get(&self, _scopes: &[&str]) -> Option<TokenInfo> {
-        Some(TokenInfo {
-            access_token: Some(self.access_token.clone()),
-            refresh_token: Some(self.refresh_token.clone()),
-            expires_at: Some(OffsetDateTime::now_utc() + Duration::seconds(self.expires_in)),
+        self.token.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("invoice_search_tok_{}", std::process::id()));
+        let path = dir.join("token.json");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(seed_token("", "access-0").is_none());
+        let store = FileTokenStore::open(&path, seed_token("refresh-1", "access-0")).unwrap();
+        let seeded = store.get(&[]).await.unwrap();
+        assert_eq!(seeded.refresh_token.as_deref(), Some("refresh-1"));
+        assert!(seeded.expires_at.unwrap() < OffsetDateTime::now_utc());
+        assert!(!path.exists());
+
+        let expires_at = OffsetDateTime::from_unix_timestamp(2_000_000_000).unwrap();
+        let token = TokenInfo {
+            access_token: Some("access-2".to_string()),
+            refresh_token: Some("refresh-1".to_string()),
+            expires_at: Some(expires_at),
             id_token: None,
-        })
+        };
+        // A dry run uses the refreshed token without writing it
+        let dry = FileTokenStore::open(&path, seed_token("refresh-1", "access-0"))
+            .unwrap()
+            .without_writes();
+        dry.set(&[], token.clone()).await.unwrap();
+        assert_eq!(dry.get(&[]).await, Some(token.clone()));
+        assert!(!path.exists());
+
+        store.set(&[], token.clone()).await.unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Re-opening ignores the seed and returns the persisted token
+        let reopened = FileTokenStore::open(&path, seed_token("other", "")).unwrap();
+        assert_eq!(reopened.get(&[]).await, Some(token));
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
\ No newline at end of file
+}